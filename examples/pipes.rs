@@ -135,7 +135,7 @@ fn parse() -> CmdResult {
 
     // test if $1 is a hexadecimal string
     fn is_hex(arg: &str) -> (bool, i32) {
-        if let Ok(vv) = i32::from_str_radix(&arg, 16) {
+        if let Ok(vv) = i32::from_str_radix(arg, 16) {
             return (true, vv);
         }
         (false, 0)
@@ -185,8 +185,8 @@ fn parse() -> CmdResult {
                 let arg_opt = args.next();
                 let (is_valid, vv) = is_N(arg_opt.clone());
                 let arg_str = arg_opt.unwrap_or_default();
-                if arg_str.starts_with("#") {
-                    let (is_valid_hex, hv) = is_hex(&arg_str[1..]);
+                if let Some(hex_str) = arg_str.strip_prefix('#') {
+                    let (is_valid_hex, hv) = is_hex(hex_str);
                     if !is_valid_hex {
                         pearg(&arg, "unrecognized hexadecimal string");
                     }
@@ -212,7 +212,7 @@ fn parse() -> CmdResult {
             }
             "-f" => {
                 let (is_valid, vv) = is_N(args.next());
-                if is_valid && vv >= 20 && vv <= 100 {
+                if is_valid && (20..=100).contains(&vv) {
                     tls_set!(f, |nf| *nf = vv);
                 } else {
                     pearg(&arg, "must be an integer and from 20 to 100");
@@ -220,7 +220,7 @@ fn parse() -> CmdResult {
             }
             "-s" => {
                 let (is_valid, vv) = is_N(args.next());
-                if is_valid && vv >= 5 && vv <= 15 {
+                if is_valid && (5..=15).contains(&vv) {
                     tls_set!(r, |nr| *nr = vv);
                 } else {
                     pearg(&arg, "must be a non-negative integer");
@@ -424,18 +424,17 @@ fn main() -> CmdResult {
 
             // Loop on edges (change color on loop):
             // +_CP_warp
-            if !tls_get!(KEEPCT) {
-                if tls_get!(x)[i] >= tls_get!(w)
+            if !tls_get!(KEEPCT)
+                && (tls_get!(x)[i] >= tls_get!(w)
                     || tls_get!(x)[i] < 0
                     || tls_get!(y)[i] >= tls_get!(h)
-                    || tls_get!(y)[i] < 0
+                    || tls_get!(y)[i] < 0)
                 {
                     tls_set!(c, |nc| nc[i] =
                         tls_get!(E)[(tls_get!(CN) * rand() / M) as usize].clone());
                     tls_set!(v, |nv| nv[i] =
-                        tls_get!(V)[(tls_get!(VN) * rand() / M) as usize].clone());
+                        tls_get!(V)[(tls_get!(VN) * rand() / M) as usize]);
                 }
-            }
             tls_set!(x, |nx| nx[i] = (nx[i] + tls_get!(w)) % tls_get!(w));
             tls_set!(y, |ny| ny[i] = (ny[i] + tls_get!(h)) % tls_get!(h));
             // -_CP_warp
@@ -1,5 +1,5 @@
-use crate::parser::{ParseArg, Parser};
-use proc_macro2::{token_stream, Delimiter, Ident, Literal, Span, TokenStream, TokenTree};
+use crate::parser::{ParseArg, Parser, WriteMode};
+use proc_macro2::{token_stream, Delimiter, Group, Ident, Literal, Span, TokenStream, TokenTree};
 use proc_macro_error::abort;
 use quote::quote;
 use std::ffi::OsString;
@@ -8,14 +8,26 @@ use std::iter::Peekable;
 // Scan string literal to tokenstream, used by most of the macros
 //
 // - support ${var} or $var for interpolation
-//   - to escape '$' itself, use "$$"
+//   - to escape '$' itself, use "$$", since rustc rejects "\$" as an unknown escape
+//   - "${var:-default}" for the default if var is empty, "${var:+alt}" for the alternate if
+//     it is not, and "${var:?message}" to fail instead, only where the generated code can fail
+//     with `?`
+//   - "${#var}" for the length, and "${var#pattern}", "${var##pattern}", "${var%pattern}" or
+//     "${var%%pattern}" to remove the shortest or the longest matching prefix or suffix
 // - support normal rust character escapes:
 //   https://doc.rust-lang.org/reference/tokens.html#ascii-escapes
 pub fn scan_str_lit(lit: &Literal) -> TokenStream {
+    scan_str(lit, false).0
+}
+
+// Same as scan_str_lit, with "$(cmd)" command substitution support if `with_subst` is set.
+// Returns whether any substitution was found, since the generated code can fail with `?`.
+fn scan_str(lit: &Literal, with_subst: bool) -> (TokenStream, bool) {
     let s = lit.to_string();
     if !s.starts_with('\"') {
-        return quote!(::cmd_lib::CmdString::from(#lit));
+        return (quote!(::cmd_lib::CmdString::from(#lit)), false);
     }
+    let mut found_subst = false;
     let mut iter = s[1..s.len() - 1] // To trim outside ""
         .chars()
         .peekable();
@@ -39,10 +51,64 @@ pub fn scan_str_lit(lit: &Literal) -> TokenStream {
             }
 
             seal_last_part(&mut last_part, &mut output);
+            if with_subst && iter.peek() == Some(&'(') {
+                iter.next();
+                if iter.peek() == Some(&'(') {
+                    iter.next();
+                    let mut expr = String::new();
+                    let mut depth = 1;
+                    for c in iter.by_ref() {
+                        if c == '(' {
+                            depth += 1;
+                        } else if c == ')' {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        expr.push(c);
+                    }
+                    if depth != 0 || iter.next() != Some(')') {
+                        abort!(lit.span(), "unterminated arithmetic expansion");
+                    }
+                    let stream = expr.parse::<TokenStream>().unwrap_or_else(|_| {
+                        abort!(lit.span(), "invalid tokens in arithmetic expansion")
+                    });
+                    let expr = scan_arith(stream);
+                    output.extend(quote!(.append(::cmd_lib::eval_arith(#expr)?.to_string())));
+                    found_subst = true;
+                    continue;
+                }
+                let mut cmd = String::new();
+                let mut depth = 1;
+                for c in iter.by_ref() {
+                    if c == '(' {
+                        depth += 1;
+                    } else if c == ')' {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                    cmd.push(c);
+                }
+                if depth != 0 {
+                    abort!(lit.span(), "unterminated command substitution");
+                }
+                let cmds = scan_str_cmd(&cmd, lit.span());
+                output.extend(quote!(.append(#cmds?.run_fun()?.trim_end_matches('\n'))));
+                found_subst = true;
+                continue;
+            }
             let mut with_brace = false;
+            let mut length = false;
             if iter.peek() == Some(&'{') {
                 with_brace = true;
                 iter.next();
+                if iter.peek() == Some(&'#') {
+                    length = true;
+                    iter.next();
+                }
             }
             let mut var = String::new();
             while let Some(&c) = iter.peek() {
@@ -55,16 +121,124 @@ pub fn scan_str_lit(lit: &Literal) -> TokenStream {
                 var.push(c);
                 iter.next();
             }
+            // the operator of "${var<op><word>}", like ":-" or "##"
+            let mut operator = None;
             if with_brace {
-                if iter.peek() != Some(&'}') {
+                if !var.is_empty() && !length && iter.peek() != Some(&'}') {
+                    let mut op = String::new();
+                    match iter.next() {
+                        Some(':') => {
+                            op.push(':');
+                            op.extend(iter.next().filter(|c| matches!(c, '-' | '+' | '?')));
+                        }
+                        Some(c @ ('#' | '%')) => {
+                            op.push(c);
+                            if iter.peek() == Some(&c) {
+                                op.extend(iter.next());
+                            }
+                        }
+                        c => op.extend(c),
+                    }
+                    if op == ":" || !op.starts_with([':', '#', '%']) {
+                        abort!(
+                            lit.span(),
+                            "unsupported parameter expansion \"${{{}{}...}}\", \
+                             only \":-\", \":+\", \":?\", \"#\", \"##\", \"%\" and \"%%\" are supported",
+                            var,
+                            op
+                        );
+                    }
+                    let mut word = String::new();
+                    let mut depth = 1;
+                    for c in iter.by_ref() {
+                        if c == '{' {
+                            depth += 1;
+                        } else if c == '}' {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        word.push(c);
+                    }
+                    if depth != 0 {
+                        abort!(lit.span(), "bad substitution");
+                    }
+                    operator = Some((op, word));
+                } else if iter.peek() != Some(&'}') || length && var.is_empty() {
                     abort!(lit.span(), "bad substitution");
                 } else {
                     iter.next();
                 }
             }
             if !var.is_empty() {
+                let name = var.clone();
                 let var = syn::parse_str::<Ident>(&var).unwrap();
-                output.extend(quote!(.append(#var.as_os_str())));
+                // the words are expanded the same as the string, only when they are used
+                let mut scan_word = |word: &str| {
+                    let word = syn::parse_str::<Literal>(&format!("\"{}\"", word))
+                        .unwrap_or_else(|_| abort!(lit.span(), "bad substitution"));
+                    let (word, subst) = scan_str(&word, with_subst);
+                    found_subst |= subst;
+                    word
+                };
+                let expansion = match operator {
+                    None if length => {
+                        quote!(#var.as_os_str().to_string_lossy().chars().count().to_string())
+                    }
+                    None => quote!(#var.as_os_str()),
+                    Some((op, word)) => match op.as_str() {
+                        ":-" => {
+                            let default = scan_word(&word);
+                            quote!({
+                                let value = #var.as_os_str();
+                                if value.is_empty() {
+                                    #default.into_os_string()
+                                } else {
+                                    value
+                                }
+                            })
+                        }
+                        ":+" => {
+                            let alt = scan_word(&word);
+                            quote!({
+                                if #var.as_os_str().is_empty() {
+                                    ::std::ffi::OsString::new()
+                                } else {
+                                    #alt.into_os_string()
+                                }
+                            })
+                        }
+                        ":?" => {
+                            if !with_subst {
+                                abort!(
+                                    lit.span(),
+                                    "\"${{{}:?}}\" is only supported in commands",
+                                    name
+                                );
+                            }
+                            let message = scan_word(&word);
+                            found_subst = true;
+                            quote!(::cmd_lib::check_var(
+                                #var.as_os_str(),
+                                #name,
+                                #message.as_ref(),
+                            )?)
+                        }
+                        _ => {
+                            let pattern = scan_word(&word);
+                            let prefix = op.starts_with('#');
+                            let longest = op.len() == 2;
+                            quote!(::cmd_lib::trim_var(
+                                #var.as_os_str(),
+                                #pattern.as_ref(),
+                                #prefix,
+                                #longest,
+                            ))
+                        }
+                    },
+                };
+                output.extend(quote!(.append(#expansion)));
             } else {
                 output.extend(quote!(.append("$")));
             }
@@ -73,37 +247,170 @@ pub fn scan_str_lit(lit: &Literal) -> TokenStream {
         }
     }
     seal_last_part(&mut last_part, &mut output);
+    (output, found_subst)
+}
+
+// Generate the expression of "$((...))" as a CmdString, with the variables substituted at
+// runtime, so it can be evaluated by `eval_arith()`. Like bash, the variables can be used with or
+// without '$'.
+fn scan_arith(stream: TokenStream) -> TokenStream {
+    let mut output = quote!(::cmd_lib::CmdString::default());
+    for tt in stream {
+        match tt {
+            TokenTree::Ident(var) => output.extend(quote!(.append(#var.as_os_str()))),
+            TokenTree::Literal(lit) => {
+                let s = lit.to_string();
+                if s.parse::<i64>().is_err() {
+                    abort!(lit.span(), "expect integer in arithmetic expansion");
+                }
+                output.extend(quote!(.append(#s)));
+            }
+            TokenTree::Punct(p) if p.as_char() == '$' => continue,
+            TokenTree::Punct(p) if matches!(p.as_char(), '+' | '-' | '*' | '/' | '%') => {
+                let s = p.as_char().to_string();
+                output.extend(quote!(.append(#s)));
+            }
+            TokenTree::Group(g) if g.delimiter() == Delimiter::Parenthesis => {
+                let inner = scan_arith(g.stream());
+                output.extend(quote!(.append("(").append(#inner).append(")")));
+            }
+            TokenTree::Group(g) if g.delimiter() == Delimiter::Brace => {
+                // ${var}
+                let mut inner = g.stream().into_iter();
+                match (inner.next(), inner.next()) {
+                    (Some(TokenTree::Ident(var)), None) => {
+                        output.extend(quote!(.append(#var.as_os_str())))
+                    }
+                    _ => abort!(g.span(), "invalid variable in arithmetic expansion"),
+                }
+            }
+            tt => abort!(tt.span(), "invalid token in arithmetic expansion"),
+        }
+        // keep the tokens apart, e.g. "x y" should fail instead of being joined
+        output.extend(quote!(.append(" ")));
+    }
     output
 }
 
+// Parse the command inside "$(...)" of a string literal, only simple commands separated with
+// spaces, pipes, ';' and '&&' are supported, and \" can be used to quote arguments.
+fn scan_str_cmd(cmd: &str, span: Span) -> TokenStream {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    // nested substitution depth, which is kept in one word
+    let mut depth = 0;
+    let mut chars = cmd.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '(' && (depth > 0 || word.ends_with('$')) {
+            depth += 1;
+        } else if c == ')' && depth > 0 {
+            depth -= 1;
+        }
+        if c == '\\' {
+            if depth == 0 && chars.peek() == Some(&'"') {
+                chars.next();
+                quoted = !quoted;
+            } else {
+                // keep other escapes and nested quotes as they are
+                word.push(c);
+                word.extend(chars.next());
+            }
+            in_word = true;
+        } else if c.is_whitespace() && !quoted && depth == 0 {
+            if in_word {
+                words.push(std::mem::take(&mut word));
+                in_word = false;
+            }
+        } else {
+            word.push(c);
+            in_word = true;
+        }
+    }
+    if quoted {
+        abort!(span, "unterminated quote in command substitution");
+    }
+    if in_word {
+        words.push(word);
+    }
+    if words.is_empty() {
+        abort!(span, "empty command substitution");
+    }
+
+    let mut fallible = false;
+    let args = words
+        .into_iter()
+        .map(|word| match word.as_str() {
+            "|" => ParseArg::Pipe,
+            ";" => ParseArg::Semicolon,
+            "&&" => ParseArg::And,
+            "||" => ParseArg::Or,
+            _ => {
+                let lit = syn::parse_str::<Literal>(&format!("\"{}\"", word))
+                    .unwrap_or_else(|_| abort!(span, "invalid argument in command substitution"));
+                let (arg, found_subst) = scan_str(&lit, true);
+                fallible |= found_subst;
+                ParseArg::ArgStr(quote!(#arg.into_os_string()))
+            }
+        })
+        .collect::<Vec<_>>();
+    Parser::from(args.into_iter().peekable(), fallible).parse(false)
+}
+
+// file descriptor of an output redirect, e.g. "3" in "3>file" or "1" in "2>&1"
+fn parse_fd(s: &str) -> Option<i32> {
+    s.parse::<u8>().ok().filter(|fd| *fd > 0).map(i32::from)
+}
+
 enum SepToken {
     Space,
     SemiColon,
     Pipe,
+    And,
+    Or,
 }
 
 enum RedirectFd {
     Stdin,
-    Stdout { append: bool },
-    Stderr { append: bool },
-    StdoutErr { append: bool },
+    StdinStr,
+    StdinDoc,
+    Out { fd: i32, mode: WriteMode },
+    StdoutErr { mode: WriteMode },
 }
 
 pub struct Lexer {
     iter: TokenStreamPeekable<token_stream::IntoIter>,
     args: Vec<ParseArg>,
-    last_arg_str: TokenStream,
+    // more than one argument only with brace expansion
+    last_args: Vec<TokenStream>,
+    // unquoted wildcards found in the last argument
+    last_arg_glob: bool,
+    // the last argument is only a variable
+    last_arg_var: Option<Ident>,
     last_redirect: Option<(RedirectFd, Span)>,
-    seen_redirect: (bool, bool, bool),
+    // fds already redirected for the current command
+    seen_redirect: Vec<i32>,
+    // command substitution found, which could fail
+    has_subst: bool,
+    // ended with '&'
+    background: bool,
+    // the shell syntax passed as it is, only reported with the "warn-metachars" feature
+    warnings: Vec<(Span, &'static str)>,
 }
 
 impl Lexer {
     pub fn new(input: TokenStream) -> Self {
         Self {
             args: vec![],
-            last_arg_str: TokenStream::new(),
+            last_args: vec![],
+            last_arg_glob: false,
+            last_arg_var: None,
             last_redirect: None,
-            seen_redirect: (false, false, false),
+            seen_redirect: vec![],
+            has_subst: false,
+            background: false,
+            warnings: vec![],
             iter: TokenStreamPeekable {
                 peekable: input.into_iter().peekable(),
                 span: Span::call_site(),
@@ -112,10 +419,39 @@ impl Lexer {
     }
 
     pub fn scan(mut self) -> Parser<impl Iterator<Item = ParseArg>> {
+        self.scan_args();
+        Parser::from(self.args.into_iter().peekable(), self.has_subst)
+            .background(self.background)
+            .warnings(self.warnings)
+    }
+
+    fn warn_metachar(&mut self, span: Span, msg: &'static str) {
+        if cfg!(feature = "warn-metachars") {
+            self.warnings.push((span, msg));
+        }
+    }
+
+    fn scan_args(&mut self) {
         while let Some(item) = self.iter.next() {
             match item {
-                TokenTree::Group(_) => {
-                    abort!(self.iter.span(), "grouping is only allowed for variables");
+                TokenTree::Group(g) => {
+                    if g.delimiter() == Delimiter::Bracket && self.at_cmd_start() {
+                        self.scan_test(&g);
+                    } else if g.delimiter() == Delimiter::Bracket {
+                        // wildcard like [a-z]
+                        let s = g
+                            .stream()
+                            .into_iter()
+                            .map(|tt| tt.to_string())
+                            .collect::<String>();
+                        let s = format!("[{}]", s);
+                        self.extend_last_arg_glob(&s);
+                    } else if g.delimiter() == Delimiter::Brace {
+                        let alts = Self::expand_brace(&g);
+                        self.expand_last_arg(alts);
+                    } else {
+                        abort!(self.iter.span(), "grouping is only allowed for variables");
+                    }
                 }
                 TokenTree::Literal(lit) => {
                     self.scan_literal(lit);
@@ -131,13 +467,24 @@ impl Lexer {
                     } else if ch == '|' {
                         self.scan_pipe();
                     } else if ch == '<' {
-                        self.set_redirect(self.iter.span(), RedirectFd::Stdin);
+                        self.scan_redirect_in();
                     } else if ch == '>' {
                         self.scan_redirect_out(1);
                     } else if ch == '&' {
                         self.scan_ampersand();
                     } else if ch == '$' {
                         self.scan_dollar();
+                    } else if ch == '~' && self.last_args.is_empty() {
+                        self.scan_tilde();
+                    } else if ch == '*' || ch == '?' {
+                        self.extend_last_arg_glob(&ch.to_string());
+                    } else if ch == '#' {
+                        self.warn_metachar(
+                            punct.span(),
+                            "`#` is passed as an argument instead of starting a comment, \
+                             use `//` for comments",
+                        );
+                        self.extend_last_arg(quote!("#"));
                     } else {
                         let s = ch.to_string();
                         self.extend_last_arg(quote!(#s));
@@ -145,65 +492,202 @@ impl Lexer {
                 }
             }
 
-            if self.iter.peek_no_gap().is_none() && !self.last_arg_str.is_empty() {
+            if self.iter.peek_no_gap().is_none() && !self.last_args.is_empty() {
                 self.add_arg_with_token(SepToken::Space, self.iter.span());
             }
         }
         self.add_arg_with_token(SepToken::Space, self.iter.span());
-        Parser::from(self.args.into_iter().peekable())
+    }
+
+    // scan "[ expr ]" as the test command, with only arguments inside
+    fn scan_test(&mut self, group: &Group) {
+        let mut inner = Lexer::new(group.stream());
+        inner.scan_args();
+        if inner.background
+            || !inner
+                .args
+                .iter()
+                .all(|arg| matches!(arg, ParseArg::ArgStr(_) | ParseArg::ArgGlob(_)))
+        {
+            abort!(group.span(), "only arguments are allowed in test command");
+        }
+        self.has_subst |= inner.has_subst;
+        self.args.push(ParseArg::ArgStr(quote!("[")));
+        self.args.append(&mut inner.args);
+        self.extend_last_arg(quote!("]"));
     }
 
     fn add_arg_with_token(&mut self, token: SepToken, token_span: Span) {
-        let last_arg_str = &self.last_arg_str;
+        let last_args = std::mem::take(&mut self.last_args);
+        let last_arg_glob = std::mem::take(&mut self.last_arg_glob);
+        let last_arg_var = self.last_arg_var.take();
         if let Some((redirect, span)) = self.last_redirect.take() {
-            if last_arg_str.is_empty() {
+            if last_args.is_empty() {
                 abort!(span, "wrong redirection format: missing target");
             }
+            if last_args.len() > 1 {
+                abort!(span, "ambiguous redirect: more than one target");
+            }
+            let last_arg_str = &last_args[0];
 
             let mut stdouterr = false;
-            let (fd, append) = match redirect {
-                RedirectFd::Stdin => (0, false),
-                RedirectFd::Stdout { append } => (1, append),
-                RedirectFd::Stderr { append } => (2, append),
-                RedirectFd::StdoutErr { append } => {
+            let mut here_str = false;
+            let mut here_doc = false;
+            let (fd, mode) = match redirect {
+                RedirectFd::Stdin => (0, WriteMode::Truncate),
+                RedirectFd::StdinStr => {
+                    here_str = true;
+                    (0, WriteMode::Truncate)
+                }
+                RedirectFd::StdinDoc => {
+                    here_doc = true;
+                    (0, WriteMode::Truncate)
+                }
+                RedirectFd::Out { fd, mode } => (fd, mode),
+                RedirectFd::StdoutErr { mode } => {
                     stdouterr = true;
-                    (1, append)
+                    (1, mode)
                 }
             };
-            self.args
-                .push(ParseArg::RedirectFile(fd, quote!(#last_arg_str), append));
+            if here_str {
+                self.args.push(ParseArg::RedirectStr(quote!(#last_arg_str)));
+            } else if here_doc {
+                self.args.push(ParseArg::RedirectDoc(quote!(#last_arg_str)));
+            } else if let (0, Some(var)) = (fd, last_arg_var) {
+                // depending on its type, the variable is the data or the file path
+                self.args.push(ParseArg::RedirectVar(quote!(#var)));
+            } else {
+                self.args
+                    .push(ParseArg::RedirectFile(fd, quote!(#last_arg_str), mode));
+            }
             if stdouterr {
                 self.args.push(ParseArg::RedirectFd(2, 1));
             }
-        } else if !last_arg_str.is_empty() {
-            self.args.push(ParseArg::ArgStr(quote!(#last_arg_str)));
+        } else {
+            for last_arg_str in last_args {
+                if last_arg_glob {
+                    self.args.push(ParseArg::ArgGlob(last_arg_str));
+                } else {
+                    self.args.push(ParseArg::ArgStr(last_arg_str));
+                }
+            }
         }
-        let mut new_redirect = (false, false, false);
+        let mut new_redirect = vec![];
         match token {
-            SepToken::Space => new_redirect = self.seen_redirect,
+            SepToken::Space => new_redirect = std::mem::take(&mut self.seen_redirect),
             SepToken::SemiColon => self.args.push(ParseArg::Semicolon),
+            SepToken::And => self.args.push(ParseArg::And),
+            SepToken::Or => self.args.push(ParseArg::Or),
             SepToken::Pipe => {
-                Self::check_set_redirect(&mut self.seen_redirect.1, "stdout", token_span);
+                self.check_set_redirect(1, token_span);
                 self.args.push(ParseArg::Pipe);
-                new_redirect.0 = true;
+                new_redirect.push(0);
             }
         }
         self.seen_redirect = new_redirect;
-        self.last_arg_str = TokenStream::new();
     }
 
     fn extend_last_arg(&mut self, stream: TokenStream) {
-        if self.last_arg_str.is_empty() {
-            self.last_arg_str = quote!(::cmd_lib::CmdString::default());
+        self.last_arg_var = None;
+        if self.last_args.is_empty() {
+            self.last_args.push(quote!(::cmd_lib::CmdString::default()));
+        }
+        for last_arg_str in self.last_args.iter_mut() {
+            last_arg_str.extend(quote!(.append(#stream)));
         }
-        self.last_arg_str.extend(quote!(.append(#stream)));
     }
 
-    fn check_set_redirect(redirect: &mut bool, name: &str, span: Span) {
-        if *redirect {
-            abort!(span, "already set {} redirection", name);
+    // unquoted wildcards, the other parts of the argument are escaped in the glob pattern
+    fn extend_last_arg_glob(&mut self, s: &str) {
+        self.last_arg_var = None;
+        if self.last_args.is_empty() {
+            self.last_args.push(quote!(::cmd_lib::CmdString::default()));
+        }
+        for last_arg_str in self.last_args.iter_mut() {
+            last_arg_str.extend(quote!(.append_glob(#s)));
         }
-        *redirect = true;
+        self.last_arg_glob = true;
+    }
+
+    fn extend_last_arg_var(&mut self, var: &Ident) {
+        let alone = self.last_args.is_empty();
+        self.extend_last_arg(quote!(#var.as_os_str()));
+        if alone {
+            self.last_arg_var = Some(var.clone());
+        }
+    }
+
+    // generate the cartesian product of the current arguments and the alternatives
+    fn expand_last_arg(&mut self, alts: Vec<String>) {
+        self.last_arg_var = None;
+        if self.last_args.is_empty() {
+            self.last_args.push(quote!(::cmd_lib::CmdString::default()));
+        }
+        self.last_args = self
+            .last_args
+            .iter()
+            .flat_map(|last_arg_str| {
+                alts.iter()
+                    .map(move |alt| quote!(#last_arg_str.append(#alt)))
+            })
+            .collect();
+    }
+
+    // expand brace group like bash, e.g. {a,b{c,d},} => ["a", "bc", "bd", ""]
+    fn expand_brace(group: &Group) -> Vec<String> {
+        let mut ret = vec![];
+        let mut cur = vec![String::new()];
+        let mut has_comma = false;
+        for tt in group.stream() {
+            let s = match tt {
+                TokenTree::Punct(ref p) if p.as_char() == ',' => {
+                    has_comma = true;
+                    ret.append(&mut cur);
+                    cur.push(String::new());
+                    continue;
+                }
+                TokenTree::Punct(ref p) if p.as_char() == '$' => {
+                    abort!(p.span(), "variables are not allowed in brace expansion");
+                }
+                TokenTree::Group(ref g) if g.delimiter() == Delimiter::Brace => {
+                    let alts = Self::expand_brace(g);
+                    cur = cur
+                        .iter()
+                        .flat_map(|c| alts.iter().map(move |alt| format!("{}{}", c, alt)))
+                        .collect();
+                    continue;
+                }
+                TokenTree::Group(ref g) => {
+                    abort!(g.span(), "invalid grouping in brace expansion");
+                }
+                TokenTree::Literal(ref lit) => {
+                    match syn::parse_str::<syn::LitStr>(&lit.to_string()) {
+                        Ok(lit_str) => lit_str.value(),
+                        Err(_) => lit.to_string(),
+                    }
+                }
+                tt => tt.to_string(),
+            };
+            cur.iter_mut().for_each(|c| c.push_str(&s));
+        }
+        ret.append(&mut cur);
+        if !has_comma {
+            // no expansion, same as bash
+            ret.iter_mut().for_each(|s| *s = format!("{{{}}}", s));
+        }
+        ret
+    }
+
+    fn check_set_redirect(&mut self, fd: i32, span: Span) {
+        if self.seen_redirect.contains(&fd) {
+            match fd {
+                0 => abort!(span, "already set stdin redirection"),
+                1 => abort!(span, "already set stdout redirection"),
+                2 => abort!(span, "already set stderr redirection"),
+                _ => abort!(span, "already set fd {} redirection", fd),
+            }
+        }
+        self.seen_redirect.push(fd);
     }
 
     fn set_redirect(&mut self, span: Span, fd: RedirectFd) {
@@ -211,16 +695,13 @@ impl Lexer {
             abort!(span, "wrong double redirection format");
         }
         match fd {
-            RedirectFd::Stdin => Self::check_set_redirect(&mut self.seen_redirect.0, "stdin", span),
-            RedirectFd::Stdout { append: _ } => {
-                Self::check_set_redirect(&mut self.seen_redirect.1, "stdout", span)
-            }
-            RedirectFd::Stderr { append: _ } => {
-                Self::check_set_redirect(&mut self.seen_redirect.2, "stderr", span)
+            RedirectFd::Stdin | RedirectFd::StdinStr | RedirectFd::StdinDoc => {
+                self.check_set_redirect(0, span)
             }
-            RedirectFd::StdoutErr { append: _ } => {
-                Self::check_set_redirect(&mut self.seen_redirect.1, "stdout", span);
-                Self::check_set_redirect(&mut self.seen_redirect.2, "stderr", span);
+            RedirectFd::Out { fd, mode: _ } => self.check_set_redirect(fd, span),
+            RedirectFd::StdoutErr { mode: _ } => {
+                self.check_set_redirect(1, span);
+                self.check_set_redirect(2, span);
             }
         }
         self.last_redirect = Some((fd, span));
@@ -230,15 +711,23 @@ impl Lexer {
         let s = lit.to_string();
         if s.starts_with('\"') || s.starts_with('r') {
             // string literal
-            let ss = scan_str_lit(&lit);
+            if s.starts_with('\"') && s.contains('`') {
+                self.warn_metachar(
+                    lit.span(),
+                    "backticks are passed as they are instead of substituting the command, \
+                     use \"$(cmd)\" instead",
+                );
+            }
+            let (ss, found_subst) = scan_str(&lit, true);
+            self.has_subst |= found_subst;
             self.extend_last_arg(quote!(#ss.into_os_string()));
         } else {
             let mut is_redirect = false;
-            if s == "1" || s == "2" {
+            if let Some(fd) = parse_fd(&s) {
                 if let Some(TokenTree::Punct(ref p)) = self.iter.peek_no_gap() {
                     if p.as_char() == '>' {
                         self.iter.next();
-                        self.scan_redirect_out(if s == "1" { 1 } else { 2 });
+                        self.scan_redirect_out(fd);
                         is_redirect = true;
                     }
                 }
@@ -251,11 +740,17 @@ impl Lexer {
 
     fn scan_pipe(&mut self) {
         if let Some(TokenTree::Punct(p)) = self.iter.peek_no_gap() {
+            if p.as_char() == '|' {
+                self.iter.next();
+                self.scan_list(SepToken::Or);
+                return;
+            }
             if p.as_char() == '&' {
                 if let Some(ref redirect) = self.last_redirect {
                     abort!(redirect.1, "invalid '&': found previous redirect");
                 }
-                Self::check_set_redirect(&mut self.seen_redirect.2, "stderr", p.span());
+                let span = p.span();
+                self.check_set_redirect(2, span);
                 self.args.push(ParseArg::RedirectFd(2, 1));
                 self.iter.next();
             }
@@ -263,10 +758,8 @@ impl Lexer {
 
         // expect new command
         match self.iter.peek() {
-            Some(TokenTree::Punct(np)) => {
-                if np.as_char() == '|' || np.as_char() == ';' {
-                    abort!(np.span(), "expect new command after '|'");
-                }
+            Some(TokenTree::Punct(np)) if np.as_char() == '|' || np.as_char() == ';' => {
+                abort!(np.span(), "expect new command after '|'");
             }
             None => {
                 abort!(self.iter.span(), "expect new command after '|'");
@@ -276,20 +769,118 @@ impl Lexer {
         self.add_arg_with_token(SepToken::Pipe, self.iter.span());
     }
 
+    // no arguments scanned yet for the current command
+    fn at_cmd_start(&self) -> bool {
+        self.last_args.is_empty()
+            && matches!(
+                self.args.last(),
+                None | Some(ParseArg::Pipe)
+                    | Some(ParseArg::Semicolon)
+                    | Some(ParseArg::And)
+                    | Some(ParseArg::Or)
+            )
+    }
+
+    // scan '&&' or '||'
+    fn scan_list(&mut self, token: SepToken) {
+        let op = if let SepToken::Or = token { "||" } else { "&&" };
+        // expect previous command
+        if self.at_cmd_start() {
+            abort!(self.iter.span(), "expect command before '{}'", op);
+        }
+
+        // expect new command
+        match self.iter.peek() {
+            Some(TokenTree::Punct(np)) if matches!(np.as_char(), '|' | ';' | '&') => {
+                abort!(np.span(), "expect new command after '{}'", op);
+            }
+            None => {
+                abort!(self.iter.span(), "expect new command after '{}'", op);
+            }
+            _ => {}
+        }
+        self.add_arg_with_token(token, self.iter.span());
+    }
+
+    fn scan_redirect_in(&mut self) {
+        let span = self.iter.span();
+        if let Some(TokenTree::Punct(p)) = self.iter.peek_no_gap() {
+            if p.as_char() == '<' {
+                self.iter.next();
+                if matches!(self.iter.peek_no_gap(), Some(TokenTree::Punct(p)) if p.as_char() == '<')
+                {
+                    self.iter.next();
+                    self.set_redirect(span, RedirectFd::StdinStr);
+                    return;
+                }
+                self.set_redirect(span, RedirectFd::StdinDoc);
+                match self.iter.peek() {
+                    Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Brace => {
+                        let g = g.clone();
+                        self.iter.next();
+                        self.scan_here_doc(&g);
+                    }
+                    // scanned as the redirect target
+                    Some(TokenTree::Literal(lit)) if lit.to_string().starts_with(['"', 'r']) => {}
+                    _ => abort!(
+                        span,
+                        "heredoc (<<) expects a string literal or a {...} block"
+                    ),
+                }
+                return;
+            }
+        }
+        self.set_redirect(span, RedirectFd::Stdin);
+    }
+
+    // scan the heredoc block as a string literal, with the surrounding blank lines and the common
+    // indentation removed, and each line ended with a newline
+    fn scan_here_doc(&mut self, group: &Group) {
+        let src = match group.span().source_text() {
+            Some(src) => src,
+            None => abort!(
+                group.span(),
+                "heredoc block without source text, use a string literal instead"
+            ),
+        };
+        let mut lines: Vec<&str> = src[1..src.len() - 1].lines().collect();
+        if lines.first().is_some_and(|line| line.trim().is_empty()) {
+            lines.remove(0);
+        }
+        if lines.last().is_some_and(|line| line.trim().is_empty()) {
+            lines.pop();
+        }
+        let indent = lines
+            .iter()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| line.len() - line.trim_start().len())
+            .min()
+            .unwrap_or(0);
+        let text: String = lines
+            .iter()
+            .map(|line| format!("{}\n", line.get(indent..).unwrap_or("").trim_end()))
+            .collect();
+
+        let mut lit = Literal::string(&text);
+        lit.set_span(group.span());
+        let (ss, found_subst) = scan_str(&lit, true);
+        self.has_subst |= found_subst;
+        self.extend_last_arg(quote!(#ss.into_os_string()));
+    }
+
     fn scan_redirect_out(&mut self, fd: i32) {
-        let append = self.check_append();
-        self.set_redirect(
-            self.iter.span(),
-            if fd == 1 {
-                RedirectFd::Stdout { append }
-            } else {
-                RedirectFd::Stderr { append }
-            },
-        );
+        let mode = self.check_write_mode();
+        self.set_redirect(self.iter.span(), RedirectFd::Out { fd, mode });
         if let Some(TokenTree::Punct(p)) = self.iter.peek_no_gap() {
             if p.as_char() == '&' {
-                if append {
-                    abort!(p.span(), "raw fd not allowed for append redirection");
+                match mode {
+                    WriteMode::Append => {
+                        abort!(p.span(), "raw fd not allowed for append redirection")
+                    }
+                    WriteMode::Clobber => {
+                        abort!(p.span(), "raw fd not allowed for clobber redirection")
+                    }
+                    WriteMode::Truncate => {}
                 }
                 self.iter.next();
                 if let Some(TokenTree::Literal(lit)) = self.iter.peek_no_gap() {
@@ -297,17 +888,14 @@ impl Lexer {
                     if s.starts_with('\"') || s.starts_with('r') {
                         abort!(lit.span(), "invalid literal string after &");
                     }
-                    if &s == "1" {
-                        self.args.push(ParseArg::RedirectFd(fd, 1));
-                    } else if &s == "2" {
-                        self.args.push(ParseArg::RedirectFd(fd, 2));
-                    } else {
-                        abort!(lit.span(), "Only &1 or &2 is supported");
+                    match parse_fd(&s) {
+                        Some(fd2) => self.args.push(ParseArg::RedirectFd(fd, fd2)),
+                        None => abort!(lit.span(), "invalid file descriptor after &"),
                     }
                     self.last_redirect = None;
                     self.iter.next();
                 } else {
-                    abort!(self.iter.span(), "expect &1 or &2");
+                    abort!(self.iter.span(), "expect a file descriptor after &");
                 }
             }
         }
@@ -319,8 +907,11 @@ impl Lexer {
                 let span = p.span();
                 if p.as_char() == '>' {
                     self.iter.next();
-                    let append = self.check_append();
-                    self.set_redirect(span, RedirectFd::StdoutErr { append });
+                    let mode = self.check_write_mode();
+                    self.set_redirect(span, RedirectFd::StdoutErr { mode });
+                } else if p.as_char() == '&' {
+                    self.iter.next();
+                    self.scan_list(SepToken::And);
                 } else {
                     abort!(span, "invalid punctuation");
                 }
@@ -335,20 +926,65 @@ impl Lexer {
         } else if self.iter.peek().is_some() {
             abort!(self.iter.span(), "invalid spacing after '&'");
         } else {
-            abort!(self.iter.span(), "invalid '&' at the end");
+            // trailing '&' to run the commands in the background
+            if self.at_cmd_start() {
+                abort!(self.iter.span(), "expect command before '&'");
+            }
+            self.background = true;
         }
     }
 
+    fn scan_tilde(&mut self) {
+        match self.iter.peek_no_gap() {
+            None => {}
+            Some(TokenTree::Punct(p))
+                if matches!(p.as_char(), '/' | ';' | '|' | '&' | '<' | '>') => {}
+            Some(TokenTree::Ident(_)) | Some(TokenTree::Literal(_)) => {
+                abort!(self.iter.span(), "~user is not supported");
+            }
+            _ => {
+                // not expanding, same as bash
+                self.extend_last_arg(quote!("~"));
+                return;
+            }
+        }
+        self.extend_last_arg(quote!(::cmd_lib::home_dir()));
+    }
+
     fn scan_dollar(&mut self) {
         let peek_no_gap = self.iter.peek_no_gap().map(|tt| tt.to_owned());
-        // let peek_no_gap = None;
         if let Some(TokenTree::Ident(var)) = peek_no_gap {
-            self.extend_last_arg(quote!(#var.as_os_str()));
+            self.extend_last_arg_var(&var);
         } else if let Some(TokenTree::Group(g)) = peek_no_gap {
+            if g.delimiter() == Delimiter::Parenthesis {
+                let mut inner = g.stream().into_iter();
+                if let (Some(TokenTree::Group(expr)), None) = (inner.next(), inner.next()) {
+                    if expr.delimiter() == Delimiter::Parenthesis {
+                        // arithmetic expansion: $((...))
+                        let expr = scan_arith(expr.stream());
+                        self.extend_last_arg(quote!(::cmd_lib::eval_arith(#expr)?.to_string()));
+                        self.has_subst = true;
+                        self.iter.next();
+                        return;
+                    }
+                }
+                let parser = Lexer::new(g.stream()).scan();
+                if parser.is_background() {
+                    abort!(
+                        g.span(),
+                        "background job is not allowed in command substitution"
+                    );
+                }
+                let cmds = parser.parse(false);
+                self.extend_last_arg(quote!(#cmds?.run_fun()?.trim_end_matches('\n')));
+                self.has_subst = true;
+                self.iter.next();
+                return;
+            }
             if g.delimiter() != Delimiter::Brace && g.delimiter() != Delimiter::Bracket {
                 abort!(
                     g.span(),
-                    "invalid grouping: found {:?}, only \"brace/bracket\" is allowed",
+                    "invalid grouping: found {:?}, only \"brace/bracket/parenthesis\" is allowed",
                     format!("{:?}", g.delimiter()).to_lowercase()
                 );
             }
@@ -360,9 +996,9 @@ impl Lexer {
                         abort!(span, "more than one variable in grouping");
                     }
                     if g.delimiter() == Delimiter::Brace {
-                        self.extend_last_arg(quote!(#var.as_os_str()));
+                        self.extend_last_arg_var(var);
                     } else {
-                        if !self.last_arg_str.is_empty() {
+                        if !self.last_args.is_empty() {
                             abort!(span, "vector variable can only be used alone");
                         }
                         self.args.push(ParseArg::ArgVec(quote!(#var)));
@@ -378,15 +1014,18 @@ impl Lexer {
         self.iter.next();
     }
 
-    fn check_append(&mut self) -> bool {
-        let mut append = false;
+    // ">>" appends and ">|" truncates even with noclobber, after the first '>' is scanned
+    fn check_write_mode(&mut self) -> WriteMode {
+        let mut mode = WriteMode::Truncate;
         if let Some(TokenTree::Punct(p)) = self.iter.peek_no_gap() {
-            if p.as_char() == '>' {
-                append = true;
-                self.iter.next();
+            match p.as_char() {
+                '>' => mode = WriteMode::Append,
+                '|' => mode = WriteMode::Clobber,
+                _ => return mode,
             }
+            self.iter.next();
         }
-        append
+        mode
     }
 }
 
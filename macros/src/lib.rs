@@ -1,5 +1,5 @@
 use proc_macro2::{Span, TokenStream, TokenTree};
-use proc_macro_error::{abort, proc_macro_error};
+use proc_macro_error::{abort, abort_call_site, proc_macro_error};
 use quote::{quote, ToTokens};
 
 /// export the function as an command to be run by `run_cmd!` or `run_fun!`
@@ -20,7 +20,6 @@ use quote::{quote, ToTokens};
 /// # Ok::<(), std::io::Error>(())
 /// ```
 /// Here we export function `foo` as `my_cmd` command.
-
 #[proc_macro_attribute]
 pub fn export_cmd(
     attr: proc_macro::TokenStream,
@@ -84,9 +83,14 @@ pub fn use_custom_cmd(item: proc_macro::TokenStream) -> proc_macro::TokenStream
 /// ```
 /// # use cmd_lib::*;
 /// use_builtin_cmd!(info); // import only one builtin command
-/// use_builtin_cmd!(echo, info, warn, err, die, cat); // import all the builtins
+/// // import all the builtins
+/// use_builtin_cmd!(
+///     echo, info, warn, err, die, cat, pwd, tee, seq, sleep, printf, test, head, tail, xargs,
+///     grep, cut, sort, uniq, tr, env, cp, mv, rm, mkdir, wc, basename, dirname, which
+/// );
 /// ```
-/// `cd` builtin command is always enabled without importing it.
+/// `cd` builtin command is always enabled without importing it, and importing `test` enables
+/// `[ expr ]` as well. `date` is only available with the `date` feature.
 #[proc_macro]
 #[proc_macro_error]
 pub fn use_builtin_cmd(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
@@ -101,6 +105,9 @@ pub fn use_builtin_cmd(item: proc_macro::TokenStream) -> proc_macro::TokenStream
             let cmd_name = cmd.to_string();
             let cmd_fn = syn::Ident::new(&format!("builtin_{}", cmd_name), Span::call_site());
             ret.extend(quote!(::cmd_lib::export_cmd(#cmd_name, ::cmd_lib::#cmd_fn);));
+            if cmd_name == "test" {
+                ret.extend(quote!(::cmd_lib::export_cmd("[", ::cmd_lib::#cmd_fn);));
+            }
         } else {
             abort!(t, "expect a list of comma separated commands");
         }
@@ -138,10 +145,14 @@ pub fn use_builtin_cmd(item: proc_macro::TokenStream) -> proc_macro::TokenStream
 #[proc_macro]
 #[proc_macro_error]
 pub fn run_cmd(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let cmds = lexer::Lexer::new(input.into()).scan().parse(false);
+    let parser = lexer::Lexer::new(input.into()).scan();
+    if parser.is_background() {
+        return spawn_background(parser.parse(true), false);
+    }
+    let cmds = parser.parse(false);
     quote! ({
         use ::cmd_lib::AsOsStr;
-        #cmds.run_cmd()
+        #cmds.and_then(|mut cmds| cmds.run_cmd())
     })
     .into()
 }
@@ -160,14 +171,101 @@ pub fn run_cmd(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
 #[proc_macro]
 #[proc_macro_error]
 pub fn run_fun(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let cmds = lexer::Lexer::new(input.into()).scan().parse(false);
+    let parser = lexer::Lexer::new(input.into()).scan();
+    if parser.is_background() {
+        return spawn_background(parser.parse(true), true);
+    }
+    let cmds = parser.parse(false);
+    quote! ({
+        use ::cmd_lib::AsOsStr;
+        #cmds.and_then(|mut cmds| cmds.run_fun())
+    })
+    .into()
+}
+
+/// Run commands like `run_cmd!`, retrying them on failure
+///
+/// It takes the number of attempts and the delay between them before `;`, and optionally a
+/// factor to multiply the delay by after each failed attempt for exponential backoff. The error
+/// of the last attempt is returned if all of them fail.
+/// ```no_run
+/// # use cmd_lib::run_cmd_retry;
+/// use std::time::Duration;
+/// let url = "https://example.com";
+/// // up to 3 attempts, waiting 1s and then 2s between them
+/// run_cmd_retry!(3, Duration::from_secs(1), 2.0; curl -sSf $url > /tmp/page.html)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[proc_macro]
+#[proc_macro_error]
+pub fn run_cmd_retry(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let (opts, input) = parse_opts(
+        input.into(),
+        2..=3,
+        "(times, delay) or (times, delay, factor)",
+    );
+    let times = &opts[0];
+    let delay = &opts[1];
+    let factor = opts.get(2).cloned().unwrap_or_else(|| quote!(1.0));
+
+    let parser = lexer::Lexer::new(input).scan();
+    if parser.is_background() {
+        abort_call_site!("invalid '&' at the end: retrying commands in the background");
+    }
+    let cmds = parser.parse(false);
     quote! ({
         use ::cmd_lib::AsOsStr;
-        #cmds.run_fun()
+        ::cmd_lib::run_with_retry(#times, #delay, #factor, || #cmds)
     })
     .into()
 }
 
+/// Run commands with/without pipes like `run_cmd!`, killing them if they don't finish in time
+///
+/// It takes the timeout as a `Duration` before `;`. When it expires, all the commands still
+/// running are killed, and an error with `ErrorKind::TimedOut` is returned, including the full
+/// command line. Same as `spawn!`, group commands are not supported.
+/// ```no_run
+/// # use cmd_lib::run_cmd_timeout;
+/// use std::time::Duration;
+/// run_cmd_timeout!(Duration::from_secs(600); cargo test --workspace | tee test.log)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[proc_macro]
+#[proc_macro_error]
+pub fn run_cmd_timeout(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let (opts, input) = parse_opts(input.into(), 1..=1, "(timeout)");
+    let timeout = &opts[0];
+    let cmds = parse_spawn(input);
+    quote! ({
+        use ::cmd_lib::AsOsStr;
+        #cmds.and_then(|cmds| cmds.spawn(false)?.wait_timeout(#timeout))
+    })
+    .into()
+}
+
+// split the comma separated options before ';' from the commands
+fn parse_opts(
+    input: TokenStream,
+    count: std::ops::RangeInclusive<usize>,
+    usage: &str,
+) -> (Vec<TokenStream>, TokenStream) {
+    let mut iter = input.into_iter();
+    let mut opts: Vec<TokenStream> = vec![TokenStream::new()];
+    loop {
+        match iter.next() {
+            Some(TokenTree::Punct(p)) if p.as_char() == ';' => break,
+            Some(TokenTree::Punct(p)) if p.as_char() == ',' => opts.push(TokenStream::new()),
+            Some(tt) => opts.last_mut().unwrap().extend([tt]),
+            None => abort_call_site!("expect options before ';' as {}", usage),
+        }
+    }
+    if opts.iter().any(|opt| opt.is_empty()) || !count.contains(&opts.len()) {
+        abort_call_site!("expect options before ';' as {}", usage);
+    }
+    (opts, iter.collect())
+}
+
 /// Run commands with/without pipes as a child process, returning a handle to check the final
 /// result
 /// ```
@@ -182,10 +280,10 @@ pub fn run_fun(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
 #[proc_macro]
 #[proc_macro_error]
 pub fn spawn(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let cmds = lexer::Lexer::new(input.into()).scan().parse(true);
+    let cmds = parse_spawn(input.into());
     quote! ({
         use ::cmd_lib::AsOsStr;
-        #cmds.spawn(false)
+        #cmds.and_then(|cmds| cmds.spawn(false))
     })
     .into()
 }
@@ -212,10 +310,32 @@ pub fn spawn(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
 #[proc_macro]
 #[proc_macro_error]
 pub fn spawn_with_output(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let cmds = lexer::Lexer::new(input.into()).scan().parse(true);
+    let cmds = parse_spawn(input.into());
+    quote! ({
+        use ::cmd_lib::AsOsStr;
+        #cmds.and_then(|cmds| cmds.spawn_with_output())
+    })
+    .into()
+}
+
+fn parse_spawn(input: TokenStream) -> TokenStream {
+    let parser = lexer::Lexer::new(input).scan();
+    if parser.is_background() {
+        abort_call_site!("invalid '&' at the end: already running in the background");
+    }
+    parser.parse(true)
+}
+
+// commands ending with '&' in run_cmd!/run_fun!, the same as spawn!/spawn_with_output!
+fn spawn_background(cmds: TokenStream, with_output: bool) -> proc_macro::TokenStream {
+    let spawn = if with_output {
+        quote!(spawn_with_output())
+    } else {
+        quote!(spawn(false))
+    };
     quote! ({
         use ::cmd_lib::AsOsStr;
-        #cmds.spawn_with_output()
+        #cmds.and_then(|cmds| cmds.#spawn)
     })
     .into()
 }
@@ -1,53 +1,119 @@
-use proc_macro2::TokenStream;
-use quote::quote;
+use proc_macro2::{Span, TokenStream};
+use quote::{quote, quote_spanned};
 use std::iter::Peekable;
 
+// how an output redirect opens its file: `>`, `>>` or `>|`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteMode {
+    Truncate,
+    Append,
+    Clobber,
+}
+
 #[derive(Debug)]
 pub enum ParseArg {
     Pipe,
     Semicolon,
-    RedirectFd(i32, i32),                 // fd1, fd2
-    RedirectFile(i32, TokenStream, bool), // fd1, file, append?
+    And,
+    Or,
+    RedirectFd(i32, i32),                      // fd1, fd2
+    RedirectFile(i32, TokenStream, WriteMode), // fd1, file, mode
+    RedirectStr(TokenStream),                  // here-string
+    RedirectDoc(TokenStream),                  // heredoc
+    RedirectVar(TokenStream),                  // stdin from a variable
     ArgStr(TokenStream),
+    ArgGlob(TokenStream),
     ArgVec(TokenStream),
 }
 
 pub struct Parser<I: Iterator<Item = ParseArg>> {
     iter: Peekable<I>,
+    fallible: bool,
+    background: bool,
+    warnings: Vec<(Span, &'static str)>,
 }
 
 impl<I: Iterator<Item = ParseArg>> Parser<I> {
-    pub fn from(iter: Peekable<I>) -> Self {
-        Self { iter }
+    pub fn from(iter: Peekable<I>, fallible: bool) -> Self {
+        Self {
+            iter,
+            fallible,
+            background: false,
+            warnings: vec![],
+        }
+    }
+
+    pub fn warnings(mut self, warnings: Vec<(Span, &'static str)>) -> Self {
+        self.warnings = warnings;
+        self
+    }
+
+    pub fn background(mut self, background: bool) -> Self {
+        self.background = background;
+        self
+    }
+
+    // ended with '&', the commands should be spawned instead of waited for
+    pub fn is_background(&self) -> bool {
+        self.background
     }
 
+    // returns `Result<GroupCmds>`, the arguments could fail with command substitution
     pub fn parse(mut self, for_spawn: bool) -> TokenStream {
         let mut ret = quote!(::cmd_lib::GroupCmds::default());
+        let mut method = quote!(append);
         while self.iter.peek().is_some() {
-            let cmd = self.parse_cmd();
+            let (cmd, next_method) = self.parse_cmd();
             if !cmd.is_empty() {
-                ret.extend(quote!(.append(#cmd)));
+                ret.extend(quote!(.#method(#cmd)));
                 assert!(
                     !(for_spawn && self.iter.peek().is_some()),
                     "wrong spawning format: group command not allowed"
                 );
             }
+            method = next_method;
         }
-        ret
+        let ret = if self.fallible {
+            quote!((|| -> ::std::io::Result<::cmd_lib::GroupCmds> { Ok(#ret) })())
+        } else {
+            quote!(::std::io::Result::Ok(#ret))
+        };
+        if self.warnings.is_empty() {
+            return ret;
+        }
+        // proc macros can't emit warnings on stable, so use a deprecated item at the token
+        let warnings = self.warnings.iter().map(|(span, msg)| {
+            quote_spanned! {*span=>
+                #[deprecated(note = #msg)]
+                struct UnhandledShellSyntax;
+                let _ = UnhandledShellSyntax;
+            }
+        });
+        quote!({
+            #({ #warnings })*
+            #ret
+        })
     }
 
-    fn parse_cmd(&mut self) -> TokenStream {
+    // returns the commands and the GroupCmds method to add the next ones, depending on whether
+    // it is followed by '&&' or '||'
+    fn parse_cmd(&mut self) -> (TokenStream, TokenStream) {
         let mut cmds = quote!(::cmd_lib::Cmds::default());
+        let mut next_method = quote!(append);
         while self.iter.peek().is_some() {
             let cmd = self.parse_pipe();
             cmds.extend(quote!(.pipe(#cmd)));
             if !matches!(self.iter.peek(), Some(ParseArg::Pipe)) {
-                self.iter.next();
+                match self.iter.next() {
+                    Some(ParseArg::And) => next_method = quote!(and),
+                    Some(ParseArg::Or) => next_method = quote!(or),
+                    _ => {}
+                }
                 break;
             }
             self.iter.next();
         }
-        cmds
+        (cmds, next_method)
     }
 
     fn parse_pipe(&mut self) -> TokenStream {
@@ -60,32 +126,59 @@ impl<I: Iterator<Item = ParseArg>> Parser<I> {
                         match (fd1, fd2) {
                             (1, 2) => redirect.extend(quote!(::StdoutToStderr)),
                             (2, 1) => redirect.extend(quote!(::StderrToStdout)),
-                            _ => panic!("unsupported fd numbers: {} {}", fd1, fd2),
+                            _ => redirect.extend(quote!(::FdToFd(#fd1, #fd2))),
                         }
                         ret.extend(quote!(.add_redirect(#redirect)));
                     }
                 }
-                ParseArg::RedirectFile(fd1, file, append) => {
+                ParseArg::RedirectFile(fd1, file, mode) => {
+                    let append = *mode == WriteMode::Append;
                     let mut redirect = quote!(::cmd_lib::Redirect);
                     match fd1 {
                         0 => redirect.extend(quote!(::FileToStdin(#file.into_path_buf()))),
+                        _ if *mode == WriteMode::Clobber => redirect.extend(quote!(
+                            ::FdToFileForce(#fd1, #file.into_path_buf())
+                        )),
                         1 => {
                             redirect.extend(quote!(::StdoutToFile(#file.into_path_buf(), #append)))
                         }
                         2 => {
                             redirect.extend(quote!(::StderrToFile(#file.into_path_buf(), #append)))
                         }
-                        _ => panic!("unsupported fd ({}) redirect to file {}", fd1, file),
+                        _ => redirect.extend(quote!(::FdToFile(
+                            #fd1,
+                            #file.into_path_buf(),
+                            #append
+                        ))),
                     }
                     ret.extend(quote!(.add_redirect(#redirect)));
                 }
+                ParseArg::RedirectStr(s) => {
+                    ret.extend(quote!(.add_redirect(
+                        ::cmd_lib::Redirect::StringToStdin(#s.to_string())
+                    )));
+                }
+                ParseArg::RedirectDoc(s) => {
+                    ret.extend(quote!(.add_redirect(
+                        ::cmd_lib::Redirect::HereDocToStdin(#s.to_string())
+                    )));
+                }
+                ParseArg::RedirectVar(var) => {
+                    ret.extend(quote!(.add_redirect({
+                        use ::cmd_lib::{StdinBytesVar as _, StdinPathVar as _, StdinStrVar as _};
+                        (&&::cmd_lib::StdinVar(&#var)).stdin_redirect()
+                    })));
+                }
                 ParseArg::ArgStr(opt) => {
                     ret.extend(quote!(.add_arg(#opt)));
                 }
+                ParseArg::ArgGlob(pattern) => {
+                    ret.extend(quote!(.add_glob(#pattern)));
+                }
                 ParseArg::ArgVec(opts) => {
                     ret.extend(quote! (.add_args(#opts)));
                 }
-                ParseArg::Pipe | ParseArg::Semicolon => break,
+                ParseArg::Pipe | ParseArg::Semicolon | ParseArg::And | ParseArg::Or => break,
             }
             self.iter.next();
         }
@@ -0,0 +1,127 @@
+use std::ffi::OsStr;
+use std::io::{Error, Result};
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Evaluates the integer expression of arithmetic expansion `$((...))`
+///
+/// It supports `+ - * / % ( )` and unary `+`/`-` on 64-bit integers, with the usual precedence.
+/// The variables are already substituted by the macros, so any operand which is not an integer
+/// is an error, as well as division by zero and overflow.
+#[doc(hidden)]
+pub fn eval_arith<S: AsRef<OsStr>>(expr: S) -> Result<i64> {
+    let expr = expr.as_ref().to_string_lossy();
+    let mut parser = ArithParser {
+        expr: &expr,
+        chars: expr.chars().peekable(),
+    };
+    let value = parser.parse_sum()?;
+    match parser.next_token() {
+        None => Ok(value),
+        Some(c) => Err(parser.error(&format!("unexpected '{}'", c))),
+    }
+}
+
+struct ArithParser<'a> {
+    expr: &'a str,
+    chars: Peekable<Chars<'a>>,
+}
+
+impl ArithParser<'_> {
+    fn error(&self, msg: &str) -> Error {
+        Error::other(format!(
+            "arithmetic expansion: {}: {}",
+            msg,
+            self.expr.trim()
+        ))
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn peek_token(&mut self) -> Option<char> {
+        self.skip_spaces();
+        self.chars.peek().copied()
+    }
+
+    fn next_token(&mut self) -> Option<char> {
+        self.skip_spaces();
+        self.chars.next()
+    }
+
+    // sum := product (('+' | '-') product)*
+    fn parse_sum(&mut self) -> Result<i64> {
+        let mut value = self.parse_product()?;
+        while let Some(op @ ('+' | '-')) = self.peek_token() {
+            self.chars.next();
+            let rhs = self.parse_product()?;
+            let ret = if op == '+' {
+                value.checked_add(rhs)
+            } else {
+                value.checked_sub(rhs)
+            };
+            value = ret.ok_or_else(|| self.error("overflow"))?;
+        }
+        Ok(value)
+    }
+
+    // product := unary (('*' | '/' | '%') unary)*
+    fn parse_product(&mut self) -> Result<i64> {
+        let mut value = self.parse_unary()?;
+        while let Some(op @ ('*' | '/' | '%')) = self.peek_token() {
+            self.chars.next();
+            let rhs = self.parse_unary()?;
+            if op != '*' && rhs == 0 {
+                return Err(self.error("division by zero"));
+            }
+            let ret = match op {
+                '*' => value.checked_mul(rhs),
+                '/' => value.checked_div(rhs),
+                _ => value.checked_rem(rhs),
+            };
+            value = ret.ok_or_else(|| self.error("overflow"))?;
+        }
+        Ok(value)
+    }
+
+    // unary := ('+' | '-') unary | '(' sum ')' | integer
+    fn parse_unary(&mut self) -> Result<i64> {
+        match self.peek_token() {
+            Some('+') => {
+                self.chars.next();
+                self.parse_unary()
+            }
+            Some('-') => {
+                self.chars.next();
+                let value = self.parse_unary()?;
+                value.checked_neg().ok_or_else(|| self.error("overflow"))
+            }
+            Some('(') => {
+                self.chars.next();
+                let value = self.parse_sum()?;
+                match self.next_token() {
+                    Some(')') => Ok(value),
+                    _ => Err(self.error("missing ')'")),
+                }
+            }
+            Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '.' => {
+                let mut operand = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if !c.is_ascii_alphanumeric() && c != '_' && c != '.' {
+                        break;
+                    }
+                    operand.push(c);
+                    self.chars.next();
+                }
+                operand
+                    .parse()
+                    .map_err(|_| self.error(&format!("not an integer: {}", operand)))
+            }
+            Some(c) => Err(self.error(&format!("unexpected '{}'", c))),
+            None => Err(self.error("missing operand")),
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Building the commands from runtime data, without the macros
+//!
+//! The macros need the commands to be known at compile time, apart from the variables. When the
+//! whole argv comes from the users or the configuration, [`Pipeline`] assembles the same commands
+//! programmatically:
+//!
+//! ```no_run
+//! # use cmd_lib::builder::Pipeline;
+//! let pattern = "foo";
+//! let output = Pipeline::new()
+//!     .command(["ls", "-l"])
+//!     .pipe(["grep", pattern])
+//!     .run_fun()?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! Each argument is passed as it is, so unlike the macros, a leading `with_dir`, `ignore` or
+//! `VAR=value` is not treated specially, and [`Pipeline::ignore_error`] ignores the failures
+//! instead. The builtin and registered custom commands are still found by their names, and the
+//! errors and pipefail are the same as running the macros.
+use crate::child::{CmdChildren, FunChildren};
+use crate::process::{Cmd, Cmds, GroupCmds};
+use crate::{CmdEnv, CmdResult, FunResult};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+
+/// A list of pipelines to run one after another, like the commands separated by `;` in the macros
+#[derive(Default)]
+pub struct Pipeline {
+    group_cmds: Vec<Vec<Cmd>>,
+    pipefail: Option<bool>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a new pipeline with the command, running after the previous ones
+    pub fn command<I, O>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = O>,
+        O: AsRef<OsStr>,
+    {
+        self.group_cmds.push(vec![Self::cmd(args)]);
+        self
+    }
+
+    /// Pipes the output of the last command into the command, same as `|`
+    ///
+    /// Without any previous command, it is the same as [`Pipeline::command`].
+    pub fn pipe<I, O>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = O>,
+        O: AsRef<OsStr>,
+    {
+        match self.group_cmds.last_mut() {
+            Some(cmds) => cmds.push(Self::cmd(args)),
+            None => return self.command(args),
+        }
+        self
+    }
+
+    /// Pipes the output of the last command into the closure, running on a thread like a custom
+    /// command
+    ///
+    /// Unlike the registered custom commands, the closure can capture and update the local state,
+    /// e.g. counting the lines it passes through.
+    pub fn pipe_fn<F>(mut self, func: F) -> Self
+    where
+        F: FnMut(&mut CmdEnv) -> CmdResult + Send + 'static,
+    {
+        match self.group_cmds.last_mut() {
+            Some(cmds) => cmds.push(Cmd::from_fn(func)),
+            None => self.group_cmds.push(vec![Cmd::from_fn(func)]),
+        }
+        self
+    }
+
+    /// Ignores the failure of the last command added
+    ///
+    /// Only that command is affected, same as the `ignore` prefix in the middle of a pipeline, so
+    /// the failures of the other commands are still reported.
+    pub fn ignore_error(mut self) -> Self {
+        if let Some(cmd) = self.group_cmds.last_mut().and_then(|cmds| cmds.pop()) {
+            self.group_cmds.last_mut().unwrap().push(cmd.ignore_error());
+        }
+        self
+    }
+
+    /// Overrides the global pipefail setting for all the pipelines
+    pub fn pipefail(mut self, enable: bool) -> Self {
+        self.pipefail = Some(enable);
+        self
+    }
+
+    /// Runs the pipelines, same as `run_cmd!`
+    pub fn run(self) -> CmdResult {
+        self.into_group_cmds()?.run_cmd()
+    }
+
+    /// Runs the pipelines and captures the output of the last one, same as `run_fun!`
+    pub fn run_fun(self) -> FunResult {
+        self.into_group_cmds()?.run_fun()
+    }
+
+    /// Spawns the only pipeline in the background, same as `spawn!`
+    pub fn spawn(self) -> Result<CmdChildren> {
+        self.check_spawn()?;
+        self.into_group_cmds()?.spawn(false)
+    }
+
+    /// Spawns the only pipeline in the background with its output captured, same as
+    /// `spawn_with_output!`
+    pub fn spawn_with_output(self) -> Result<FunChildren> {
+        self.check_spawn()?;
+        self.into_group_cmds()?.spawn_with_output()
+    }
+
+    /// Spawns the only pipeline in the background with the output of the last command written
+    /// into the file directly, without going through a pipe
+    pub fn spawn_to(self, out: File) -> Result<CmdChildren> {
+        self.check_spawn()?;
+        self.into_group_cmds()?.spawn_to(out)
+    }
+
+    /// Spawns the only pipeline in the background with its stderr captured, while the output goes
+    /// to stdout of the current process, to be waited with `FunChildren::wait_stderr_captured()`
+    pub fn spawn_stderr_captured(self) -> Result<FunChildren> {
+        self.check_spawn()?;
+        self.into_group_cmds()?.spawn_stderr_captured()
+    }
+
+    fn cmd<I, O>(args: I) -> Cmd
+    where
+        I: IntoIterator<Item = O>,
+        O: AsRef<OsStr>,
+    {
+        args.into_iter()
+            .fold(Cmd::default(), |cmd, arg| cmd.add_literal_arg(arg))
+    }
+
+    // same as the spawning macros, which only accept one pipeline
+    fn check_spawn(&self) -> CmdResult {
+        if self.group_cmds.len() != 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "only one pipeline can be spawned, found {}",
+                    self.group_cmds.len()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    // an empty argv has no program to run, which the macros reject at compile time
+    fn into_group_cmds(self) -> Result<GroupCmds> {
+        if self.group_cmds.iter().flatten().any(Cmd::is_empty) {
+            return Err(Error::new(ErrorKind::InvalidInput, "empty command"));
+        }
+        let mut group_cmds = GroupCmds::default();
+        if let Some(pipefail) = self.pipefail {
+            group_cmds = group_cmds.pipefail(pipefail);
+        }
+        Ok(self
+            .group_cmds
+            .into_iter()
+            .fold(group_cmds, |group_cmds, cmds| {
+                group_cmds.append(cmds.into_iter().fold(Cmds::default(), Cmds::pipe))
+            }))
+    }
+}
@@ -1,12 +1,74 @@
-use crate::{CmdEnv, CmdResult};
+use crate::io::CmdIn;
+use crate::process::env_inherit_enabled;
+use crate::{Cmd, CmdEnv, CmdResult};
+use faccess::{AccessMode, PathExt};
 use log::*;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use regex::RegexBuilder;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SLEEP_INTERVAL: Duration = Duration::from_millis(10);
 
 #[doc(hidden)]
 pub fn builtin_echo(env: &mut CmdEnv) -> CmdResult {
-    let msg = env.args()[1..].join(" ");
-    writeln!(env.stdout(), "{}", msg)
+    // same as bash, the options are only the leading arguments like "-n", "-e" or "-ne"
+    let (mut newline, mut escapes) = (true, false);
+    let mut args = &env.args()[1..];
+    while let Some(flags) = args
+        .first()
+        .and_then(|arg| arg.strip_prefix('-'))
+        .filter(|flags| !flags.is_empty() && flags.chars().all(|c| "neE".contains(c)))
+    {
+        for c in flags.chars() {
+            match c {
+                'n' => newline = false,
+                'e' => escapes = true,
+                _ => escapes = false,
+            }
+        }
+        args = &args[1..];
+    }
+
+    let msg = args.join(" ");
+    let mut out = if escapes {
+        let mut out = String::new();
+        let mut chars = msg.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => push_escape(&mut out, &mut chars),
+                c => out.push(c),
+            }
+        }
+        out
+    } else {
+        msg
+    };
+    if newline {
+        out.push('\n');
+    }
+    env.write_stdout(&out)
+}
+
+// the character escaped by a backslash, or the backslash itself if it is not an escape
+fn push_escape(out: &mut String, chars: &mut impl Iterator<Item = char>) {
+    match chars.next() {
+        Some('n') => out.push('\n'),
+        Some('t') => out.push('\t'),
+        Some('r') => out.push('\r'),
+        Some('0') => out.push('\0'),
+        Some('\\') => out.push('\\'),
+        Some('"') => out.push('"'),
+        Some('\'') => out.push('\''),
+        Some(c) => {
+            out.push('\\');
+            out.push(c);
+        }
+        None => out.push('\\'),
+    }
 }
 
 #[doc(hidden)]
@@ -47,17 +109,1399 @@ pub fn builtin_trace(env: &mut CmdEnv) -> CmdResult {
 
 #[doc(hidden)]
 pub fn builtin_cat(env: &mut CmdEnv) -> CmdResult {
-    if env.args().len() == 1 {
-        let mut buf = vec![];
-        env.stdin().read_to_end(&mut buf)?;
-        env.stdout().write_all(&buf)?;
-        return Ok(());
+    let mut buf = [0; 8192];
+    let files = match env.args() {
+        [_] => vec!["-".to_string()],
+        [_, files @ ..] => files.to_vec(),
+        [] => vec![],
+    };
+    let mut ret = Ok(());
+    for arg in files {
+        let copied = if arg == "-" {
+            cat_copy(env, None, &mut buf)
+        } else {
+            let file = file_op_path(env, &arg);
+            File::open(file).and_then(|mut file| cat_copy(env, Some(&mut file), &mut buf))
+        };
+        // report the error and continue with the next file, same as cat
+        if let Err(e) = copied {
+            writeln!(env.stderr(), "cat: {}: {}", arg, e)?;
+            if ret.is_ok() {
+                ret = Err(Error::new(e.kind(), format!("cat: {}: {}", arg, e)));
+            }
+        }
+    }
+    ret
+}
+
+// copy from the file or stdin to stdout in chunks
+fn cat_copy(env: &mut CmdEnv, mut file: Option<&mut File>, buf: &mut [u8]) -> CmdResult {
+    loop {
+        let n = match file {
+            Some(ref mut file) => file.read(buf)?,
+            None => env.stdin().read(buf)?,
+        };
+        if n == 0 {
+            return Ok(());
+        }
+        env.stdout().write_all(&buf[..n])?;
+    }
+}
+
+#[doc(hidden)]
+pub fn builtin_head(env: &mut CmdEnv) -> CmdResult {
+    let (lines, _, file) = lines_args(env, "head")?;
+
+    // stdin is closed when returning early, so the upstream commands get a broken pipe and stop
+    match file.filter(|file| file != "-") {
+        None => head_copy(env, None, lines),
+        Some(arg) => {
+            let file = file_op_path(env, &arg);
+            File::open(file)
+                .and_then(|mut file| head_copy(env, Some(&mut file), lines))
+                .map_err(|e| Error::new(e.kind(), format!("head: {}: {}", arg, e)))
+        }
     }
+}
 
-    let mut file = PathBuf::from(env.args()[1].to_owned());
-    if file.is_relative() {
-        file = PathBuf::from(env.current_dir()).join(file);
+#[doc(hidden)]
+pub fn builtin_tail(env: &mut CmdEnv) -> CmdResult {
+    let (lines, follow, file) = lines_args(env, "tail")?;
+
+    match file.filter(|file| file != "-") {
+        // same as tail, following a pipe only reads it to the end
+        None => {
+            let last_lines = tail_lines(&mut BufReader::new(env.stdin()), lines)?;
+            tail_write(env, last_lines)
+        }
+        Some(arg) => {
+            let file = file_op_path(env, &arg);
+            let err = |e: Error| Error::new(e.kind(), format!("tail: {}: {}", arg, e));
+            let mut reader = BufReader::new(File::open(file).map_err(err)?);
+            let last_lines = tail_lines(&mut reader, lines).map_err(err)?;
+            tail_write(env, last_lines)?;
+            if follow {
+                tail_follow(env, &mut reader).map_err(err)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// parse the options of head and tail: `-n N`, `-nN`, `-N` and `-f` for tail only
+fn lines_args(env: &CmdEnv, cmd: &str) -> Result<(usize, bool, Option<String>)> {
+    let mut lines = 10;
+    let mut follow = false;
+    let mut file = None;
+    let mut args = env.args()[1..].iter();
+    while let Some(arg) = args.next() {
+        let count = if arg == "-n" {
+            args.next().ok_or_else(|| {
+                Error::other(format!("{}: option requires an argument -- 'n'", cmd))
+            })?
+        } else if let Some(count) = arg.strip_prefix("-n") {
+            count
+        } else if arg == "-f" && cmd == "tail" {
+            follow = true;
+            continue;
+        } else if arg != "-" && arg.starts_with('-') {
+            &arg[1..]
+        } else if file.is_none() {
+            file = Some(arg.clone());
+            continue;
+        } else {
+            return Err(Error::other(format!("{}: expect at most 1 file", cmd)));
+        };
+        lines = count
+            .parse()
+            .map_err(|_| Error::other(format!("{}: invalid number of lines: {}", cmd, count)))?;
+    }
+    Ok((lines, follow, file))
+}
+
+// copy the first lines from the file or stdin to stdout
+fn head_copy(env: &mut CmdEnv, mut file: Option<&mut File>, mut lines: usize) -> CmdResult {
+    let mut buf = [0; 8192];
+    while lines > 0 {
+        let n = match file {
+            Some(ref mut file) => file.read(&mut buf)?,
+            None => env.stdin().read(&mut buf)?,
+        };
+        if n == 0 {
+            break;
+        }
+        let mut end = n;
+        for (i, _) in buf[..n].iter().enumerate().filter(|(_, b)| **b == b'\n') {
+            lines -= 1;
+            if lines == 0 {
+                end = i + 1;
+                break;
+            }
+        }
+        env.stdout().write_all(&buf[..end])?;
+    }
+    Ok(())
+}
+
+// keep the last lines in a ring buffer, reading to the end
+fn tail_lines(reader: &mut impl BufRead, lines: usize) -> Result<VecDeque<Vec<u8>>> {
+    let mut last_lines = VecDeque::with_capacity(lines.min(1024));
+    loop {
+        let mut line = vec![];
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(last_lines);
+        }
+        if lines == 0 {
+            continue;
+        }
+        if last_lines.len() == lines {
+            last_lines.pop_front();
+        }
+        last_lines.push_back(line);
+    }
+}
+
+fn tail_write(env: &mut CmdEnv, lines: VecDeque<Vec<u8>>) -> CmdResult {
+    let mut out = BufWriter::new(env.stdout());
+    for line in lines {
+        out.write_all(&line)?;
+    }
+    out.flush()
+}
+
+// stream the appended content until being killed
+fn tail_follow(env: &mut CmdEnv, reader: &mut impl Read) -> CmdResult {
+    let mut buf = [0; 8192];
+    loop {
+        if env.is_killed() {
+            return Err(Error::new(ErrorKind::Interrupted, "interrupted"));
+        }
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            thread::sleep(SLEEP_INTERVAL);
+            continue;
+        }
+        env.stdout().write_all(&buf[..n])?;
+    }
+}
+
+#[doc(hidden)]
+pub fn builtin_xargs(env: &mut CmdEnv) -> Result<i32> {
+    let mut batch_size = usize::MAX;
+    let mut args = env.args()[1..].iter();
+    let mut cmd = vec![];
+    while let Some(arg) = args.next() {
+        let count = if arg == "-n" {
+            args.next()
+                .ok_or_else(|| Error::other("xargs: option requires an argument -- 'n'"))?
+        } else if let Some(count) = arg.strip_prefix("-n") {
+            count
+        } else if arg == "-r" {
+            // no command is run for empty input anyway
+            continue;
+        } else {
+            cmd.push(arg.clone());
+            cmd.extend(args.cloned());
+            break;
+        };
+        batch_size = count
+            .parse()
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| Error::other(format!("xargs: invalid number: {}", count)))?;
+    }
+    if cmd.is_empty() {
+        cmd.push("echo".into());
+    }
+
+    // same as xargs, keep running the other batches on failure, and exit with 123
+    let input = env.stdin_string()?;
+    let items: Vec<&str> = input.split_whitespace().collect();
+    let mut code = 0;
+    for batch in items.chunks(batch_size) {
+        if env.is_killed() {
+            return Err(Error::new(ErrorKind::Interrupted, "xargs: interrupted"));
+        }
+        let batch_cmd =
+            Cmd::default().add_args(cmd.iter().map(String::as_str).chain(batch.iter().copied()));
+        // same as xargs, the commands don't read the remaining input
+        if let Err(e) = env.run_cmd(batch_cmd, CmdIn::Null) {
+            writeln!(env.stderr(), "xargs: {}", e)?;
+            code = 123;
+        }
+    }
+    Ok(code)
+}
+
+#[doc(hidden)]
+pub fn builtin_env(env: &mut CmdEnv) -> CmdResult {
+    let args = env.args()[1..].to_vec();
+    let mut cmd = vec![];
+    for arg in args {
+        if !cmd.is_empty() {
+            cmd.push(arg);
+        } else if let Some((key, value)) = arg.split_once('=') {
+            env.set_var(key.into(), value.into());
+        } else if arg.starts_with('-') {
+            return Err(Error::other(format!("env: invalid option -- '{}'", arg)));
+        } else {
+            cmd.push(arg);
+        }
+    }
+    if !cmd.is_empty() {
+        return env.run(cmd);
+    }
+
+    // the variables passed to the command override the inherited ones
+    let mut vars = BTreeMap::new();
+    if env_inherit_enabled() {
+        for (key, value) in std::env::vars_os() {
+            vars.insert(
+                key.to_string_lossy().to_string(),
+                value.to_string_lossy().to_string(),
+            );
+        }
+    }
+    for key in env.removed_vars() {
+        vars.remove(key);
+    }
+    vars.extend(env.vars().map(|(key, value)| (key.clone(), value.clone())));
+    let mut stdout = env.stdout();
+    for (key, value) in vars {
+        writeln!(stdout, "{}={}", key, value)?;
     }
-    env.stdout().write_all(&std::fs::read(file)?)?;
     Ok(())
 }
+
+#[doc(hidden)]
+pub fn builtin_cp(env: &mut CmdEnv) -> CmdResult {
+    let (flags, files) = flag_args(env, "cp", "rRf", false)?;
+    let recursive = flags.contains(['r', 'R']);
+    let force = flags.contains('f');
+    copy_or_move(env, "cp", files, |src, dst| {
+        copy_path(src, dst, recursive, force)
+    })
+}
+
+#[doc(hidden)]
+pub fn builtin_mv(env: &mut CmdEnv) -> CmdResult {
+    // there is no prompt anyway, so "-f" changes nothing
+    let (_, files) = flag_args(env, "mv", "f", false)?;
+    copy_or_move(env, "mv", files, |src, dst| match fs::rename(src, dst) {
+        Err(e) if is_cross_device(&e) => {
+            copy_path(src, dst, true, false)?;
+            if src.is_dir() {
+                fs::remove_dir_all(src)
+            } else {
+                fs::remove_file(src)
+            }
+        }
+        ret => ret,
+    })
+}
+
+// the paths are on different file systems, so the rename can't be done
+fn is_cross_device(e: &Error) -> bool {
+    #[cfg(unix)]
+    let code = libc::EXDEV;
+    // ERROR_NOT_SAME_DEVICE
+    #[cfg(not(unix))]
+    let code = 17;
+    e.raw_os_error() == Some(code)
+}
+
+#[doc(hidden)]
+pub fn builtin_rm(env: &mut CmdEnv) -> CmdResult {
+    let (flags, files) = flag_args(env, "rm", "rRf", false)?;
+    let recursive = flags.contains(['r', 'R']);
+    let force = flags.contains('f');
+    if files.is_empty() && !force {
+        return Err(Error::other("rm: missing operand"));
+    }
+    let mut ret = Ok(());
+    for file in files {
+        let path = file_op_path(env, &file);
+        // symbolic links are removed, instead of the directories they point to
+        let removed = match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() => {
+                if recursive {
+                    fs::remove_dir_all(&path)
+                } else {
+                    Err(Error::other("Is a directory"))
+                }
+            }
+            Ok(_) => fs::remove_file(&path),
+            Err(e) => Err(e),
+        };
+        match removed {
+            Err(e) if force && e.kind() == ErrorKind::NotFound => {}
+            Err(e) => file_op_error(env, &mut ret, "rm", &file, e)?,
+            Ok(()) => {}
+        }
+    }
+    ret
+}
+
+#[doc(hidden)]
+pub fn builtin_mkdir(env: &mut CmdEnv) -> CmdResult {
+    let (flags, dirs) = flag_args(env, "mkdir", "p", false)?;
+    let parents = flags.contains('p');
+    if dirs.is_empty() {
+        return Err(Error::other("mkdir: missing operand"));
+    }
+    let mut ret = Ok(());
+    for dir in dirs {
+        let path = file_op_path(env, &dir);
+        // same as coreutils, "-p" is fine with the existing directories
+        let created = if parents {
+            fs::create_dir_all(&path)
+        } else {
+            fs::create_dir(&path)
+        };
+        if let Err(e) = created {
+            file_op_error(env, &mut ret, "mkdir", &dir, e)?;
+        }
+    }
+    ret
+}
+
+// split the flags like "-rf" from the operands, until "--"; with `operand_ends_flags`, the
+// arguments after the first operand are all operands, like the ones after the grep pattern
+fn flag_args(
+    env: &CmdEnv,
+    cmd: &str,
+    allowed: &str,
+    operand_ends_flags: bool,
+) -> Result<(String, Vec<String>)> {
+    let mut flags = String::new();
+    let mut operands = vec![];
+    let mut args = env.args()[1..].iter();
+    while let Some(arg) = args.next() {
+        if operand_ends_flags && !operands.is_empty() {
+            operands.push(arg.clone());
+            operands.extend(args.cloned());
+            break;
+        }
+        if arg == "--" {
+            operands.extend(args.cloned());
+            break;
+        }
+        match arg.strip_prefix('-').filter(|s| !s.is_empty()) {
+            Some(chars) => {
+                for c in chars.chars() {
+                    if !allowed.contains(c) {
+                        return Err(Error::other(format!("{}: invalid option -- '{}'", cmd, c)));
+                    }
+                    flags.push(c);
+                }
+            }
+            None => operands.push(arg.clone()),
+        }
+    }
+    Ok((flags, operands))
+}
+
+fn file_op_path(env: &CmdEnv, file: &str) -> PathBuf {
+    let mut path = PathBuf::from(file);
+    if path.is_relative() {
+        path = env.current_dir().join(path);
+    }
+    path
+}
+
+// report the error and continue with the next operand, returning the first error at last
+fn file_op_error(
+    env: &mut CmdEnv,
+    ret: &mut CmdResult,
+    cmd: &str,
+    file: &str,
+    e: Error,
+) -> CmdResult {
+    let msg = format!("{}: {}: {}", cmd, file, e);
+    writeln!(env.stderr(), "{}", msg)?;
+    if ret.is_ok() {
+        *ret = Err(Error::new(e.kind(), msg));
+    }
+    Ok(())
+}
+
+// same as cp and mv, the sources go into the target if it is a directory
+fn copy_or_move(
+    env: &mut CmdEnv,
+    cmd: &str,
+    mut files: Vec<String>,
+    op: impl Fn(&Path, &Path) -> CmdResult,
+) -> CmdResult {
+    if files.len() < 2 {
+        return Err(Error::other(format!("{}: missing file operand", cmd)));
+    }
+    let target = files.pop().unwrap();
+    let target_path = file_op_path(env, &target);
+    let into_dir = target_path.is_dir();
+    if files.len() > 1 && !into_dir {
+        return Err(Error::other(format!(
+            "{}: target {} is not a directory",
+            cmd, target
+        )));
+    }
+    let mut ret = Ok(());
+    for file in files {
+        let src = file_op_path(env, &file);
+        let dst = match src.file_name() {
+            Some(name) if into_dir => target_path.join(name),
+            _ => target_path.clone(),
+        };
+        if let Err(e) = op(&src, &dst) {
+            file_op_error(env, &mut ret, cmd, &file, e)?;
+        }
+    }
+    ret
+}
+
+fn copy_path(src: &Path, dst: &Path, recursive: bool, force: bool) -> CmdResult {
+    if src.is_dir() {
+        if !recursive {
+            return Err(Error::other("-r not specified; omitting directory"));
+        }
+        if dst.starts_with(src) {
+            return Err(Error::other("cannot copy a directory into itself"));
+        }
+        fs::create_dir_all(dst)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_path(
+                &entry.path(),
+                &dst.join(entry.file_name()),
+                recursive,
+                force,
+            )?;
+        }
+        return Ok(());
+    }
+    match fs::copy(src, dst) {
+        // same as "cp -f", remove the destination which can not be opened and try again
+        Err(_) if force && src.is_file() && dst.is_file() => {
+            fs::remove_file(dst)?;
+            fs::copy(src, dst).map(|_| ())
+        }
+        ret => ret.map(|_| ()),
+    }
+}
+
+#[doc(hidden)]
+pub fn builtin_grep(env: &mut CmdEnv) -> Result<i32> {
+    let (flags, operands) = flag_args(env, "grep", "vic", true)?;
+    let (invert, ignore_case) = (flags.contains('v'), flags.contains('i'));
+    let count_only = flags.contains('c');
+    let (pattern, file) = match operands.as_slice() {
+        [] => return Err(Error::other("grep: missing pattern")),
+        [pattern] => (pattern.clone(), None),
+        [pattern, file] => (pattern.clone(), Some(file.clone())),
+        _ => return Err(Error::other("grep: expect at most 1 file")),
+    };
+    let re = RegexBuilder::new(&pattern)
+        .case_insensitive(ignore_case)
+        .build()
+        .map_err(|e| Error::other(format!("grep: {}", e)))?;
+
+    let mut count = 0;
+    let mut grep_lines = |reader: &mut dyn BufRead, out: &mut dyn Write| -> CmdResult {
+        let mut line = vec![];
+        loop {
+            line.clear();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                return Ok(());
+            }
+            let text = String::from_utf8_lossy(&line);
+            if re.is_match(text.trim_end_matches('\n')) == invert {
+                continue;
+            }
+            count += 1;
+            if !count_only {
+                out.write_all(&line)?;
+                if !line.ends_with(b"\n") {
+                    writeln!(out)?;
+                }
+            }
+        }
+    };
+    match file.filter(|file| file != "-") {
+        None => {
+            let (stdin, mut stdout) = env.stdin_stdout();
+            grep_lines(&mut BufReader::new(stdin), &mut stdout)?
+        }
+        Some(arg) => {
+            let file = file_op_path(env, &arg);
+            let file = File::open(file)
+                .map_err(|e| Error::new(e.kind(), format!("grep: {}: {}", arg, e)))?;
+            grep_lines(&mut BufReader::new(file), &mut env.stdout())?
+        }
+    }
+    if count_only {
+        writeln!(env.stdout(), "{}", count)?;
+    }
+    // same as grep, exit with 1 if no lines are selected
+    Ok(if count > 0 { 0 } else { 1 })
+}
+
+#[doc(hidden)]
+pub fn builtin_cut(env: &mut CmdEnv) -> CmdResult {
+    let mut delim = '\t';
+    let mut fields = None;
+    let mut chars = None;
+    let mut file = None;
+    let mut args = env.args()[1..].iter();
+    while let Some(arg) = args.next() {
+        let (opt, value) = match arg.as_str() {
+            "-d" | "-f" | "-c" => {
+                let value = args.next().ok_or_else(|| {
+                    Error::other(format!(
+                        "cut: option requires an argument -- '{}'",
+                        &arg[1..]
+                    ))
+                })?;
+                (&arg[1..], value.as_str())
+            }
+            _ if arg.len() > 2 && arg.starts_with('-') && !arg.starts_with("--") => {
+                arg[1..].split_at(1)
+            }
+            _ if file.is_none() => {
+                file = Some(arg.clone());
+                continue;
+            }
+            _ => return Err(Error::other("cut: expect at most 1 file")),
+        };
+        match opt {
+            "d" => {
+                let mut value_chars = value.chars();
+                delim = match (value_chars.next(), value_chars.next()) {
+                    (Some(c), None) => c,
+                    _ => {
+                        return Err(Error::other(
+                            "cut: the delimiter must be a single character",
+                        ))
+                    }
+                };
+            }
+            "f" => fields = Some(cut_list(value)?),
+            "c" => chars = Some(cut_list(value)?),
+            _ => return Err(Error::other(format!("cut: invalid option -- '{}'", opt))),
+        }
+    }
+    let (by_fields, list) = match (fields, chars) {
+        (Some(list), None) => (true, list),
+        (None, Some(list)) => (false, list),
+        (Some(_), Some(_)) => {
+            return Err(Error::other("cut: only one type of list may be specified"))
+        }
+        (None, None) => {
+            return Err(Error::other(
+                "cut: you must specify a list of fields or characters",
+            ))
+        }
+    };
+    let selected = |i: usize| list.iter().any(|(start, end)| (*start..=*end).contains(&i));
+
+    let cut_lines = |reader: &mut dyn BufRead, out: &mut dyn Write| -> CmdResult {
+        for line in reader.lines() {
+            let line = line?;
+            let cut: String = if by_fields {
+                // same as cut, the lines without the delimiter are printed as they are
+                if !line.contains(delim) {
+                    line
+                } else {
+                    let selected_fields: Vec<&str> = line
+                        .split(delim)
+                        .enumerate()
+                        .filter(|(i, _)| selected(i + 1))
+                        .map(|(_, field)| field)
+                        .collect();
+                    selected_fields.join(&delim.to_string())
+                }
+            } else {
+                line.chars()
+                    .enumerate()
+                    .filter(|(i, _)| selected(i + 1))
+                    .map(|(_, c)| c)
+                    .collect()
+            };
+            writeln!(out, "{}", cut)?;
+        }
+        Ok(())
+    };
+    match file.filter(|file| file != "-") {
+        None => {
+            let (stdin, mut stdout) = env.stdin_stdout();
+            cut_lines(&mut BufReader::new(stdin), &mut stdout)
+        }
+        Some(arg) => {
+            let file = file_op_path(env, &arg);
+            let file = File::open(file)
+                .map_err(|e| Error::new(e.kind(), format!("cut: {}: {}", arg, e)))?;
+            cut_lines(&mut BufReader::new(file), &mut env.stdout())
+        }
+    }
+}
+
+// parse the 1-based list of cut like "1,3-5,7-", into inclusive ranges
+fn cut_list(list: &str) -> Result<Vec<(usize, usize)>> {
+    let invalid = || Error::other(format!("cut: invalid list: {}", list));
+    let parse = |s: &str, default: usize| -> Result<usize> {
+        match s {
+            "" => Ok(default),
+            s => s.parse().ok().filter(|&n| n > 0).ok_or_else(invalid),
+        }
+    };
+    list.split(',')
+        .map(|range| {
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (parse(start, 1)?, parse(end, usize::MAX)?),
+                None => {
+                    let n = parse(range, 0)?;
+                    (n, n)
+                }
+            };
+            if start == 0 || start > end || range == "-" {
+                return Err(invalid());
+            }
+            Ok((start, end))
+        })
+        .collect()
+}
+
+// all the lines are buffered before sorting, so the memory grows with the size of the input
+#[doc(hidden)]
+pub fn builtin_sort(env: &mut CmdEnv) -> CmdResult {
+    let (flags, mut files) = flag_args(env, "sort", "nru", false)?;
+    let (numeric, reverse) = (flags.contains('n'), flags.contains('r'));
+    let unique = flags.contains('u');
+
+    let mut lines: Vec<Vec<u8>> = vec![];
+    let mut read_lines = |reader: &mut dyn BufRead| -> CmdResult {
+        loop {
+            let mut line = vec![];
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                return Ok(());
+            }
+            if line.ends_with(b"\n") {
+                line.pop();
+            }
+            lines.push(line);
+        }
+    };
+    if files.is_empty() {
+        files.push("-".to_string());
+    }
+    for arg in files {
+        if arg == "-" {
+            read_lines(&mut BufReader::new(env.stdin()))?;
+        } else {
+            let file = file_op_path(env, &arg);
+            let file = File::open(file)
+                .map_err(|e| Error::new(e.kind(), format!("sort: {}: {}", arg, e)))?;
+            read_lines(&mut BufReader::new(file))?;
+        }
+    }
+
+    let compare_keys = |a: &Vec<u8>, b: &Vec<u8>| {
+        if numeric {
+            sort_number(a).total_cmp(&sort_number(b))
+        } else {
+            a.cmp(b)
+        }
+    };
+    // same as sort, the equal numbers are compared as text again unless only the unique ones
+    // are kept, where the first one of them in the input is printed
+    lines.sort_by(|a, b| {
+        let mut order = compare_keys(a, b);
+        if numeric && !unique {
+            order = order.then_with(|| a.cmp(b));
+        }
+        if reverse {
+            order.reverse()
+        } else {
+            order
+        }
+    });
+    if unique {
+        lines.dedup_by(|a, b| compare_keys(a, b).is_eq());
+    }
+    let mut out = BufWriter::new(env.stdout());
+    for line in lines {
+        out.write_all(&line)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()
+}
+
+// the leading number of the line for "sort -n", where the lines without a number are 0
+fn sort_number(line: &[u8]) -> f64 {
+    let text = String::from_utf8_lossy(line);
+    let text = text.trim_start();
+    let mut end = 0;
+    let mut seen_dot = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '-' if i == 0 => {}
+            '.' if !seen_dot => seen_dot = true,
+            '0'..='9' => {}
+            _ => break,
+        }
+        end = i + 1;
+    }
+    text[..end].parse().unwrap_or(0.0)
+}
+
+// only the adjacent lines are compared, so each line is printed once the next one differs
+#[doc(hidden)]
+pub fn builtin_uniq(env: &mut CmdEnv) -> CmdResult {
+    let (flags, files) = flag_args(env, "uniq", "cd", false)?;
+    let (count, repeated) = (flags.contains('c'), flags.contains('d'));
+    let file = match files.as_slice() {
+        [] => None,
+        [file] => Some(file.clone()),
+        _ => return Err(Error::other("uniq: expect at most 1 file")),
+    };
+
+    let uniq_lines = |reader: &mut dyn BufRead, out: &mut dyn Write| -> CmdResult {
+        let mut write_line = |line: &[u8], n: usize| -> CmdResult {
+            if repeated && n < 2 {
+                return Ok(());
+            }
+            if count {
+                // same width as uniq
+                write!(out, "{:>7} ", n)?;
+            }
+            out.write_all(line)?;
+            out.write_all(b"\n")
+        };
+        let (mut last, mut n) = (None::<Vec<u8>>, 0);
+        loop {
+            let mut line = vec![];
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            if line.ends_with(b"\n") {
+                line.pop();
+            }
+            match last {
+                Some(ref last_line) if *last_line == line => n += 1,
+                _ => {
+                    if let Some(last_line) = last.replace(line) {
+                        write_line(&last_line, n)?;
+                    }
+                    n = 1;
+                }
+            }
+        }
+        match last {
+            Some(last_line) => write_line(&last_line, n),
+            None => Ok(()),
+        }
+    };
+    match file.filter(|file| file != "-") {
+        None => {
+            let (stdin, mut stdout) = env.stdin_stdout();
+            uniq_lines(&mut BufReader::new(stdin), &mut stdout)
+        }
+        Some(arg) => {
+            let file = file_op_path(env, &arg);
+            let file = File::open(file)
+                .map_err(|e| Error::new(e.kind(), format!("uniq: {}: {}", arg, e)))?;
+            uniq_lines(&mut BufReader::new(file), &mut env.stdout())
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn builtin_tr(env: &mut CmdEnv) -> CmdResult {
+    let (flags, sets) = flag_args(env, "tr", "ds", true)?;
+    let (delete, squeeze) = (flags.contains('d'), flags.contains('s'));
+    let sets = sets
+        .iter()
+        .map(|set| tr_set(set))
+        .collect::<Result<Vec<_>>>()?;
+    let expected = if delete && !squeeze {
+        1
+    } else if !delete && squeeze {
+        sets.len().clamp(1, 2)
+    } else {
+        2
+    };
+    if sets.len() != expected {
+        return Err(Error::other(format!(
+            "tr: expect {} set(s) of characters, found {}",
+            expected,
+            sets.len()
+        )));
+    }
+
+    let mut translated = HashMap::new();
+    if !delete && sets.len() == 2 {
+        // same as GNU tr, the second set is padded with its last character
+        let last = *sets[1]
+            .last()
+            .ok_or_else(|| Error::other("tr: the second set must not be empty"))?;
+        for (i, c) in sets[0].iter().enumerate() {
+            translated.insert(*c, *sets[1].get(i).unwrap_or(&last));
+        }
+    }
+    let deleted: HashSet<char> = if delete {
+        sets[0].iter().copied().collect()
+    } else {
+        HashSet::new()
+    };
+    // the characters are squeezed after being translated, so in the last set
+    let squeezed: HashSet<char> = if squeeze {
+        sets[sets.len() - 1].iter().copied().collect()
+    } else {
+        HashSet::new()
+    };
+
+    let (stdin, mut stdout) = env.stdin_stdout();
+    let mut reader = BufReader::new(stdin);
+    let mut buf = vec![];
+    let mut last = None;
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            return Ok(());
+        }
+        let mut out = String::new();
+        for c in String::from_utf8_lossy(&buf).chars() {
+            if deleted.contains(&c) {
+                continue;
+            }
+            let c = *translated.get(&c).unwrap_or(&c);
+            if last == Some(c) && squeezed.contains(&c) {
+                continue;
+            }
+            out.push(c);
+            last = Some(c);
+        }
+        stdout.write_all(out.as_bytes())?;
+    }
+}
+
+// expand the escapes, ranges like "a-z" and classes like "[:digit:]" of a tr set, in order
+fn tr_set(spec: &str) -> Result<Vec<char>> {
+    let mut unescaped = String::new();
+    let mut spec_chars = spec.chars();
+    while let Some(c) = spec_chars.next() {
+        match c {
+            '\\' => push_escape(&mut unescaped, &mut spec_chars),
+            c => unescaped.push(c),
+        }
+    }
+    let chars: Vec<char> = unescaped.chars().collect();
+    let mut set = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] == '[' && chars.get(i + 1) == Some(&':') {
+            let rest: String = chars[i + 2..].iter().collect();
+            if let Some(end) = rest.find(":]") {
+                let class = &rest[..end];
+                let matches: fn(&u8) -> bool = match class {
+                    "alnum" => u8::is_ascii_alphanumeric,
+                    "alpha" => u8::is_ascii_alphabetic,
+                    "blank" => |c| *c == b' ' || *c == b'\t',
+                    "cntrl" => u8::is_ascii_control,
+                    "digit" => u8::is_ascii_digit,
+                    "lower" => u8::is_ascii_lowercase,
+                    "punct" => u8::is_ascii_punctuation,
+                    "space" => u8::is_ascii_whitespace,
+                    "upper" => u8::is_ascii_uppercase,
+                    "xdigit" => u8::is_ascii_hexdigit,
+                    _ => return Err(Error::other(format!("tr: invalid class: {}", class))),
+                };
+                set.extend((0..128u8).filter(matches).map(char::from));
+                i += 2 + rest[..end].chars().count() + 2;
+                continue;
+            }
+        }
+        if chars.get(i + 1) == Some(&'-') && i + 2 < chars.len() {
+            let (start, end) = (chars[i], chars[i + 2]);
+            if start > end {
+                return Err(Error::other(format!(
+                    "tr: invalid range: {}-{}",
+                    start, end
+                )));
+            }
+            set.extend(start..=end);
+            i += 3;
+            continue;
+        }
+        set.push(chars[i]);
+        i += 1;
+    }
+    Ok(set)
+}
+
+// print lines, words and bytes in this order, same as wc, no matter how the flags are ordered
+#[doc(hidden)]
+pub fn builtin_wc(env: &mut CmdEnv) -> CmdResult {
+    let (flags, files) = flag_args(env, "wc", "lwc", false)?;
+    let mut selected = ['l', 'w', 'c'].map(|flag| flags.contains(flag));
+    if !selected.contains(&true) {
+        selected = [true; 3];
+    }
+
+    let mut ret = Ok(());
+    let mut results = vec![];
+    let mut total = [0; 3];
+    if files.is_empty() {
+        results.push((wc_count(&mut env.stdin())?, None));
+    }
+    for arg in files.iter() {
+        let counted = if arg == "-" {
+            wc_count(&mut env.stdin())
+        } else {
+            let file = file_op_path(env, arg);
+            File::open(file).and_then(|mut file| wc_count(&mut file))
+        };
+        match counted {
+            Ok(counts) => {
+                for (sum, n) in total.iter_mut().zip(counts) {
+                    *sum += n;
+                }
+                results.push((counts, Some(arg.as_str())));
+            }
+            // report the error and continue with the next file, same as wc
+            Err(e) => {
+                writeln!(env.stderr(), "wc: {}: {}", arg, e)?;
+                if ret.is_ok() {
+                    ret = Err(Error::new(e.kind(), format!("wc: {}: {}", arg, e)));
+                }
+            }
+        }
+    }
+    if files.len() > 1 {
+        results.push((total, Some("total")));
+    }
+
+    // a single count is printed as it is, otherwise the columns are aligned to the widest one
+    let width = if results.len() == 1 && selected.iter().filter(|s| **s).count() == 1 {
+        0
+    } else {
+        let max = results
+            .iter()
+            .flat_map(|(counts, _)| counts.iter().zip(selected))
+            .filter_map(|(n, s)| s.then_some(n))
+            .max();
+        max.map_or(1, |n| n.to_string().len())
+    };
+    let mut out = BufWriter::new(env.stdout());
+    for (counts, name) in results {
+        let mut line = counts
+            .iter()
+            .zip(selected)
+            .filter(|(_, s)| *s)
+            .map(|(n, _)| format!("{:>width$}", n, width = width))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if let Some(name) = name {
+            line += &format!(" {}", name);
+        }
+        writeln!(out, "{}", line)?;
+    }
+    out.flush()?;
+    ret
+}
+
+// count the newlines, the words separated by whitespaces and the bytes
+fn wc_count(reader: &mut dyn Read) -> Result<[usize; 3]> {
+    let mut buf = [0; 8192];
+    let mut counts = [0; 3];
+    let mut in_word = false;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(counts);
+        }
+        counts[2] += n;
+        for b in buf[..n].iter() {
+            if *b == b'\n' {
+                counts[0] += 1;
+            }
+            if b.is_ascii_whitespace() {
+                in_word = false;
+            } else if !in_word {
+                in_word = true;
+                counts[1] += 1;
+            }
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn builtin_pwd(env: &mut CmdEnv) -> CmdResult {
+    let dir = env.current_dir().display().to_string();
+    writeln!(env.stdout(), "{}", dir)
+}
+
+// same as coreutils, trailing slashes are ignored and the root stays as it is
+#[doc(hidden)]
+pub fn builtin_basename(env: &mut CmdEnv) -> CmdResult {
+    let (name, suffix) = match env.args() {
+        [_, name] => (name.clone(), None),
+        [_, name, suffix] => (name.clone(), Some(suffix.clone())),
+        [_] => return Err(Error::other("basename: missing operand")),
+        _ => {
+            return Err(Error::other(
+                "basename: expect a name and an optional suffix",
+            ))
+        }
+    };
+    let trimmed = name.trim_end_matches(std::path::is_separator);
+    let base = if trimmed.is_empty() && !name.is_empty() {
+        &name[..1]
+    } else {
+        let start = trimmed.rfind(std::path::is_separator).map_or(0, |i| i + 1);
+        let base = &trimmed[start..];
+        match suffix {
+            // the suffix is not removed if it is the whole name
+            Some(ref suffix) if base != suffix => {
+                base.strip_suffix(suffix.as_str()).unwrap_or(base)
+            }
+            _ => base,
+        }
+    };
+    writeln!(env.stdout(), "{}", base)
+}
+
+// same as coreutils without a format, except the time zone shown as an offset for the local time
+#[cfg(feature = "date")]
+const DATE_FORMAT: &str = "%a %b %e %H:%M:%S %Z %Y";
+
+// the same output on all the platforms, with "-u" for UTC and "+FORMAT" in strftime syntax
+#[cfg(feature = "date")]
+#[doc(hidden)]
+pub fn builtin_date(env: &mut CmdEnv) -> CmdResult {
+    use chrono::format::{Item, StrftimeItems};
+    use chrono::{Local, Utc};
+
+    let mut utc = false;
+    let mut format = DATE_FORMAT.to_string();
+    for arg in env.args()[1..].iter() {
+        match arg.as_str() {
+            "-u" | "--utc" => utc = true,
+            _ if arg.starts_with('+') => format = arg[1..].to_string(),
+            _ => return Err(Error::other(format!("date: invalid argument: {}", arg))),
+        }
+    }
+    // the invalid specifiers fail when formatting, so they are reported before it
+    if StrftimeItems::new(&format).any(|item| matches!(item, Item::Error)) {
+        return Err(Error::other(format!("date: invalid format: {}", format)));
+    }
+    let date = if utc {
+        Utc::now().format(&format).to_string()
+    } else {
+        Local::now().format(&format).to_string()
+    };
+    writeln!(env.stdout(), "{}", date)
+}
+
+// same as coreutils, "." for names without a directory, and every name is printed on a line
+#[doc(hidden)]
+pub fn builtin_dirname(env: &mut CmdEnv) -> CmdResult {
+    let names = env.args()[1..].to_vec();
+    if names.is_empty() {
+        return Err(Error::other("dirname: missing operand"));
+    }
+    let mut out = BufWriter::new(env.stdout());
+    for name in names.iter() {
+        let trimmed = name.trim_end_matches(std::path::is_separator);
+        let dir = match trimmed.rfind(std::path::is_separator) {
+            None if trimmed.is_empty() && !name.is_empty() => &name[..1],
+            None => ".",
+            Some(i) => match trimmed[..i].trim_end_matches(std::path::is_separator) {
+                "" => &name[..1],
+                dir => dir,
+            },
+        };
+        writeln!(out, "{}", dir)?;
+    }
+    out.flush()
+}
+
+// the registered builtin and custom commands first, since they are run instead of the programs,
+// then the executables in PATH, with the extensions in PATHEXT on Windows
+#[doc(hidden)]
+pub fn builtin_which(env: &mut CmdEnv) -> Result<i32> {
+    let names = env.args()[1..].to_vec();
+    if names.is_empty() {
+        return Err(Error::other("which: missing operand"));
+    }
+    let paths = match env.var("PATH") {
+        Some(paths) => paths.into(),
+        None => std::env::var_os("PATH").unwrap_or_default(),
+    };
+    let mut exts = vec![String::new()];
+    if cfg!(windows) {
+        let path_ext = std::env::var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".into());
+        exts.extend(
+            path_ext
+                .split(';')
+                .filter(|ext| !ext.is_empty())
+                .map(String::from),
+        );
+    }
+    let current_dir = env.current_dir().to_path_buf();
+    let is_program = |path: &Path| {
+        let path = current_dir.join(path);
+        path.is_file() && path.access(AccessMode::EXECUTE).is_ok()
+    };
+    let mut code = 0;
+    for name in names.iter() {
+        if crate::is_builtin(name) {
+            writeln!(env.stdout(), "{}: shell builtin", name)?;
+            continue;
+        }
+        let dirs = if Path::new(name).components().count() > 1 {
+            vec![PathBuf::new()]
+        } else {
+            std::env::split_paths(&paths).collect()
+        };
+        let found = dirs.iter().find_map(|dir| {
+            exts.iter()
+                .map(|ext| dir.join(format!("{}{}", name, ext)))
+                .find(|path| is_program(path))
+        });
+        match found {
+            Some(path) => writeln!(env.stdout(), "{}", path.display())?,
+            None => {
+                writeln!(env.stderr(), "{}: not found", name)?;
+                code = 1;
+            }
+        }
+    }
+    Ok(code)
+}
+
+#[doc(hidden)]
+pub fn builtin_tee(env: &mut CmdEnv) -> CmdResult {
+    // all the flags are parsed first, so "-a" after a file still appends to it
+    let (flags, names) = flag_args(env, "tee", "a", false)?;
+    let append = flags.contains('a');
+    let mut files = vec![];
+    for name in names {
+        files.push(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(!append)
+                .append(append)
+                .open(file_op_path(env, &name))?,
+        );
+    }
+
+    let mut buf = [0; 8192];
+    loop {
+        let n = env.stdin().read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        env.stdout().write_all(&buf[..n])?;
+        for file in files.iter_mut() {
+            file.write_all(&buf[..n])?;
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn builtin_seq(env: &mut CmdEnv) -> CmdResult {
+    let args = env.args()[1..]
+        .iter()
+        .map(|arg| {
+            arg.parse::<i64>()
+                .map_err(|_| Error::other(format!("seq: invalid integer argument: {}", arg)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let (first, step, last) = match args[..] {
+        [last] => (1, 1, last),
+        [first, last] => (first, 1, last),
+        [first, step, last] => (first, step, last),
+        _ => return Err(Error::other("seq: expect 1 to 3 arguments")),
+    };
+    if step == 0 {
+        return Err(Error::other("seq: invalid zero increment value"));
+    }
+
+    let mut out = BufWriter::new(env.stdout());
+    let mut i = first;
+    while (step > 0 && i <= last) || (step < 0 && i >= last) {
+        writeln!(out, "{}", i)?;
+        match i.checked_add(step) {
+            Some(next) => i = next,
+            None => break,
+        }
+    }
+    out.flush()
+}
+
+#[doc(hidden)]
+pub fn builtin_sleep(env: &mut CmdEnv) -> CmdResult {
+    let arg = match env.args() {
+        [_, arg] => arg,
+        _ => return Err(Error::other("sleep: expect 1 argument")),
+    };
+    let (num, scale) = if let Some(num) = arg.strip_suffix("ms") {
+        (num, 0.001)
+    } else if let Some(num) = arg.strip_suffix('s') {
+        (num, 1.0)
+    } else {
+        (arg.as_str(), 1.0)
+    };
+    let duration = num
+        .parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs * scale).ok())
+        .ok_or_else(|| Error::other(format!("sleep: invalid time interval: {}", arg)))?;
+
+    // sleep in small steps, so it can be stopped when running in a thread
+    let deadline = Instant::now() + duration;
+    loop {
+        if env.is_killed() {
+            return Err(Error::new(ErrorKind::Interrupted, "sleep: interrupted"));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(());
+        }
+        thread::sleep(SLEEP_INTERVAL.min(deadline - now));
+    }
+}
+
+#[doc(hidden)]
+pub fn builtin_printf(env: &mut CmdEnv) -> CmdResult {
+    let (format, args) = match env.args() {
+        [_, format, args @ ..] => (format.clone(), args.to_vec()),
+        _ => return Err(Error::other("printf: missing format string")),
+    };
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut missing_arg = false;
+    let mut invalid_num = None;
+    let mut invalid_spec = false;
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => push_escape(&mut out, &mut chars),
+            '%' => {
+                if chars.peek() == Some(&'%') {
+                    chars.next();
+                    out.push('%');
+                    continue;
+                }
+                let (mut left, mut zero) = (false, false);
+                while let Some(&flag) = chars.peek() {
+                    match flag {
+                        '-' => left = true,
+                        '0' => zero = true,
+                        _ => break,
+                    }
+                    chars.next();
+                }
+                let mut width = 0;
+                while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+                    width = width * 10 + d as usize;
+                    chars.next();
+                }
+                let spec = chars.next();
+                let arg = args.next();
+                missing_arg |= arg.is_none();
+                let s = match spec {
+                    Some('s') => arg.cloned().unwrap_or_default(),
+                    Some(spec @ ('d' | 'x')) => {
+                        let arg = arg.map_or("0", |arg| arg.as_str());
+                        let n = arg.parse::<i64>().unwrap_or_else(|_| {
+                            invalid_num.get_or_insert(arg.to_string());
+                            0
+                        });
+                        if spec == 'd' {
+                            n.to_string()
+                        } else {
+                            format!("{:x}", n)
+                        }
+                    }
+                    // same as bash, the output formatted so far is still printed
+                    _ => {
+                        invalid_spec = true;
+                        break;
+                    }
+                };
+                out += &printf_pad(s, width, left, zero && spec != Some('s'));
+            }
+            c => out.push(c),
+        }
+    }
+
+    if invalid_spec {
+        env.write_stderr(&format!(
+            "printf: invalid format specifier in {:?}\n",
+            format
+        ))?;
+    } else if missing_arg {
+        env.write_stderr("printf: missing arguments for format string\n")?;
+    } else if args.next().is_some() {
+        env.write_stderr("printf: too many arguments for format string\n")?;
+    }
+    env.write_stdout(&out)?;
+    match invalid_num {
+        Some(arg) => Err(Error::other(format!("printf: invalid number: {}", arg))),
+        None => Ok(()),
+    }
+}
+
+fn printf_pad(s: String, width: usize, left: bool, zero: bool) -> String {
+    let len = s.chars().count();
+    if len >= width {
+        return s;
+    }
+    let fill = width - len;
+    if left {
+        s + &" ".repeat(fill)
+    } else if zero {
+        let (sign, digits) = match s.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", s.as_str()),
+        };
+        format!("{}{}{}", sign, "0".repeat(fill), digits)
+    } else {
+        " ".repeat(fill) + &s
+    }
+}
+
+#[doc(hidden)]
+pub fn builtin_test(env: &mut CmdEnv) -> Result<i32> {
+    let mut args = &env.args()[1..];
+    if env.args()[0] == "[" {
+        match args.split_last() {
+            Some((last, rest)) if last == "]" => args = rest,
+            _ => return Err(Error::other("[: missing ']'")),
+        }
+    }
+    let (negate, args) = match args.split_first() {
+        Some((first, rest)) if first == "!" => (true, rest),
+        _ => (false, args),
+    };
+    let path = |file: &str| file_op_path(env, file);
+    let ret = match args {
+        [] => false,
+        [s] => !s.is_empty(),
+        [op, file] if op == "-e" => path(file).exists(),
+        [op, file] if op == "-f" => path(file).is_file(),
+        [op, file] if op == "-d" => path(file).is_dir(),
+        [op, s] if op == "-z" => s.is_empty(),
+        [op, s] if op == "-n" => !s.is_empty(),
+        [s1, op, s2] if op == "=" || op == "==" => s1 == s2,
+        [s1, op, s2] if op == "!=" => s1 != s2,
+        _ => {
+            return Err(Error::other(format!(
+                "test: unsupported expression: {}",
+                args.join(" ")
+            )))
+        }
+    };
+    Ok(if ret != negate { 0 } else { 1 })
+}
@@ -1,9 +1,17 @@
-use crate::{process, CmdResult, FunResult};
+use crate::io::AtomicFile;
+use crate::process::{debug_enabled, debug_trace};
+use crate::{CmdError, CmdResult, FunResult};
 use log::{info, warn};
 use os_pipe::PipeReader;
-use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
 use std::process::{Child, ExitStatus};
-use std::thread::JoinHandle;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const POLLING_INTERVAL: Duration = Duration::from_millis(10);
 
 /// Representation of running or exited children processes, connected with pipes
 /// optionally.
@@ -12,20 +20,31 @@ use std::thread::JoinHandle;
 pub struct CmdChildren {
     children: Vec<Result<CmdChild>>,
     ignore_error: bool,
+    pipefail: bool,
+    // the whole pipeline for the errors, including the commands failed to spawn
+    full_cmds: String,
 }
 
 impl CmdChildren {
-    pub(crate) fn new(children: Vec<Result<CmdChild>>, ignore_error: bool) -> Self {
+    pub(crate) fn new(children: Vec<Result<CmdChild>>, ignore_error: bool, pipefail: bool) -> Self {
         Self {
             children,
             ignore_error,
+            pipefail,
+            full_cmds: String::new(),
         }
     }
 
+    pub(crate) fn with_full_cmds(mut self, full_cmds: &str) -> Self {
+        self.full_cmds = full_cmds.to_string();
+        self
+    }
+
     pub(crate) fn into_fun_children(self) -> FunChildren {
         FunChildren {
             children: self.children,
             ignore_error: self.ignore_error,
+            pipefail: self.pipefail,
         }
     }
 
@@ -34,27 +53,197 @@ impl CmdChildren {
         let handle = self.children.pop().unwrap();
         match handle {
             Err(e) => {
-                let _ = Self::wait_children(&mut self.children);
+                let _ = Self::wait_children(&mut self.children, self.pipefail);
                 return Err(e);
             }
             Ok(handle) => {
                 if let Err(e) = handle.wait(true) {
-                    let _ = Self::wait_children(&mut self.children);
+                    let _ = Self::wait_children(&mut self.children, self.pipefail);
                     return Err(e);
                 }
             }
         }
-        Self::wait_children(&mut self.children)
+        Self::wait_children(&mut self.children, self.pipefail)
+    }
+
+    /// Waits for all the children to finish, returning the exit code of the last command
+    ///
+    /// Unlike `wait()`, a non-zero exit code is not an error, and the failures of the other
+    /// commands in the pipeline are ignored. Only spawning or IO failures are returned as errors.
+    /// On Unix, a process terminated by a signal reports `128 + signal`, the same as in bash.
+    /// A failed builtin or custom command reports 1.
+    pub fn wait_with_status(&mut self) -> Result<i32> {
+        let mut ret = self.children.pop().unwrap().and_then(CmdChild::wait_status);
+        while let Some(child) = self.children.pop() {
+            match child {
+                Err(e) => ret = Err(e),
+                Ok(child) => {
+                    let _ = child.wait(false);
+                }
+            }
+        }
+        ret
+    }
+
+    /// Waits for all the children to finish, returning each command string and its exit code in
+    /// pipeline order
+    ///
+    /// It helps to find out which command in the pipeline failed. The exit codes are the same as
+    /// `wait_with_status()`, and `None` is reported for the commands which could not be spawned.
+    /// Only IO failures when waiting are returned as errors.
+    pub fn wait_with_statuses(&mut self) -> Result<Vec<(String, Option<i32>)>> {
+        let mut statuses = vec![];
+        let mut ret = Ok(());
+        while let Some(child) = self.children.pop() {
+            match child {
+                Err(e) => {
+                    let cmd = CmdError::from_io_error(&e).map_or("", CmdError::cmd);
+                    statuses.push((cmd.to_string(), None));
+                }
+                Ok(child) => {
+                    let cmd = child.cmd.clone();
+                    match child.wait_status() {
+                        Ok(code) => statuses.push((cmd, Some(code))),
+                        Err(e) => ret = ret.and(Err(e)),
+                    }
+                }
+            }
+        }
+        ret?;
+        statuses.reverse();
+        Ok(statuses)
     }
 
-    fn wait_children(children: &mut Vec<Result<CmdChild>>) -> CmdResult {
+    /// Waits for all the children to finish, returning each command string and the time from
+    /// spawning it to finishing, in pipeline order
+    ///
+    /// It helps to find the slow steps of the pipeline. The failures are the same as `wait()`.
+    /// Since the children are waited for one by one, a command finished earlier than a later
+    /// one in the pipeline may be reported with the time when it was waited for. In debug mode,
+    /// the time of each command is also traced when waiting, e.g. with `run_cmd!`.
+    pub fn wait_with_timings(&mut self) -> Result<Vec<(String, Duration)>> {
+        let mut timings = vec![];
         let mut ret = Ok(());
-        while !children.is_empty() {
-            let child_handle = children.pop().unwrap();
+        let mut is_last = true;
+        while let Some(child) = self.children.pop() {
+            match child {
+                Err(e) => ret = Err(e),
+                Ok(child) => {
+                    let cmd = child.cmd.clone();
+                    let (res, elapsed) = child.wait_timed(is_last || self.pipefail);
+                    if let Err(e) = res {
+                        ret = Err(e);
+                    }
+                    timings.push((cmd, elapsed));
+                }
+            }
+            is_last = false;
+        }
+        ret?;
+        timings.reverse();
+        Ok(timings)
+    }
+
+    /// Returns the OS-assigned process identifiers of the children, in pipeline order
+    ///
+    /// Builtin or custom commands are not running as processes, so `None` is returned for them.
+    pub fn pids(&self) -> Vec<Option<u32>> {
+        self.children
+            .iter()
+            .map(|child| child.as_ref().ok().and_then(|child| child.handle.id()))
+            .collect()
+    }
+
+    /// Waits for all the children to finish, but no longer than `timeout`
+    ///
+    /// If the timeout expires first, every child still running in the pipeline gets killed, and
+    /// an error with `ErrorKind::TimedOut` is returned. Builtin or custom commands running in
+    /// threads can not be killed, so they are asked to stop (see `CmdEnv::is_killed()`) and
+    /// detached if still running.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> CmdResult {
+        let deadline = Instant::now() + timeout;
+        // keep draining stderr while polling, or children could block on a full pipe
+        let mut polling_stderr: Vec<Option<StderrLogging>> = self
+            .children
+            .iter_mut()
+            .map(|child| child.as_mut().ok().map(CmdChild::log_stderr))
+            .collect();
+        loop {
+            if self
+                .children
+                .iter_mut()
+                .flatten()
+                .all(|child| child.handle.is_finished())
+            {
+                let ret = self.wait();
+                drop(polling_stderr);
+                return ret;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            thread::sleep(POLLING_INTERVAL.min(deadline - now));
+        }
+
+        for (child, polling) in self.children.drain(..).zip(polling_stderr.drain(..)) {
+            if let (Ok(child), Some(polling)) = (child, polling) {
+                let _ = child.kill(polling);
+            }
+        }
+        Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("Running {} timed out after {:?}", self.full_cmds, timeout),
+        ))
+    }
+
+    /// Kills all the children in the pipeline, without waiting for them to finish normally
+    ///
+    /// Processes are killed and reaped. Builtin or custom commands running in threads can not
+    /// be killed, so this is best-effort for them: they are asked to stop (see
+    /// `CmdEnv::is_killed()`) and detached if still running. All children are handled even if
+    /// some of them failed to be killed, and the first error is returned. With
+    /// `GroupCmds::new_process_group()`, the grandchildren are killed as well.
+    pub fn kill_all(&mut self) -> CmdResult {
+        let mut ret = Ok(());
+        for mut child in self.children.drain(..).flatten() {
+            let polling = child.log_stderr();
+            if let Err(e) = child.kill(polling) {
+                if ret.is_ok() {
+                    ret = Err(e);
+                }
+            }
+        }
+        ret
+    }
+
+    /// Sends the signal to every child process in the pipeline which is still running
+    ///
+    /// Unlike `kill_all()`, the children are not waited for, so e.g. `SIGTERM` can be sent for a
+    /// graceful shutdown and followed by `wait_timeout()`. Builtin or custom commands running in
+    /// threads are not processes, so they are skipped. There are no signals on Windows, where the
+    /// processes get killed instead, whichever signal is sent. With
+    /// `GroupCmds::new_process_group()`, the whole process groups get the signal.
+    pub fn signal(&mut self, sig: i32) -> CmdResult {
+        let mut ret = Ok(());
+        for child in self.children.iter_mut().flatten() {
+            if let Err(e) = child.handle.signal(sig, child.process_group) {
+                warn!("Sending signal {} to {} failed: {}", sig, child.cmd, e);
+                if ret.is_ok() {
+                    ret = Err(CmdChildHandle::cmd_io_error(e, &child.cmd, false));
+                }
+            }
+        }
+        ret
+    }
+
+    fn wait_children(children: &mut Vec<Result<CmdChild>>, pipefail: bool) -> CmdResult {
+        let mut ret = Ok(());
+        while let Some(child_handle) = children.pop() {
             match child_handle {
                 Err(e) => ret = Err(e),
                 Ok(child_handle) => {
-                    if let Err(e) = child_handle.wait(false) {
+                    if let Err(e) = child_handle.wait(pipefail) {
                         ret = Err(e);
                     }
                 }
@@ -64,6 +253,17 @@ impl CmdChildren {
     }
 }
 
+/// Output and exit code of the children, returned by `FunChildren::wait_with_all()`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CmdOutput {
+    /// Output of the last command, with the trailing newline kept
+    pub stdout: String,
+    /// Error output of all the commands, in pipeline order
+    pub stderr: String,
+    /// Exit code of the last command, the same as `CmdChildren::wait_with_status()`
+    pub status: i32,
+}
+
 /// Representation of running or exited children processes with output, connected with pipes
 /// optionally.
 ///
@@ -71,42 +271,198 @@ impl CmdChildren {
 pub struct FunChildren {
     children: Vec<Result<CmdChild>>,
     ignore_error: bool,
+    pipefail: bool,
 }
 
 impl FunChildren {
+    /// Waits for the children to finish, returning the output with the trailing newline trimmed
+    ///
+    /// This is what `run_fun!` returns.
     pub fn wait_with_output(&mut self) -> FunResult {
+        let mut s = self.wait_with_output_raw()?;
+        if s.ends_with('\n') {
+            s.pop();
+        }
+        Ok(s)
+    }
+
+    /// Waits for the children to finish, returning the output as it is
+    ///
+    /// Unlike `wait_with_output()`, the trailing newline is kept, e.g. to capture a file's
+    /// exact content.
+    pub fn wait_with_output_raw(&mut self) -> FunResult {
+        let output = self.wait_with_output_bytes()?;
+        Ok(String::from_utf8_lossy(&output).to_string())
+    }
+
+    /// Waits for the children to finish, returning the output together with the exit code of the
+    /// last command
+    ///
+    /// Unlike `wait_with_output()`, the output is kept even if the commands fail, since many
+    /// tools print useful information before exiting with non-zero code. The trailing newline is
+    /// trimmed, and the status is the same as `wait_with_status()`.
+    pub fn wait_with_output_and_status(&mut self) -> (String, Result<i32>) {
+        // keep draining stderr while reading, or children could block on a full pipe
+        let polling_stderr: Vec<StderrLogging> = self
+            .children
+            .iter_mut()
+            .flatten()
+            .map(CmdChild::log_stderr)
+            .collect();
+        let mut output = vec![];
+        let mut read_ret = Ok(());
+        if let Some(Ok(child)) = self.children.last_mut() {
+            if let Some(mut stdout) = child.stdout.take() {
+                read_ret = stdout.read_to_end(&mut output).map(|_| ());
+            }
+        }
+        let children = std::mem::take(&mut self.children);
+        let status =
+            CmdChildren::new(children, self.ignore_error, self.pipefail).wait_with_status();
+        drop(polling_stderr);
+
+        let mut output = String::from_utf8_lossy(&output).into_owned();
+        if output.ends_with('\n') {
+            output.pop();
+        }
+        (output, read_ret.and(status))
+    }
+
+    /// Waits for the children to finish, returning the raw output bytes
+    ///
+    /// Unlike `wait_with_output()`, there is no UTF-8 conversion and the trailing newline is
+    /// not trimmed, so it can be used to capture binary data.
+    pub fn wait_with_output_bytes(&mut self) -> Result<Vec<u8>> {
+        let mut output = vec![];
+        self.wait_into_bytes(&mut output)?;
+        Ok(output)
+    }
+
+    /// Waits for the children to finish, appending the raw output bytes to `buf`
+    ///
+    /// Same as `wait_with_output_bytes()`, but the buffer can be reused across many commands to
+    /// avoid allocating a new one each time. Nothing is trimmed.
+    pub fn wait_into_bytes(&mut self, buf: &mut Vec<u8>) -> CmdResult {
         // wait for the last child result
         let handle = self.children.pop().unwrap();
         match handle {
             Err(e) => {
-                let _ = CmdChildren::wait_children(&mut self.children);
+                let _ = CmdChildren::wait_children(&mut self.children, self.pipefail);
                 Err(e)
             }
             Ok(handle) => {
-                let wait_last = handle.wait_with_output(self.ignore_error);
+                let wait_last = handle.wait_with_output(self.ignore_error, buf);
                 match wait_last {
                     Err(e) => {
-                        let _ = CmdChildren::wait_children(&mut self.children);
+                        let _ = CmdChildren::wait_children(&mut self.children, self.pipefail);
                         Err(e)
                     }
-                    Ok(output) => {
-                        let mut s = String::from_utf8_lossy(&output).to_string();
-                        if s.ends_with('\n') {
-                            s.pop();
-                        }
-                        let ret = CmdChildren::wait_children(&mut self.children);
+                    Ok(()) => {
+                        let ret = CmdChildren::wait_children(&mut self.children, self.pipefail);
                         if let Err(e) = ret {
                             if !self.ignore_error {
                                 return Err(e);
                             }
                         }
-                        Ok(s)
+                        Ok(())
                     }
                 }
             }
         }
     }
 
+    /// Waits for the children to finish, appending the output to `buf`
+    ///
+    /// Same as `wait_with_output_raw()`, but the buffer can be reused across many commands to
+    /// avoid allocating a new string each time. The trailing newline is kept, so the outputs of
+    /// several commands can be appended one after another, and invalid UTF-8 sequences are
+    /// replaced.
+    ///
+    /// ```no_run
+    /// # use cmd_lib::spawn_with_output;
+    /// let mut buf = String::new();
+    /// for i in 0..100 {
+    ///     buf.clear();
+    ///     spawn_with_output!(echo $i)?.wait_into(&mut buf)?;
+    /// }
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn wait_into(&mut self, buf: &mut String) -> CmdResult {
+        let mut bytes = std::mem::take(buf).into_bytes();
+        let len = bytes.len();
+        let ret = self.wait_into_bytes(&mut bytes);
+        *buf = match String::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(e) => {
+                let bytes = e.into_bytes();
+                // the existing content is valid, only the appended output needs replacing
+                let mut s = String::from_utf8_lossy(&bytes[..len]).into_owned();
+                s.push_str(&String::from_utf8_lossy(&bytes[len..]));
+                s
+            }
+        };
+        ret
+    }
+
+    /// Waits for the children to finish, deserializing the output as JSON
+    ///
+    /// It fails with `ErrorKind::InvalidData` if the output can't be deserialized, and the error
+    /// message includes the beginning of the output. Only available with `serde` feature.
+    ///
+    /// ```no_run
+    /// # use cmd_lib::spawn_with_output;
+    /// let pods: serde_json::Value = spawn_with_output!(kubectl get pods -o json)?.wait_with_json()?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn wait_with_json<T: serde::de::DeserializeOwned>(&mut self) -> Result<T> {
+        const SNIPPET_LEN: usize = 200;
+        let output = self.wait_with_output_bytes()?;
+        serde_json::from_slice(&output).map_err(|e| {
+            let output = String::from_utf8_lossy(&output);
+            let mut snippet: String = output.chars().take(SNIPPET_LEN).collect();
+            if snippet.len() < output.len() {
+                snippet += "...";
+            }
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid JSON output: {}, output: {:?}", e, snippet),
+            )
+        })
+    }
+
+    /// Returns an iterator over the output lines, as they are produced by the running children
+    ///
+    /// The lines are yielded without the trailing newline, including the final one even if it
+    /// is not terminated. After the output is finished, the children are waited for, and their
+    /// error is yielded as the last item if any. Dropping the iterator early closes the output
+    /// and reaps the children.
+    pub fn lines(self) -> impl Iterator<Item = Result<String>> {
+        self.into_output(BufReader::new)
+    }
+
+    /// Returns a reader over the output, as it is produced by the running children
+    ///
+    /// Unlike `wait_with_output()`, nothing is buffered in memory, and the children are blocked
+    /// until the output is read, so it can be copied somewhere with `std::io::copy` in constant
+    /// memory. The children are waited for at the end of the output, and their error is returned
+    /// by the last read if any. Dropping the reader early closes the output and reaps the children.
+    pub fn into_reader(self) -> impl Read {
+        self.into_output(|stdout| stdout)
+    }
+
+    fn into_output<R>(mut self, f: impl FnOnce(PipeReader) -> R) -> FunOutput<R> {
+        let (stdout, polling_stderr) = match self.children.last_mut() {
+            Some(Ok(child)) => (child.stdout.take(), Some(child.log_stderr())),
+            _ => (None, None),
+        };
+        FunOutput {
+            reader: stdout.map(f),
+            children: Some(self),
+            polling_stderr,
+        }
+    }
+
     pub fn wait_with_pipe(&mut self, f: &mut dyn FnMut(Box<dyn Read>)) -> CmdResult {
         let child = self.children.pop().unwrap()?;
         let polling_stderr = StderrLogging::new(&child.cmd, child.stderr);
@@ -117,7 +473,7 @@ impl FunChildren {
                     let _ = proc.kill();
                 }
             }
-            CmdChildHandle::Thread(_) => {
+            CmdChildHandle::Thread(..) => {
                 if let Some(stdout) = child.stdout {
                     f(Box::new(stdout));
                 }
@@ -129,7 +485,282 @@ impl FunChildren {
             }
         };
         drop(polling_stderr);
-        CmdChildren::wait_children(&mut self.children)
+        CmdChildren::wait_children(&mut self.children, self.pipefail)
+    }
+
+    /// Waits for the children to finish, returning both stdout and stderr with the exit code
+    ///
+    /// The error output is collected instead of being logged, and it is read in parallel with the
+    /// output, so the children won't be blocked on either of them. Same as `wait_with_status()`,
+    /// a non-zero exit code is not an error.
+    pub fn wait_with_all(&mut self) -> Result<CmdOutput> {
+        let stderr_readers = self.read_stderr()?;
+        let mut stdout = vec![];
+        if let Some(Ok(child)) = self.children.last_mut() {
+            if let Some(mut out) = child.stdout.take() {
+                out.read_to_end(&mut stdout)?;
+            }
+        }
+        let stderr = Self::join_stderr(stderr_readers);
+        let children = std::mem::take(&mut self.children);
+        let status =
+            CmdChildren::new(children, self.ignore_error, self.pipefail).wait_with_status()?;
+        Ok(CmdOutput {
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+            status,
+        })
+    }
+
+    /// Waits for the children spawned by `spawn_stderr_captured()`, returning their captured stderr
+    ///
+    /// The output goes to stdout of the current process directly as it is produced, without a
+    /// pipe in between, so it suits the build tools whose output is only shown to the users, while
+    /// the errors are inspected on failure. The error output of all the commands is collected
+    /// instead of being logged, and if the commands fail, it is appended to the error message.
+    ///
+    /// ```no_run
+    /// # use cmd_lib::builder::Pipeline;
+    /// let ret = Pipeline::new()
+    ///     .command(["cargo", "build"])
+    ///     .spawn_stderr_captured()?
+    ///     .wait_stderr_captured();
+    /// if let Err(e) = ret {
+    ///     if e.to_string().contains("could not compile") {
+    ///         // ...
+    ///     }
+    /// }
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn wait_stderr_captured(mut self) -> Result<String> {
+        let stderr_readers = self.read_stderr()?;
+        if let Some(Ok(child)) = self.children.last_mut() {
+            // only captured when spawned with `spawn_with_output()`, pass it through then
+            if let Some(mut out) = child.stdout.take() {
+                let _ = std::io::copy(&mut out, &mut std::io::stdout());
+            }
+        }
+        let stderr = Self::join_stderr(stderr_readers);
+        let stderr = String::from_utf8_lossy(&stderr).into_owned();
+        let children = std::mem::take(&mut self.children);
+        match CmdChildren::new(children, self.ignore_error, self.pipefail).wait() {
+            Ok(()) => Ok(stderr),
+            Err(e) if stderr.is_empty() => Err(e),
+            Err(e) => Err(Error::new(
+                e.kind(),
+                format!("{}\n{}", e, stderr.trim_end()),
+            )),
+        }
+    }
+
+    // read stderr of all the children in parallel with the output, so none of them is blocked
+    fn read_stderr(&mut self) -> Result<Vec<JoinHandle<Vec<u8>>>> {
+        let mut stderr_readers = vec![];
+        for child in self.children.iter_mut().flatten() {
+            if let Some(mut stderr) = child.stderr.take() {
+                stderr_readers.push(thread::Builder::new().spawn(move || {
+                    let mut buf = vec![];
+                    let _ = stderr.read_to_end(&mut buf);
+                    buf
+                })?);
+            }
+        }
+        Ok(stderr_readers)
+    }
+
+    fn join_stderr(stderr_readers: Vec<JoinHandle<Vec<u8>>>) -> Vec<u8> {
+        let mut stderr = vec![];
+        for reader in stderr_readers {
+            if let Ok(buf) = reader.join() {
+                stderr.extend(buf);
+            }
+        }
+        stderr
+    }
+
+    /// Waits for the children to finish, calling the callbacks with each line of stdout and
+    /// stderr as soon as it is produced
+    ///
+    /// The lines are passed without the trailing newline, and the stderr lines from all the
+    /// commands in the pipeline are passed to `on_stderr` instead of being logged. The callbacks
+    /// are called from the current thread, in the order the lines are read.
+    pub fn wait_with_streams(
+        &mut self,
+        mut on_stdout: impl FnMut(&str),
+        mut on_stderr: impl FnMut(&str),
+    ) -> CmdResult {
+        self.wait_with_lines(&mut |is_stdout, line| {
+            if is_stdout {
+                on_stdout(line);
+            } else {
+                on_stderr(line);
+            }
+        })
+    }
+
+    /// Waits for the children to finish, returning stdout and stderr combined in the order the
+    /// lines are read, with the trailing newline trimmed
+    ///
+    /// The order is only best-effort, since the two streams are read from different pipes: the
+    /// lines written close together may be swapped, and a program may buffer its stdout when it is
+    /// not a terminal. For the exact order, redirect stderr in the commands instead, like
+    /// `run_fun!(cmd 2>&1)` or `run_fun!(cmd |& grep warning)`, so both go to the same pipe.
+    pub fn wait_with_output_combined(&mut self) -> FunResult {
+        let mut lines = vec![];
+        self.wait_with_lines(&mut |_, line| lines.push(line.to_owned()))?;
+        Ok(lines.join("\n"))
+    }
+
+    /// Waits for the children to finish, writing each line of the output to stdout and each line
+    /// of stderr to stderr of the current process, prefixed with `"{prefix} | "`
+    ///
+    /// It tags the output of several pipelines running at the same time, like `docker-compose`
+    /// does for the services. Each line is written and flushed at once, so the lines from
+    /// different pipelines are never mixed up.
+    ///
+    /// ```no_run
+    /// # use cmd_lib::spawn_with_output;
+    /// let mut web = spawn_with_output!(python3 -m http.server)?;
+    /// let mut worker = spawn_with_output!(./worker.sh)?;
+    /// std::thread::spawn(move || web.stream_prefixed("web"));
+    /// worker.stream_prefixed("worker")?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn stream_prefixed(&mut self, prefix: &str) -> CmdResult {
+        self.wait_with_lines(&mut |is_stdout, line| {
+            let line = format!("{} | {}\n", prefix, line);
+            // errors of the parent's own stdout or stderr are not the commands' failures
+            let _ = if is_stdout {
+                let mut stdout = std::io::stdout().lock();
+                stdout
+                    .write_all(line.as_bytes())
+                    .and_then(|_| stdout.flush())
+            } else {
+                let mut stderr = std::io::stderr().lock();
+                stderr
+                    .write_all(line.as_bytes())
+                    .and_then(|_| stderr.flush())
+            };
+        })
+    }
+
+    // pass the lines of the last stdout and all the stderr to `f`, as they arrive
+    fn wait_with_lines(&mut self, f: &mut dyn FnMut(bool, &str)) -> CmdResult {
+        let (tx, rx) = mpsc::channel();
+        let mut readers = vec![];
+        let last = self.children.len() - 1;
+        for (i, child) in self.children.iter_mut().enumerate() {
+            if let Ok(child) = child {
+                if i == last {
+                    if let Some(stdout) = child.stdout.take() {
+                        readers.push(Self::send_lines(stdout, true, tx.clone())?);
+                    }
+                }
+                if let Some(stderr) = child.stderr.take() {
+                    readers.push(Self::send_lines(stderr, false, tx.clone())?);
+                }
+            }
+        }
+        // the receiving ends after all the readers are finished, with the last lines sent
+        drop(tx);
+        for (is_stdout, line) in rx {
+            f(is_stdout, &line);
+        }
+        for reader in readers {
+            let _ = reader.join();
+        }
+        self.wait_with_output_bytes().map(|_| ())
+    }
+
+    fn send_lines(
+        reader: PipeReader,
+        is_stdout: bool,
+        tx: Sender<(bool, String)>,
+    ) -> Result<JoinHandle<()>> {
+        thread::Builder::new().spawn(move || {
+            let mut reader = BufReader::new(reader);
+            let mut buf = vec![];
+            loop {
+                buf.clear();
+                match reader.read_until(b'\n', &mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {
+                        if buf.ends_with(b"\n") {
+                            buf.pop();
+                        }
+                        let line = String::from_utf8_lossy(&buf).into_owned();
+                        if tx.send((is_stdout, line)).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+struct FunOutput<R> {
+    reader: Option<R>,
+    children: Option<FunChildren>,
+    polling_stderr: Option<StderrLogging>,
+}
+
+impl<R> FunOutput<R> {
+    fn finish(&mut self) -> Option<Result<String>> {
+        // close the output first, so the children won't be blocked on writing
+        self.reader = None;
+        let ret = self.children.take()?.wait_with_output_bytes();
+        self.polling_stderr = None;
+        ret.err().map(Err)
+    }
+}
+
+impl Iterator for FunOutput<BufReader<PipeReader>> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(reader) = self.reader.as_mut() {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) => {}
+                Ok(_) => {
+                    if line.ends_with('\n') {
+                        line.pop();
+                        if line.ends_with('\r') {
+                            line.pop();
+                        }
+                    }
+                    return Some(Ok(line));
+                }
+                Err(e) => {
+                    let _ = self.finish();
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.finish()
+    }
+}
+
+impl Read for FunOutput<PipeReader> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if let Some(reader) = self.reader.as_mut() {
+            match reader.read(buf) {
+                Ok(0) if !buf.is_empty() => {}
+                Err(e) if e.kind() != ErrorKind::Interrupted => {
+                    let _ = self.finish();
+                    return Err(e);
+                }
+                ret => return ret,
+            }
+        }
+        self.finish().map_or(Ok(0), |ret| ret.map(|_| 0))
+    }
+}
+
+impl<R> Drop for FunOutput<R> {
+    fn drop(&mut self) {
+        let _ = self.finish();
     }
 }
 
@@ -138,6 +769,15 @@ pub(crate) struct CmdChild {
     cmd: String,
     stdout: Option<PipeReader>,
     stderr: Option<PipeReader>,
+    // started with "ignore" in the middle of the pipeline
+    ignore_error: bool,
+    // leading its own process group
+    process_group: bool,
+    // the threads feeding stdin from `Cmd::stdin_from()` or copying the output into the buffers
+    io_threads: Vec<JoinHandle<()>>,
+    // the output files replaced only if the command succeeds, with `set_atomic_redirect(true)`
+    atomic_files: Vec<AtomicFile>,
+    started: Instant,
 }
 
 impl CmdChild {
@@ -152,50 +792,229 @@ impl CmdChild {
             cmd,
             stdout,
             stderr,
+            ignore_error: false,
+            process_group: false,
+            io_threads: vec![],
+            atomic_files: vec![],
+            started: Instant::now(),
         }
     }
 
-    fn wait(self, is_last: bool) -> CmdResult {
-        let res = self.handle.wait_with_stderr(self.stderr, &self.cmd);
-        if let Err(e) = res {
-            if is_last || process::pipefail_enabled() {
-                return Err(e);
+    pub(crate) fn with_ignore_error(mut self, ignore_error: bool) -> Self {
+        self.ignore_error = ignore_error;
+        self
+    }
+
+    pub(crate) fn with_process_group(mut self, process_group: bool) -> Self {
+        self.process_group = process_group;
+        self
+    }
+
+    pub(crate) fn with_io_threads(mut self, io_threads: Vec<JoinHandle<()>>) -> Self {
+        self.io_threads.extend(io_threads);
+        self
+    }
+
+    pub(crate) fn with_atomic_files(mut self, atomic_files: Vec<AtomicFile>) -> Self {
+        self.atomic_files = atomic_files;
+        self
+    }
+
+    // before spawning, so the builtins run in the current thread are timed as well
+    pub(crate) fn with_started(mut self, started: Instant) -> Self {
+        self.started = started;
+        self
+    }
+
+    fn trace_finished(cmd: &str, started: Instant) -> Duration {
+        let elapsed = started.elapsed();
+        if debug_enabled() {
+            debug_trace(&format!(
+                "Command {} finished in {}ms",
+                cmd,
+                elapsed.as_millis()
+            ));
+        }
+        elapsed
+    }
+
+    // the command has finished, so the feeder stops soon with a broken pipe if not done yet, and
+    // the output is all copied into the buffers after joining
+    fn join_io_threads(io_threads: Vec<JoinHandle<()>>) {
+        for io_thread in io_threads {
+            let _ = io_thread.join();
+        }
+    }
+
+    // the files are removed instead when dropped, if the command failed
+    fn commit_atomic_files(atomic_files: Vec<AtomicFile>) -> CmdResult {
+        let mut ret = Ok(());
+        for atomic_file in atomic_files {
+            let res = atomic_file.commit();
+            if ret.is_ok() {
+                ret = res;
             }
         }
-        Ok(())
+        ret
     }
 
-    fn wait_with_output(self, ignore_error: bool) -> Result<Vec<u8>> {
-        let buf = {
-            if let Some(mut out) = self.stdout {
-                let mut buf = vec![];
-                if let Err(e) = out.read_to_end(&mut buf) {
-                    if !ignore_error {
-                        return Err(CmdChildHandle::cmd_io_error(e, &self.cmd, false));
-                    }
+    fn log_stderr(&mut self) -> StderrLogging {
+        StderrLogging::new(&self.cmd, self.stderr.take())
+    }
+
+    fn kill(self, polling_stderr: StderrLogging) -> CmdResult {
+        let (mut handle, cmd) = (self.handle, self.cmd);
+        let running_thread = matches!(handle, CmdChildHandle::Thread(..)) && !handle.is_finished();
+        let ret = handle.kill(self.process_group);
+        if running_thread {
+            // the thread still holds the stderr pipe, don't wait for it
+            polling_stderr.detach();
+        }
+        ret.map_err(|e| {
+            warn!("Killing {} failed: {}", cmd, e);
+            CmdChildHandle::cmd_io_error(e, &cmd, false)
+        })
+    }
+
+    fn wait_status(self) -> Result<i32> {
+        let polling_stderr = StderrLogging::new(&self.cmd, self.stderr);
+        let ret = self.handle.wait_status(&self.cmd);
+        drop(polling_stderr);
+        Self::join_io_threads(self.io_threads);
+        Self::trace_finished(&self.cmd, self.started);
+        match ret {
+            Ok(0) => Self::commit_atomic_files(self.atomic_files).map(|_| 0),
+            ret => ret,
+        }
+    }
+
+    // the failure is only returned for the last command, or with pipefail
+    fn wait(self, report_error: bool) -> CmdResult {
+        self.wait_timed(report_error).0
+    }
+
+    // also returning the time from spawning to finishing
+    fn wait_timed(self, report_error: bool) -> (CmdResult, Duration) {
+        let res = self.handle.wait_with_stderr(self.stderr, &self.cmd);
+        Self::join_io_threads(self.io_threads);
+        let elapsed = Self::trace_finished(&self.cmd, self.started);
+        let atomic_files = self.atomic_files;
+        let res = res.and_then(|_| Self::commit_atomic_files(atomic_files));
+        match res {
+            Err(e) if report_error && !self.ignore_error => (Err(e), elapsed),
+            _ => (Ok(()), elapsed),
+        }
+    }
+
+    fn wait_with_output(self, ignore_error: bool, buf: &mut Vec<u8>) -> CmdResult {
+        let ignore_error = ignore_error || self.ignore_error;
+        if let Some(mut out) = self.stdout {
+            if let Err(e) = out.read_to_end(buf) {
+                if !ignore_error {
+                    return Err(CmdChildHandle::cmd_io_error(e, &self.cmd, false));
                 }
-                buf
-            } else {
-                vec![]
             }
-        };
+        }
         let res = self.handle.wait_with_stderr(self.stderr, &self.cmd);
+        Self::join_io_threads(self.io_threads);
+        Self::trace_finished(&self.cmd, self.started);
+        let atomic_files = self.atomic_files;
+        let res = res.and_then(|_| Self::commit_atomic_files(atomic_files));
         if let Err(e) = res {
             if !ignore_error {
                 return Err(e);
             }
         }
-        Ok(buf)
+        Ok(())
     }
 }
 
 pub(crate) enum CmdChildHandle {
     Proc(Child),
-    Thread(JoinHandle<CmdResult>),
-    SyncFn(()),
+    Thread(JoinHandle<Result<i32>>, Arc<AtomicBool>),
+    SyncFn(i32),
 }
 
 impl CmdChildHandle {
+    fn id(&self) -> Option<u32> {
+        match self {
+            CmdChildHandle::Proc(proc) => Some(proc.id()),
+            CmdChildHandle::Thread(..) | CmdChildHandle::SyncFn(_) => None,
+        }
+    }
+
+    fn is_finished(&mut self) -> bool {
+        match self {
+            CmdChildHandle::Proc(proc) => !matches!(proc.try_wait(), Ok(None)),
+            CmdChildHandle::Thread(thread, _) => thread.is_finished(),
+            CmdChildHandle::SyncFn(_) => true,
+        }
+    }
+
+    fn kill(self, process_group: bool) -> CmdResult {
+        match self {
+            CmdChildHandle::Proc(mut proc) => {
+                #[cfg(unix)]
+                if process_group {
+                    Self::signal_group(&proc, libc::SIGKILL)?;
+                    return proc.wait().map(|_| ());
+                }
+                #[cfg(not(unix))]
+                let _ = process_group;
+                proc.kill()?;
+                proc.wait().map(|_| ())
+            }
+            CmdChildHandle::Thread(thread, killed) => {
+                // running threads can not be killed, ask them to stop and detach them
+                killed.store(true, Ordering::Relaxed);
+                if thread.is_finished() {
+                    let _ = thread.join();
+                }
+                Ok(())
+            }
+            CmdChildHandle::SyncFn(_) => Ok(()),
+        }
+    }
+
+    fn signal(&mut self, sig: i32, process_group: bool) -> CmdResult {
+        if let CmdChildHandle::Proc(proc) = self {
+            #[cfg(unix)]
+            if process_group {
+                return Self::signal_group(proc, sig);
+            }
+            #[cfg(not(unix))]
+            let _ = process_group;
+            // the pid of a reaped process could have been reused already
+            if let Ok(Some(_)) = proc.try_wait() {
+                return Ok(());
+            }
+            #[cfg(unix)]
+            if unsafe { libc::kill(proc.id() as libc::pid_t, sig) } != 0 {
+                return Err(Error::last_os_error());
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = sig;
+                proc.kill()?;
+            }
+        }
+        Ok(())
+    }
+
+    // the group outlives its leader while the grandchildren are running, and its id won't be
+    // reused by then, so it is signaled even after the child exited
+    #[cfg(unix)]
+    fn signal_group(proc: &Child, sig: i32) -> CmdResult {
+        if unsafe { libc::kill(-(proc.id() as libc::pid_t), sig) } != 0 {
+            let e = Error::last_os_error();
+            // all the processes in the group are gone
+            if e.raw_os_error() != Some(libc::ESRCH) {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
     fn wait_with_stderr(self, stderr: Option<PipeReader>, cmd: &str) -> CmdResult {
         let polling_stderr = StderrLogging::new(cmd, stderr);
         match self {
@@ -205,36 +1024,81 @@ impl CmdChildHandle {
                     Err(e) => return Err(CmdChildHandle::cmd_io_error(e, cmd, false)),
                     Ok(status) => {
                         if !status.success() {
-                            return Err(Self::status_to_io_error(
-                                status,
-                                &format!("Running {} exited with error", cmd),
-                            ));
+                            return Err(CmdError::NonZeroExit {
+                                cmd: cmd.into(),
+                                code: status.code(),
+                            }
+                            .into());
                         }
                     }
                 }
             }
-            CmdChildHandle::Thread(thread) => {
+            CmdChildHandle::Thread(thread, _) => {
                 let status = thread.join();
                 match status {
-                    Ok(result) => {
-                        if let Err(e) = result {
-                            return Err(CmdChildHandle::cmd_io_error(e, cmd, false));
+                    Ok(Ok(0)) => {}
+                    Ok(Ok(code)) => {
+                        return Err(CmdError::NonZeroExit {
+                            cmd: cmd.into(),
+                            code: Some(code),
                         }
+                        .into());
                     }
+                    Ok(Err(e)) => return Err(CmdChildHandle::cmd_io_error(e, cmd, false)),
                     Err(e) => {
-                        return Err(Error::new(
-                            ErrorKind::Other,
-                            format!("Running {} thread joined with error: {:?}", cmd, e),
-                        ))
+                        return Err(Error::other(format!(
+                            "Running {} thread joined with error: {:?}",
+                            cmd, e
+                        )))
+                    }
+                }
+            }
+            CmdChildHandle::SyncFn(code) => {
+                if code != 0 {
+                    return Err(CmdError::NonZeroExit {
+                        cmd: cmd.into(),
+                        code: Some(code),
                     }
+                    .into());
                 }
             }
-            CmdChildHandle::SyncFn(_) => {}
         }
         drop(polling_stderr);
         Ok(())
     }
 
+    fn wait_status(self, cmd: &str) -> Result<i32> {
+        match self {
+            CmdChildHandle::Proc(mut proc) => proc
+                .wait()
+                .map(Self::exit_code)
+                .map_err(|e| CmdChildHandle::cmd_io_error(e, cmd, false)),
+            CmdChildHandle::Thread(thread, _) => match thread.join() {
+                Ok(Ok(code)) => Ok(code),
+                Ok(Err(e)) => {
+                    info!("Running {} failed: {}", cmd, e);
+                    Ok(1)
+                }
+                Err(e) => Err(Error::other(format!(
+                    "Running {} thread joined with error: {:?}",
+                    cmd, e
+                ))),
+            },
+            CmdChildHandle::SyncFn(code) => Ok(code),
+        }
+    }
+
+    fn exit_code(status: ExitStatus) -> i32 {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signal) = status.signal() {
+                return 128 + signal;
+            }
+        }
+        status.code().unwrap_or(1)
+    }
+
     fn cmd_io_error(e: Error, command: &str, spawning: bool) -> Error {
         Error::new(
             e.kind(),
@@ -246,20 +1110,6 @@ impl CmdChildHandle {
             ),
         )
     }
-
-    fn status_to_io_error(status: ExitStatus, command: &str) -> Error {
-        if let Some(code) = status.code() {
-            Error::new(
-                ErrorKind::Other,
-                format!("{}; status code: {}", command, code),
-            )
-        } else {
-            Error::new(
-                ErrorKind::Other,
-                format!("{}; terminated by {}", command, status),
-            )
-        }
-    }
 }
 
 struct StderrLogging {
@@ -273,7 +1123,7 @@ impl StderrLogging {
             let thread = std::thread::spawn(move || {
                 BufReader::new(stderr)
                     .lines()
-                    .filter_map(|line| line.ok())
+                    .map_while(Result::ok)
                     .for_each(|line| info!("{}", line))
             });
             Self {
@@ -287,6 +1137,10 @@ impl StderrLogging {
             }
         }
     }
+
+    fn detach(mut self) {
+        self.thread.take();
+    }
 }
 
 impl Drop for StderrLogging {
@@ -0,0 +1,81 @@
+use std::error;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+/// Structured error for running commands
+///
+/// It is carried as the inner error of the `std::io::Error` returned by the macros, and can be
+/// retrieved with [`CmdError::from_io_error`]:
+///
+/// ```
+/// # use cmd_lib::*;
+/// let err = run_cmd!(sh -c "exit 3").unwrap_err();
+/// assert_eq!(CmdError::from_io_error(&err).and_then(CmdError::exit_code), Some(3));
+/// ```
+#[derive(Debug)]
+pub enum CmdError {
+    /// The command could not be spawned
+    Spawn { cmd: String, source: Error },
+    /// The command exited with non-zero status code, or it was terminated by a signal
+    NonZeroExit { cmd: String, code: Option<i32> },
+}
+
+impl CmdError {
+    /// Returns the CmdError carried by `e`, if any
+    pub fn from_io_error(e: &Error) -> Option<&CmdError> {
+        e.get_ref()?.downcast_ref()
+    }
+
+    /// Returns the command string which caused this error
+    pub fn cmd(&self) -> &str {
+        match self {
+            CmdError::Spawn { cmd, .. } | CmdError::NonZeroExit { cmd, .. } => cmd,
+        }
+    }
+
+    /// Returns the exit code of the command, if it exited with non-zero status code
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            CmdError::Spawn { .. } => None,
+            CmdError::NonZeroExit { code, .. } => *code,
+        }
+    }
+}
+
+impl fmt::Display for CmdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CmdError::Spawn { cmd, source } => write!(f, "Spawning {} failed: {}", cmd, source),
+            CmdError::NonZeroExit {
+                cmd,
+                code: Some(code),
+            } => write!(
+                f,
+                "Running {} exited with error; status code: {}",
+                cmd, code
+            ),
+            CmdError::NonZeroExit { cmd, code: None } => {
+                write!(f, "Running {} exited with error; terminated by signal", cmd)
+            }
+        }
+    }
+}
+
+impl error::Error for CmdError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CmdError::Spawn { source, .. } => Some(source),
+            CmdError::NonZeroExit { .. } => None,
+        }
+    }
+}
+
+impl From<CmdError> for Error {
+    fn from(e: CmdError) -> Error {
+        let kind = match e {
+            CmdError::Spawn { ref source, .. } => source.kind(),
+            CmdError::NonZeroExit { .. } => ErrorKind::Other,
+        };
+        Error::new(kind, e)
+    }
+}
@@ -1,13 +1,18 @@
 use os_pipe::*;
-use std::fs::File;
-use std::io::{Read, Result, Write};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Cursor, Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 #[derive(Debug)]
 pub enum CmdIn {
     Null,
     File(File),
     Pipe(PipeReader),
+    // read in the current process, or through a pipe fed by a thread for the programs
+    Buffer(Cursor<Vec<u8>>),
 }
 
 impl Read for CmdIn {
@@ -16,6 +21,18 @@ impl Read for CmdIn {
             CmdIn::Null => Ok(0),
             CmdIn::File(file) => file.read(buf),
             CmdIn::Pipe(pipe) => pipe.read(buf),
+            CmdIn::Buffer(cursor) => cursor.read(buf),
+        }
+    }
+}
+
+impl CmdIn {
+    pub fn try_clone(&self) -> Result<Self> {
+        match self {
+            CmdIn::Null => Ok(CmdIn::Null),
+            CmdIn::File(file) => file.try_clone().map(CmdIn::File),
+            CmdIn::Pipe(pipe) => pipe.try_clone().map(CmdIn::Pipe),
+            CmdIn::Buffer(cursor) => Ok(CmdIn::Buffer(cursor.clone())),
         }
     }
 }
@@ -26,6 +43,7 @@ impl From<CmdIn> for Stdio {
             CmdIn::Null => Stdio::null(),
             CmdIn::File(file) => Stdio::from(file),
             CmdIn::Pipe(pipe) => Stdio::from(pipe),
+            CmdIn::Buffer(_) => unreachable!("buffers are replaced by pipes for the programs"),
         }
     }
 }
@@ -35,6 +53,8 @@ pub enum CmdOut {
     Null,
     File(File),
     Pipe(PipeWriter),
+    // shared by the clones, so `2>&1` writes into the same buffer
+    Buffer(Arc<Mutex<Vec<u8>>>),
 }
 
 impl Write for CmdOut {
@@ -43,6 +63,10 @@ impl Write for CmdOut {
             CmdOut::Null => Ok(buf.len()),
             CmdOut::File(file) => file.write(buf),
             CmdOut::Pipe(pipe) => pipe.write(buf),
+            CmdOut::Buffer(buffer) => {
+                buffer.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
         }
     }
 
@@ -51,6 +75,7 @@ impl Write for CmdOut {
             CmdOut::Null => Ok(()),
             CmdOut::File(file) => file.flush(),
             CmdOut::Pipe(pipe) => pipe.flush(),
+            CmdOut::Buffer(_) => Ok(()),
         }
     }
 }
@@ -61,6 +86,7 @@ impl CmdOut {
             CmdOut::Null => Ok(CmdOut::Null),
             CmdOut::File(file) => file.try_clone().map(CmdOut::File),
             CmdOut::Pipe(pipe) => pipe.try_clone().map(CmdOut::Pipe),
+            CmdOut::Buffer(buffer) => Ok(CmdOut::Buffer(buffer.clone())),
         }
     }
 }
@@ -71,6 +97,73 @@ impl From<CmdOut> for Stdio {
             CmdOut::Null => Stdio::null(),
             CmdOut::File(file) => Stdio::from(file),
             CmdOut::Pipe(pipe) => Stdio::from(pipe),
+            CmdOut::Buffer(_) => unreachable!("buffers are replaced by pipes for the programs"),
+        }
+    }
+}
+
+// The output file of `set_atomic_redirect(true)`, written as a temporary file in the same
+// directory first. It replaces the file with a rename only when committed after the command
+// succeeds, otherwise the temporary file is removed when dropped.
+pub(crate) struct AtomicFile {
+    tmp_path: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub(crate) fn create(path: &Path) -> Result<(Self, File)> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let name = path.file_name().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "not a file name for atomic redirect",
+            )
+        })?;
+        let tmp_name = format!(
+            ".{}.{}.{}.tmp",
+            name.to_string_lossy(),
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let tmp_path = path.with_file_name(tmp_name);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)?;
+        // the replaced file keeps its permissions
+        if let Ok(metadata) = fs::metadata(path) {
+            let _ = fs::set_permissions(&tmp_path, metadata.permissions());
+        }
+        let atomic_file = Self {
+            tmp_path,
+            path: path.to_path_buf(),
+            committed: false,
+        };
+        Ok((atomic_file, file))
+    }
+
+    pub(crate) fn commit(mut self) -> Result<()> {
+        fs::rename(&self.tmp_path, &self.path).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!(
+                    "failed to rename {} to {}: {}",
+                    self.tmp_path.display(),
+                    self.path.display(),
+                    e
+                ),
+            )
+        })?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.tmp_path);
         }
     }
 }
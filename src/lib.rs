@@ -114,6 +114,10 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! Commands can also be chained with `&&` and `||`, the same as in bash with `set -e`: the command after
+//! `&&` only runs if the previous one succeeds, and the one after `||` only runs if it fails. The failure
+//! of a command in the list only fails the whole group if it is the final status of the last list.
+//!
 //! - run_fun! --> FunResult
 //!
 //! ```
@@ -127,6 +131,32 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! - run_cmd_retry! --> CmdResult
+//!
+//! For flaky steps like network requests, the commands can be retried on failure up to the given
+//! number of attempts, with a delay between them, which is multiplied by the optional factor after
+//! each failed attempt:
+//!
+//! ```no_run
+//! # use cmd_lib::run_cmd_retry;
+//! use std::time::Duration;
+//! let url = "https://example.com/file.tar.gz";
+//! run_cmd_retry!(5, Duration::from_secs(1), 2.0; curl -sSfO $url)?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! - run_cmd_timeout! --> CmdResult
+//!
+//! To make sure a step like a CI job won't hang, the commands are killed if they don't finish in
+//! time, with an error of `ErrorKind::TimedOut`:
+//!
+//! ```no_run
+//! # use cmd_lib::run_cmd_timeout;
+//! use std::time::Duration;
+//! run_cmd_timeout!(Duration::from_secs(600); cargo test --workspace | tee test.log)?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
 //! ### Abstraction without overhead
 //!
 //! Since all the macros' lexical analysis and syntactic analysis happen at compile time, it can
@@ -155,10 +185,24 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //! You can consider "" as glue, so everything inside the quotes will be treated as a single atomic component.
+//! To write a literal dollar sign inside the quotes, escape it as `$$`, e.g. `run_fun!(echo "price: $$5")`
+//! gives `price: $5`. Since rust doesn't accept `\$` in string literals, it can't be used as the escape.
+//!
+//! Inside the quotes, the common parameter expansions of bash are supported as well:
+//! - `"${name:-default}"` gives the default when the variable is empty, and `"${name:+alt}"` gives the
+//!   alternate when it is not, both of which can refer to other variables too
+//! - `"${name:?message}"` fails at runtime with the message when the variable is empty
+//! - `"${#name}"` gives the length in characters
+//! - `"${name#pattern}"` and `"${name%pattern}"` remove the shortest prefix or suffix matching the glob
+//!   pattern, and `##` or `%%` remove the longest one
+//!
+//! For example, `run_cmd!(tar czf "${archive:-backup.tgz}" "${dir:?no directory to backup}")`, or
+//! `run_cmd!(mv $file "${file%.*}.txt")` to change the extension.
 //!
 //! If they are part of [Raw string literals](https://doc.rust-lang.org/reference/tokens.html#raw-string-literals),
-//! there will be no string interpolation, the same as in idiomatic rust. However, you can always use `format!` macro
-//! to form the new string. For example:
+//! there will be no string interpolation, the same as in idiomatic rust. Since `'x'` is a char in rust, raw strings
+//! take the place of single quotes in shell, e.g. `run_fun!(echo r"$HOME")` gives `$HOME` as it is, and `r#"..."#`
+//! can contain double quotes too. However, you can always use `format!` macro to form the new string. For example:
 //! ```no_run
 //! # use cmd_lib::run_cmd;
 //! // string interpolation
@@ -179,9 +223,57 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! Brace expansion is also supported, the same as in bash, e.g. `mkdir -p project/{src,tests,docs}` will
+//! create three directories. A leading `~` in the parameters is expanded to the home directory as well.
+//!
+//! Command substitution with `$(...)` works both in parameters and inside string literals, e.g.
+//! `run_cmd!(echo "today is $(date +%Y-%m-%d)")`, with trailing newlines of the output trimmed. The
+//! substituted commands run before the whole group starts, and their failure is returned as error.
+//!
+//! Arithmetic expansion `$((...))` evaluates integer expressions with `+ - * / % ( )`, where the
+//! variables can be used with or without `$`, e.g. `run_fun!(echo $((count * 2 + 1)))`. It fails at
+//! runtime if any variable is not an integer, or for division by zero and overflow.
+//!
 //! ### Redirection and Piping
-//! Right now piping and stdin, stdout, stderr redirection are supported. Most parts are the same as in
-//! [bash scripts](https://www.gnu.org/software/bash/manual/html_node/Redirections.html#Redirections).
+//! Right now piping and stdin, stdout, stderr redirection are supported, as well as feeding a string
+//! into stdin with here-string (`<<<`). Most parts are the same as in
+//! [bash scripts](https://www.gnu.org/software/bash/manual/html_node/Redirections.html#Redirections),
+//! e.g. `run_cmd!(cargo build &>> build.log)` appends both stdout and stderr to the same file, and
+//! `run_fun!(cargo build |& grep warning)` pipes both of them into the next command, the same as `2>&1 |`.
+//! Redirecting to or from `/dev/null` works on Windows too, where `NUL` is also accepted.
+//! Call `set_redirect_mkdir(true)` to create the missing parent directories of the output files.
+//! With `set_noclobber(true)`, plain `>` fails instead of overwriting an existing file, while `>|`
+//! always truncates it and `>>` still appends to it. With `set_atomic_redirect(true)`, `>` and `>|` write into
+//! a temporary file first, which replaces the file only if the command succeeds, so it is never left partially
+//! written.
+//! On Unix, other file descriptors can be redirected for the programs too, e.g. `3>status.log` or
+//! `5>&1`, which is not supported by builtin or custom commands.
+//!
+//! Heredoc (`<<`) takes a string literal or a `{ ... }` block instead of the free-form text until
+//! a terminator line. The block is fed as multiple lines, with the common indentation removed,
+//! and variables are substituted the same as in string literals. Since it still needs to be valid
+//! rust tokens, use a string literal for the text with unbalanced quotes or brackets.
+//!
+//! ```
+//! # use cmd_lib::run_cmd;
+//! let name = "my_app";
+//! run_cmd!(cat > /tmp/config.toml << {
+//!     [package]
+//!     name = "$name"
+//! })?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! When `< $var` takes a byte variable (`Vec<u8>`, `&[u8]`, `[u8; N]` or any type implementing
+//! `AsStdinBytes`), the data itself is fed into stdin, while other variables are still the file paths.
+//! For a reader, read it into bytes first.
+//!
+//! ```
+//! # use cmd_lib::run_fun;
+//! let input: Vec<u8> = b"b\na\n".to_vec();
+//! assert_eq!(run_fun!(sort < $input)?, "a\nb");
+//! # Ok::<(), std::io::Error>(())
+//! ```
 //!
 //! ### Logging
 //!
@@ -221,13 +313,68 @@
 //! Use `std::env::set_current_dir` if you want to change the current
 //! working directory for the whole program.
 //!
+//! Same as the shell, `cd` without arguments goes to the home directory, and `cd -` goes back to the
+//! previous directory and prints it. For the paths only known at runtime, a leading `~` and the variables
+//! like `$VAR` or `${VAR}` in the path are expanded as well, from the exported variables or the environment.
+//!
 //! #### ignore
 //!
-//! Ignore errors for command execution, which can be used without importing.
+//! Ignore errors for command execution, which can be used without importing. At the start of a
+//! pipeline, the failure of the whole pipeline is ignored, while in the middle of it, only that
+//! command's failure is ignored, e.g. `run_cmd!(cat file | ignore grep pattern | wc -l)`. When building
+//! the commands with `builder::Pipeline`, `ignore_error()` ignores the failure of the last command added only.
+//!
+//! #### with_dir
+//!
+//! Run only one command in another directory with `with_dir DIR cmd ...`, which can be used without
+//! importing. Unlike `cd`, the following commands are not affected.
+//!
+//! ```no_run
+//! # use cmd_lib::run_cmd;
+//! run_cmd!(with_dir /tmp ls; pwd)?; // pwd still prints the current directory
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! #### export
+//!
+//! Set variables for all the following commands in the same macro block with `export KEY=VALUE ...`,
+//! which can be used without importing. Like `cd`, it only changes the current scope.
+//!
+//! ```no_run
+//! # use cmd_lib::run_cmd;
+//! run_cmd! (
+//!     export GREETING=hello;
+//!     sh -c "echo $$GREETING";
+//!     GREETING=hi sh -c "echo $$GREETING"; // the command's own variables take precedence
+//! )?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! #### read
+//!
+//! Read one line from stdin into the variables with `read NAME ...`, which are exported to the following
+//! commands like `export`. The line is split on whitespace, the last variable gets the remaining words and
+//! the missing ones are set to empty. It fails at the end of input.
+//!
+//! ```no_run
+//! # use cmd_lib::run_cmd;
+//! run_cmd! (
+//!     head -1 /etc/passwd | tr ":" " " | read USER_NAME REST;
+//!     sh -c "echo $$USER_NAME";
+//! )?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! #### unset
+//!
+//! Remove variables for all the following commands in the same macro block with `unset KEY ...`,
+//! including the ones inherited from the current process, e.g. `run_cmd!(unset LD_PRELOAD; ./app)`.
+//! Like `export`, it only changes the current scope, and the command's own variables still take precedence.
 //!
 //! #### echo
 //!
-//! Print messages to stdout, which needs to be imported with `use_builtin_cmd!` macro.
+//! Print messages to stdout, which needs to be imported with `use_builtin_cmd!` macro. Same as the shell,
+//! `-n` omits the trailing newline, and `-e` interprets the escapes like `\n` and `\t`.
 //!
 //! ```
 //! # use cmd_lib::{run_cmd, use_builtin_cmd};
@@ -237,6 +384,120 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! #### test
+//!
+//! Check file types and compare strings, with `-e`, `-f`, `-d`, `-z`, `-n`, `=` and `!=`. It can also
+//! be written as `[ expr ]` after importing `test` with `use_builtin_cmd!` macro.
+//!
+//! ```no_run
+//! # use cmd_lib::{run_cmd, use_builtin_cmd};
+//! use_builtin_cmd!(test, echo);
+//! run_cmd!([ -f config.toml ] || echo "config.toml is missing")?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! #### cp, mv and rm
+//!
+//! Portable file operations implemented with `std::fs`, which behave the same on Windows. `cp` and
+//! `rm` take `-r` for directories, and `-f` to replace the destination which can not be opened or to
+//! ignore missing files. Since they shadow the system commands, they need to be imported with
+//! `use_builtin_cmd!` macro.
+//!
+//! ```no_run
+//! # use cmd_lib::{run_cmd, use_builtin_cmd};
+//! use_builtin_cmd!(cp, rm);
+//! run_cmd!(cp -r assets dist; rm -f dist/.DS_Store)?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! #### mkdir
+//!
+//! Create directories relative to the current directory of the command, with `-p` to create the missing
+//! parents and accept the existing ones, same as `mkdir` on Unix. It needs to be imported with
+//! `use_builtin_cmd!` macro.
+//!
+//! #### wc
+//!
+//! Count the lines, words and bytes of stdin or the files, with `-l`, `-w` and `-c` to print only some
+//! of them, and a total line for multiple files. Same as `wc`, the lines are the number of newlines, so
+//! `echo rust | wc -c` counts 5 bytes including the trailing newline, while a last line without the
+//! newline isn't counted. A single count of stdin is printed without padding, e.g.
+//! `run_fun!(ls | wc -l)` is just the number.
+//!
+//! #### cut
+//!
+//! Select the fields of each line with `-f LIST` and the delimiter `-d DELIM` (tab by default), or the
+//! characters with `-c LIST`, where LIST is like `1,3-5,7-`. Same as `cut`, the missing fields are skipped
+//! and the lines without the delimiter are printed as they are, e.g. `run_fun!(cat /etc/passwd | cut -d: -f1)`
+//! lists the user names.
+//!
+//! #### sort
+//!
+//! Sort the lines of stdin or the files by bytes, with `-n` to compare the leading numbers instead, where the
+//! lines without a number are 0 the same as `sort`, `-r` to reverse the order and `-u` to print the equal lines
+//! only once, e.g. `run_fun!(cat nums | sort -n | head -1)` gets the minimum. Since nothing can be printed
+//! before the last line is read, all the input is kept in memory, so avoid it for huge outputs.
+//!
+//! #### uniq
+//!
+//! Collapse the adjacent equal lines of stdin or a file into one, with `-c` to prefix each line with the
+//! number of times it is repeated and `-d` to print only the repeated lines. Same as `uniq`, the lines are
+//! streamed and only compared with the previous one, so pair it with `sort` to count all of them, e.g.
+//! `run_fun!(cat log | sort | uniq -c | sort -rn)`.
+//!
+//! #### tr
+//!
+//! Translate the characters of stdin in the first set into the ones of the second set, or delete them with
+//! `-d`, where `-s` squeezes the repeated characters of the last set into one. The sets support ranges like
+//! `a-z`, classes like `[:digit:]` and escapes like `\n`, e.g. `run_fun!(echo Hello | tr a-z A-Z)` prints
+//! `HELLO`, the same with GNU and BSD systems.
+//!
+//! #### basename and dirname
+//!
+//! Strip the directory or the last component from a path, the same as coreutils, with an optional
+//! suffix to remove for `basename`. Both `/` and `\` are separators on Windows.
+//!
+//! ```no_run
+//! # use cmd_lib::{run_fun, use_builtin_cmd};
+//! use_builtin_cmd!(basename, dirname);
+//! let file = "/a/b/c.txt";
+//! assert_eq!(run_fun!(basename $file .txt)?, "c");
+//! assert_eq!(run_fun!(dirname $file)?, "/a/b");
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! #### date
+//!
+//! Print the current time with the `date` feature, in the same way on all the platforms. `+FORMAT` takes the
+//! strftime specifiers like coreutils, and `-u` shows the time in UTC instead of the local time:
+//!
+//! ```no_run
+//! # use cmd_lib::{run_fun, use_builtin_cmd};
+//! # #[cfg(feature = "date")]
+//! # {
+//! use_builtin_cmd!(date);
+//! let backup = format!("backup-{}.tar", run_fun!(date +%Y%m%d)?);
+//! let now = run_fun!(date -u "+%Y-%m-%dT%H:%M:%SZ")?;
+//! # }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! #### which
+//!
+//! Print the path of the programs found in `PATH`, or `NAME: shell builtin` for the registered builtin and
+//! custom commands, which are run instead. It fails if any name is not found, with `NAME: not found` in
+//! stderr:
+//!
+//! ```no_run
+//! # use cmd_lib::{run_cmd, run_fun, use_builtin_cmd};
+//! use_builtin_cmd!(which);
+//! let cargo = run_fun!(which cargo)?;
+//! if run_cmd!(which rsync > /dev/null).is_err() {
+//!     // fall back to cp
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
 //! ### Macros to register your own commands
 //! Declare your function with `#[export_cmd(..)]` attribute, and import it with `use_custom_cmd!` macro:
 //!
@@ -256,15 +517,54 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! To exit with a specific code, return `std::io::Result<i32>` instead of `CmdResult`. A non-zero
+//! code fails the command like any other process, and `wait_with_status()` returns it:
+//!
+//! ```
+//! # use cmd_lib::*;
+//! #[export_cmd(is_even)]
+//! fn is_even(env: &mut CmdEnv) -> std::io::Result<i32> {
+//!     let n: i32 = env.args()[1].parse().unwrap_or(1);
+//!     Ok(n % 2)
+//! }
+//!
+//! use_custom_cmd!(is_even);
+//! run_cmd!(is_even 3 || echo "3 is odd")?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! To run other commands from your own command, like `xargs` does, call `env.run(args)`, which
+//! uses the current directory, variables, stdin and stdout of your command.
+//!
+//! To check at runtime whether a name runs a registered builtin or custom command instead of an
+//! external program, use `is_builtin(name)`, and `registered_builtins()` lists all of their names.
+//!
 //! ### Low-level process spawning macros
 //!
 //! `spawn!` macro executes the whole command as a child process, returning a handle to it. By
 //! default, stdin, stdout and stderr are inherited from the parent. The process will run in the
 //! background, so you can run other stuff concurrently. You can call `wait()` to wait
-//! for the process to finish.
+//! for the process to finish, or `wait_timeout()` to kill it if it runs for too long. To stop it
+//! at any time, call `kill_all()`. If a non-zero exit code is expected, `wait_with_status()` returns
+//! it without treating it as an error.
 //!
 //! With `spawn_with_output!` you can get output by calling `wait_with_output()`, or even do stream
-//! processing with `wait_with_pipe()`.
+//! processing with `wait_with_pipe()` or `lines()`. For large outputs, `into_reader()` reads them
+//! without buffering, e.g. to copy them into a file with `std::io::copy`. To get stderr as well,
+//! `wait_with_streams()` passes the lines of both streams as they arrive, `wait_with_all()` collects
+//! them with the exit code, and `wait_with_output_combined()` merges them into one string. With
+//! `builder::Pipeline::spawn_stderr_captured()`, the output goes to the terminal directly, while
+//! `wait_stderr_captured()` returns the captured stderr. To tell apart several pipelines running
+//! together, `stream_prefixed("web")` prints their lines tagged like `web | ...`.
+//!
+//! Same as `run_fun!`, `wait_with_output()` trims the trailing newline, and `wait_with_output_raw()`
+//! returns the output untouched, while `wait_with_output_and_status()` keeps the output even if the
+//! commands fail. With the `serde` feature enabled, `wait_with_json()` deserializes the output of
+//! tools like `kubectl get -o json` directly. In hot loops, `wait_into()` and `wait_into_bytes()` append
+//! the untouched output to a reusable buffer instead of allocating a new one for each command.
+//!
+//! Same as in bash, a trailing `&` runs the commands in the background: `run_cmd!(cmd &)` and
+//! `run_fun!(cmd &)` are the same as `spawn!(cmd)` and `spawn_with_output!(cmd)`.
 //!
 //! ```no_run
 //! # use cmd_lib::*;
@@ -287,10 +587,44 @@
 //!         .take(10)
 //!         .for_each(|line| println!("{}", line));
 //! })?;
+//!
+//! for line in spawn_with_output!(cargo build)?.lines() {
+//!     println!("{}", line?);
+//! }
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
 //!
+//! ### Building commands at runtime
+//!
+//! When the whole command comes from runtime data, like the argv provided by the users, the
+//! `builder::Pipeline` assembles it without the macros, and runs it with the same error handling and
+//! pipefail. Each argument is passed as it is, so there is nothing to quote or escape. Besides running it
+//! like the macros, `spawn_to(file)` writes the output of the last command into an open file directly,
+//! without going through a pipe. A closure can also be a stage of the pipeline with `pipe_fn()`, running
+//! on a thread like the custom commands, but capturing the local state without being registered:
+//!
+//! ```no_run
+//! # use cmd_lib::builder::Pipeline;
+//! # use std::io::Write;
+//! let argv = vec!["ls".to_string(), "-l".to_string()];
+//! let output = Pipeline::new().command(&argv).pipe(["grep", "foo"]).run_fun()?;
+//!
+//! let mut count = 0;
+//! Pipeline::new()
+//!     .command(["cat", "data.txt"])
+//!     .pipe_fn(move |env| {
+//!         for line in env.stdin_string()?.lines() {
+//!             count += 1;
+//!             writeln!(env.stdout(), "{}: {}", count, line)?;
+//!         }
+//!         Ok(())
+//!     })
+//!     .pipe(["sort", "-r"])
+//!     .run()?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
 //! ### Macros to define, get and set thread-local global variables
 //! - `tls_init!` to define thread local global variable
 //! - `tls_get!` to get the value
@@ -323,6 +657,19 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! By default, the commands inherit all the environment variables of the current process. Call
+//! `set_env_inherit(false)` to run them with only the assigned variables, e.g. for reproducible builds.
+//!
+//! With `set_debug(true)` or `CMD_LIB_DEBUG=1`, each command is traced before running, and its duration
+//! is traced after finishing. The traces go to the `log` crate by default, and
+//! `set_debug_target(DebugTarget::Stderr)` prints them directly without a logger, while
+//! `DebugTarget::Custom(f)` passes them to your own function. To get the durations in code, call
+//! `wait_with_timings()` on the spawned children.
+//!
+//! To observe or adjust every spawned process, e.g. for tracing or resource limits, register a hook with
+//! `set_command_hook(Some(hook))`. It is called with the final `std::process::Command` right before
+//! spawning it. Builtin and custom commands have their own `set_builtin_hook()` with the `CmdEnv` instead.
+//!
 //! ### Security Notes
 //! Using macros can actually avoid command injection, since we do parsing before variable substitution.
 //! For example, below code is fine even without any quotes:
@@ -337,8 +684,17 @@
 //!
 //! ### Glob/Wildcard
 //!
-//! This library does not provide glob functions, to avoid silent errors and other surprises.
-//! You can use the [glob](https://github.com/rust-lang-nursery/glob) package instead.
+//! Unquoted parameters with wildcards (`*`, `?` or `[...]`) are expanded at runtime, relative to
+//! the current directory of the commands, e.g. `run_cmd!(cd /tmp; rm *.tmp)`. Same as bash, the
+//! pattern is passed as it is when nothing matches, and hidden files only match with a leading `.`
+//! in the pattern. Quoted strings like `"*.tmp"` are never expanded. Notice `/*` starts a comment in
+//! rust, so write `$dir/?*.log` instead of `$dir/*.log`.
+//!
+//! For more complex cases, you can use the [glob](https://github.com/rust-lang-nursery/glob) package.
+//!
+//! Some shell syntax is passed to the commands as it is, like `#` which doesn't start a comment, or the
+//! backticks in strings which don't substitute the command. When porting the scripts, enable the
+//! `warn-metachars` feature to get compile-time warnings for them.
 //!
 //! ### Thread Safety
 //!
@@ -349,27 +705,46 @@
 
 pub use cmd_lib_macros::{
     cmd_debug, cmd_die, cmd_echo, cmd_error, cmd_info, cmd_trace, cmd_warn, export_cmd, run_cmd,
-    run_fun, spawn, spawn_with_output, use_builtin_cmd, use_custom_cmd,
+    run_cmd_retry, run_cmd_timeout, run_fun, spawn, spawn_with_output, use_builtin_cmd,
+    use_custom_cmd,
 };
 /// Return type for run_fun!() macro
 pub type FunResult = std::io::Result<String>;
 /// Return type for run_cmd!() macro
 pub type CmdResult = std::io::Result<()>;
+pub use arith::eval_arith;
+#[cfg(feature = "date")]
+pub use builtins::builtin_date;
 pub use builtins::{
-    builtin_cat, builtin_debug, builtin_die, builtin_echo, builtin_error, builtin_info,
-    builtin_trace, builtin_warn,
+    builtin_basename, builtin_cat, builtin_cp, builtin_cut, builtin_debug, builtin_die,
+    builtin_dirname, builtin_echo, builtin_env, builtin_error, builtin_grep, builtin_head,
+    builtin_info, builtin_mkdir, builtin_mv, builtin_printf, builtin_pwd, builtin_rm, builtin_seq,
+    builtin_sleep, builtin_sort, builtin_tail, builtin_tee, builtin_test, builtin_tr,
+    builtin_trace, builtin_uniq, builtin_warn, builtin_wc, builtin_which, builtin_xargs,
 };
-pub use child::{CmdChildren, FunChildren};
+pub use child::{CmdChildren, CmdOutput, FunChildren};
+pub use error::CmdError;
 #[doc(hidden)]
 pub use log;
 pub use logger::init_builtin_logger;
+#[cfg(unix)]
+pub use process::Resource;
 pub use process::{
-    export_cmd, set_debug, set_pipefail, AsOsStr, Cmd, CmdEnv, CmdString, Cmds, GroupCmds, Redirect,
+    check_var, export_cmd, home_dir, is_builtin, registered_builtins, run_with_retry,
+    set_atomic_redirect, set_builtin_hook, set_command_hook, set_debug, set_debug_target,
+    set_dry_run, set_env_inherit, set_noclobber, set_path_check, set_pipefail, set_redirect_mkdir,
+    trim_var, AsOsStr, AsStdinBytes, Cmd, CmdEnv, CmdExitCode, CmdString, Cmds, DebugTarget,
+    GroupCmds, Redirect, StdinBytesVar, StdinPathVar, StdinStrVar, StdinVar,
 };
 
+mod arith;
+pub mod builder;
 mod builtins;
 mod child;
+mod error;
 mod io;
 mod logger;
 mod process;
+#[cfg(all(unix, feature = "pty"))]
+mod pty;
 mod thread_local;
@@ -1,24 +1,38 @@
 use crate::child::{CmdChild, CmdChildHandle, CmdChildren, FunChildren};
-use crate::io::{CmdIn, CmdOut};
-use crate::{CmdResult, FunResult};
+use crate::io::{AtomicFile, CmdIn, CmdOut};
+use crate::{CmdError, CmdResult, FunResult};
 use faccess::{AccessMode, PathExt};
 use lazy_static::lazy_static;
-use log::{debug, warn};
-use os_pipe::{self, PipeReader, PipeWriter};
+use log::{debug, info, warn};
+use os_pipe::{self, PipeReader};
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::fs::{File, OpenOptions};
-use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Cursor, Error, ErrorKind, Read, Result, Write};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt as _;
 #[cfg(target_os = "windows")]
 use std::os::windows::process::CommandExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 const CD_CMD: &str = "cd";
+const EXPORT_CMD: &str = "export";
+const READ_CMD: &str = "read";
+const UNSET_CMD: &str = "unset";
 const IGNORE_CMD: &str = "ignore";
+const WITH_DIR_CMD: &str = "with_dir";
+#[cfg(unix)]
+const DEFAULT_PATH: &str = "/bin:/usr/bin";
+#[cfg(not(unix))]
+const DEFAULT_PATH: &str = "";
 
 /// Environment for builtin or custom commands
 pub struct CmdEnv {
@@ -27,7 +41,10 @@ pub struct CmdEnv {
     stderr: CmdOut,
     args: Vec<String>,
     vars: HashMap<String, String>,
+    // removed by "unset", hiding the ones inherited from the current process
+    removed_vars: Vec<String>,
     current_dir: PathBuf,
+    killed: Arc<AtomicBool>,
 }
 impl CmdEnv {
     /// Returns the arguments for this command
@@ -40,6 +57,20 @@ impl CmdEnv {
         self.vars.get(key)
     }
 
+    /// Sets the environment variable key to value for this command
+    pub fn set_var(&mut self, key: String, value: String) {
+        self.vars.insert(key, value);
+    }
+
+    /// Returns an iterator of all the environment variables for this command
+    pub fn vars(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.vars.iter()
+    }
+
+    pub(crate) fn removed_vars(&self) -> &[String] {
+        &self.removed_vars
+    }
+
     /// Returns the current working directory for this command
     pub fn current_dir(&self) -> &Path {
         &self.current_dir
@@ -59,9 +90,115 @@ impl CmdEnv {
     pub fn stderr(&mut self) -> impl Write + '_ {
         &mut self.stderr
     }
+
+    // both stdin and stdout, for the builtins streaming the input to the output
+    pub(crate) fn stdin_stdout(&mut self) -> (impl Read + '_, impl Write + '_) {
+        (&mut self.stdin, &mut self.stdout)
+    }
+
+    /// Writes the whole string to the standard output for this command
+    pub fn write_stdout(&mut self, s: &str) -> CmdResult {
+        self.stdout.write_all(s.as_bytes())
+    }
+
+    /// Writes the whole string to the standard error for this command
+    pub fn write_stderr(&mut self, s: &str) -> CmdResult {
+        self.stderr.write_all(s.as_bytes())
+    }
+
+    /// Reads all the standard input for this command into bytes
+    pub fn stdin_bytes(&mut self) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        self.stdin.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Reads all the standard input for this command into a string, replacing invalid UTF-8
+    /// sequences
+    pub fn stdin_string(&mut self) -> Result<String> {
+        let buf = self.stdin_bytes()?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    /// Returns true if this command was asked to stop, e.g. by `CmdChildren::kill_all()`
+    ///
+    /// Commands running in threads can not be killed, so long-running builtin or custom
+    /// commands should check it from time to time and return early.
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::Relaxed)
+    }
+
+    /// Runs another command from this command, e.g. to implement composite commands like `xargs`
+    ///
+    /// The first argument is the program, or a builtin or custom command. It runs in this
+    /// command's current directory with its environment variables, reading from its standard
+    /// input and writing to its standard output, while the error output is logged as usual.
+    /// Since the registered commands are only looked up before running, it is fine to call the
+    /// command itself recursively, as long as it terminates.
+    ///
+    /// ```
+    /// # use cmd_lib::*;
+    /// #[export_cmd(twice)]
+    /// fn twice(env: &mut CmdEnv) -> CmdResult {
+    ///     let args = env.args()[1..].to_vec();
+    ///     env.run(&args)?;
+    ///     env.run(&args)
+    /// }
+    /// use_custom_cmd!(twice);
+    /// assert_eq!(run_fun!(twice echo hi)?, "hi\nhi");
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn run<I, O>(&mut self, args: I) -> CmdResult
+    where
+        I: IntoIterator<Item = O>,
+        O: AsRef<OsStr>,
+    {
+        let cmd = Cmd::default().add_args(args);
+        if cmd.args.is_empty() {
+            return Err(Error::other("missing command to run"));
+        }
+        let stdin = self.stdin.try_clone()?;
+        self.run_cmd(cmd, stdin)
+    }
+
+    // run another command from a builtin, writing to this command's stdout
+    pub(crate) fn run_cmd(&mut self, mut cmd: Cmd, stdin: CmdIn) -> CmdResult {
+        for (k, v) in self.vars.iter() {
+            cmd.vars.entry(k.clone()).or_insert_with(|| v.clone());
+        }
+        cmd.stdin_redirect = Some(stdin);
+        cmd.stdout_redirect = Some(self.stdout.try_clone()?);
+        let mut current_dir = self.current_dir.clone();
+        Cmds::default().pipe(cmd).run_cmd(&mut current_dir)
+    }
 }
 
-type FnFun = fn(&mut CmdEnv) -> CmdResult;
+/// Return value of builtin and custom commands, converted to the command's exit code
+///
+/// Commands returning `CmdResult` always exit with 0 on success, while commands returning
+/// `Result<i32>` can report any exit code, e.g. 1 for a false condition.
+pub trait CmdExitCode {
+    fn exit_code(self) -> i32;
+}
+
+impl CmdExitCode for () {
+    fn exit_code(self) -> i32 {
+        0
+    }
+}
+
+impl CmdExitCode for i32 {
+    fn exit_code(self) -> i32 {
+        self
+    }
+}
+
+type FnFun = Arc<dyn Fn(&mut CmdEnv) -> Result<i32> + Send + Sync>;
+// the registered commands are shared, while the closures of `Cmd::from_fn()` run only once
+type FnMutFun = Box<dyn FnMut(&mut CmdEnv) -> Result<i32> + Send>;
+
+// the name of the closures in the logs and errors
+const FN_CMD: &str = "fn";
 
 lazy_static! {
     static ref CMD_MAP: Mutex<HashMap<OsString, FnFun>> = {
@@ -69,13 +206,46 @@ lazy_static! {
         let m: HashMap<OsString, FnFun> = HashMap::new();
         Mutex::new(m)
     };
+    static ref DEBUG_TARGET: Mutex<DebugTarget> = Mutex::new(DebugTarget::Log);
+    static ref COMMAND_HOOK: Mutex<Option<fn(&mut Command)>> = Mutex::new(None);
+    static ref BUILTIN_HOOK: Mutex<Option<fn(&mut CmdEnv)>> = Mutex::new(None);
 }
 
 #[doc(hidden)]
-pub fn export_cmd(cmd: &'static str, func: FnFun) {
+pub fn export_cmd<F, R>(cmd: &'static str, func: F)
+where
+    F: Fn(&mut CmdEnv) -> Result<R> + Send + Sync + 'static,
+    R: CmdExitCode,
+{
+    let func: FnFun = Arc::new(move |env| func(env).map(CmdExitCode::exit_code));
     CMD_MAP.lock().unwrap().insert(OsString::from(cmd), func);
 }
 
+/// Returns true if `name` is registered as a builtin or custom command
+///
+/// The builtins are registered with `use_builtin_cmd!` and the custom commands with
+/// `use_custom_cmd!`, so it reflects what the macros run instead of the external programs.
+/// The commands handled by the macros directly, like `cd` or `export`, are not included.
+pub fn is_builtin(name: &str) -> bool {
+    CMD_MAP.lock().unwrap().contains_key(OsStr::new(name))
+}
+
+/// Returns the sorted names of all the registered builtin and custom commands
+///
+/// Same as `is_builtin()`, the commands handled by the macros directly are not included. It can
+/// be used to list the available commands in the help text, or to check which registrations have
+/// taken effect.
+pub fn registered_builtins() -> Vec<String> {
+    let mut names: Vec<String> = CMD_MAP
+        .lock()
+        .unwrap()
+        .keys()
+        .map(|name| name.to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
 /// set debug mode or not, false by default
 ///
 /// Setting environment variable CMD_LIB_DEBUG=0|1 has the same effect
@@ -83,13 +253,150 @@ pub fn set_debug(enable: bool) {
     std::env::set_var("CMD_LIB_DEBUG", if enable { "1" } else { "0" });
 }
 
+/// Where the command traces are written to in debug mode
+#[derive(Clone, Copy, Debug)]
+pub enum DebugTarget {
+    /// `debug!` macro of the log crate, which needs a logger to be initialized
+    Log,
+    /// stderr of the current process, even without any logger
+    Stderr,
+    /// a custom function called with each trace
+    Custom(fn(&str)),
+}
+
+/// set where the command traces are written to in debug mode, `DebugTarget::Log` by default
+///
+/// It doesn't enable debug mode, which is still controlled by `set_debug()`.
+pub fn set_debug_target(target: DebugTarget) {
+    *DEBUG_TARGET.lock().unwrap() = target;
+}
+
+pub(crate) fn debug_trace(msg: &str) {
+    let target = *DEBUG_TARGET.lock().unwrap();
+    match target {
+        DebugTarget::Log => debug!("{}", msg),
+        DebugTarget::Stderr => eprintln!("{}", msg),
+        DebugTarget::Custom(f) => f(msg),
+    }
+}
+
+/// set a hook called with each `std::process::Command` right before spawning it, none by default
+///
+/// The hook sees the final command, after its arguments, environment variables, current
+/// directory and redirects are all set up, so it can be used to add tracing, extra variables
+/// or `pre_exec` setup like resource limits. Builtin and custom commands are not spawned as
+/// processes, use `set_builtin_hook()` for them. Pass `None` to remove the hook.
+pub fn set_command_hook(hook: Option<fn(&mut Command)>) {
+    *COMMAND_HOOK.lock().unwrap() = hook;
+}
+
+/// set a hook called with the `CmdEnv` of each builtin or custom command right before running
+/// it, none by default
+///
+/// Pass `None` to remove the hook.
+pub fn set_builtin_hook(hook: Option<fn(&mut CmdEnv)>) {
+    *BUILTIN_HOOK.lock().unwrap() = hook;
+}
+
 /// set pipefail or not, true by default
 ///
-/// Setting environment variable CMD_LIB_PIPEFAIL=0|1 has the same effect
+/// Setting environment variable CMD_LIB_PIPEFAIL=0|1 has the same effect. Since it is global
+/// to the process, use `GroupCmds::pipefail()` or `Cmds::pipefail()` to override it for only
+/// some of the commands.
 pub fn set_pipefail(enable: bool) {
     std::env::set_var("CMD_LIB_PIPEFAIL", if enable { "1" } else { "0" });
 }
 
+/// set dry-run mode or not, false by default
+///
+/// In dry-run mode, commands are only logged instead of being executed, except for the builtin
+/// `cd` command, so the following commands can still be logged with the right directory.
+///
+/// Setting environment variable CMD_LIB_DRY_RUN=0|1 has the same effect
+pub fn set_dry_run(enable: bool) {
+    std::env::set_var("CMD_LIB_DRY_RUN", if enable { "1" } else { "0" });
+}
+
+/// set whether the commands inherit the environment variables of the current process, true by
+/// default
+///
+/// When disabled, the commands only see the variables assigned before them, like
+/// `FOO=100 cmd`. Notice `PATH` is cleared as well, so the programs may not be found unless
+/// using absolute paths or passing it explicitly, like `PATH=$path cmd`. Builtin and custom
+/// commands are not affected.
+///
+/// Setting environment variable CMD_LIB_ENV_INHERIT=0|1 has the same effect
+pub fn set_env_inherit(enable: bool) {
+    std::env::set_var("CMD_LIB_ENV_INHERIT", if enable { "1" } else { "0" });
+}
+
+/// set whether to check that the programs exist in `PATH` before spawning them, true by default
+///
+/// A missing program fails with a clearer error like "command not found: foo (searched PATH)".
+/// The check is only done on Unix, and it can be disabled to save the extra file system lookups
+/// when running commands in a hot loop.
+///
+/// Setting environment variable CMD_LIB_PATH_CHECK=0|1 has the same effect
+pub fn set_path_check(enable: bool) {
+    std::env::set_var("CMD_LIB_PATH_CHECK", if enable { "1" } else { "0" });
+}
+
+/// Runs the commands built by `build` up to `times` times until they succeed, sleeping `delay`
+/// after each failure, which is multiplied by `factor` for the next attempt
+///
+/// The error of the last attempt is returned if all of them fail. It is used by
+/// `run_cmd_retry!` macro, which rebuilds the commands for every attempt.
+#[doc(hidden)]
+pub fn run_with_retry<F>(times: u32, mut delay: Duration, factor: f64, mut build: F) -> CmdResult
+where
+    F: FnMut() -> Result<GroupCmds>,
+{
+    let mut attempt = 1;
+    loop {
+        match build().and_then(|mut cmds| cmds.run_cmd()) {
+            Err(e) if attempt < times => {
+                warn!("{}, retrying in {:?} ({}/{}) ...", e, delay, attempt, times);
+                thread::sleep(delay);
+                delay = delay.mul_f64(factor);
+                attempt += 1;
+            }
+            ret => return ret,
+        }
+    }
+}
+
+/// set whether to create the missing parent directories for output redirects, false by default
+///
+/// When enabled, `run_cmd!(cargo build > logs/build.log)` creates `logs` first if needed. The
+/// files redirected from are not affected.
+///
+/// Setting environment variable CMD_LIB_REDIRECT_MKDIR=0|1 has the same effect
+pub fn set_redirect_mkdir(enable: bool) {
+    std::env::set_var("CMD_LIB_REDIRECT_MKDIR", if enable { "1" } else { "0" });
+}
+
+/// set whether plain `>` redirects refuse to overwrite existing files, false by default
+///
+/// Same as bash's `set -o noclobber`, `run_cmd!(echo xx > $file)` fails when `file` already
+/// exists, while `>|` still truncates it and `>>` still appends to it.
+///
+/// Setting environment variable CMD_LIB_NOCLOBBER=0|1 has the same effect
+pub fn set_noclobber(enable: bool) {
+    std::env::set_var("CMD_LIB_NOCLOBBER", if enable { "1" } else { "0" });
+}
+
+/// set whether the truncating redirects replace the files atomically, false by default
+///
+/// The output of `>` or `>|` is written into a temporary file in the same directory, which is
+/// renamed to the file only after the command succeeds, and removed if it fails. So the file is
+/// never seen partially written, e.g. when generating the config files. `>>` still appends to
+/// the file directly.
+///
+/// Setting environment variable CMD_LIB_ATOMIC_REDIRECT=0|1 has the same effect
+pub fn set_atomic_redirect(enable: bool) {
+    std::env::set_var("CMD_LIB_ATOMIC_REDIRECT", if enable { "1" } else { "0" });
+}
+
 pub(crate) fn debug_enabled() -> bool {
     std::env::var("CMD_LIB_DEBUG") == Ok("1".into())
 }
@@ -98,61 +405,225 @@ pub(crate) fn pipefail_enabled() -> bool {
     std::env::var("CMD_LIB_PIPEFAIL") != Ok("0".into())
 }
 
+pub(crate) fn dry_run_enabled() -> bool {
+    std::env::var("CMD_LIB_DRY_RUN") == Ok("1".into())
+}
+
+pub(crate) fn path_check_enabled() -> bool {
+    std::env::var("CMD_LIB_PATH_CHECK") != Ok("0".into())
+}
+
+pub(crate) fn redirect_mkdir_enabled() -> bool {
+    std::env::var("CMD_LIB_REDIRECT_MKDIR") == Ok("1".into())
+}
+
+pub(crate) fn noclobber_enabled() -> bool {
+    std::env::var("CMD_LIB_NOCLOBBER") == Ok("1".into())
+}
+
+pub(crate) fn atomic_redirect_enabled() -> bool {
+    std::env::var("CMD_LIB_ATOMIC_REDIRECT") == Ok("1".into())
+}
+
+pub(crate) fn env_inherit_enabled() -> bool {
+    std::env::var("CMD_LIB_ENV_INHERIT") != Ok("0".into())
+}
+
 #[doc(hidden)]
 #[derive(Default)]
 pub struct GroupCmds {
     group_cmds: Vec<Cmds>,
     current_dir: PathBuf,
+    // the previous directory for "cd -"
+    prev_dir: PathBuf,
+    pipefail: Option<bool>,
+    new_process_group: bool,
+    stdin_null: bool,
+    interactive: bool,
+    #[cfg(all(unix, feature = "pty"))]
+    use_pty: bool,
+    // set by "export" for the following commands, or removed by "unset" if None
+    exported_vars: HashMap<String, Option<String>>,
 }
 
 impl GroupCmds {
+    /// Overrides the global pipefail setting for all the commands in this group
+    pub fn pipefail(mut self, enable: bool) -> Self {
+        self.pipefail = Some(enable);
+        self
+    }
+
+    /// Runs each process in its own process group on Unix
+    ///
+    /// Killing or signaling the children then reaches the whole process group, including the
+    /// grandchildren they have spawned, e.g. the compilers started by `make`. Since the processes
+    /// are no longer in the foreground process group, they won't get `Ctrl-C` from the terminal.
+    /// It has no effect on other platforms.
+    pub fn new_process_group(mut self, enable: bool) -> Self {
+        self.new_process_group = enable;
+        self
+    }
+
+    /// Reads stdin of the pipelines from the null device instead of inheriting it
+    ///
+    /// It keeps the commands running in the background from stealing the terminal input. The
+    /// explicit stdin redirects like `< file` still take precedence.
+    pub fn stdin_null(mut self, enable: bool) -> Self {
+        self.stdin_null = enable;
+        self
+    }
+
+    /// Runs the last command of the pipelines with the terminal of the current process
+    ///
+    /// Its stderr is inherited instead of being logged through a pipe, so the interactive
+    /// programs and progress bars checking whether stderr is a terminal work as in the shell,
+    /// like `run_cmd!(cat log | less)`. A builtin or custom command is also run in the current
+    /// thread. The captured output of `run_fun!` is not affected.
+    pub fn interactive(mut self, enable: bool) -> Self {
+        self.interactive = enable;
+        self
+    }
+
+    /// Captures the output of the pipelines through a pseudo-terminal instead of a pipe
+    ///
+    /// The programs checking whether stdout is a terminal, like `git` or `ls`, then print the
+    /// same colored or interactive output as in the terminal. The newlines are kept as `"\n"`.
+    /// Only the captured stdout is affected, and stderr is still logged as usual. It is only
+    /// available on Unix with the `pty` feature.
+    #[cfg(all(unix, feature = "pty"))]
+    pub fn use_pty(mut self, enable: bool) -> Self {
+        self.use_pty = enable;
+        self
+    }
+
     pub fn append(mut self, cmds: Cmds) -> Self {
         self.group_cmds.push(cmds);
         self
     }
 
+    pub fn and(mut self, mut cmds: Cmds) -> Self {
+        cmds.and_prev = true;
+        self.group_cmds.push(cmds);
+        self
+    }
+
+    pub fn or(mut self, mut cmds: Cmds) -> Self {
+        cmds.or_prev = true;
+        self.group_cmds.push(cmds);
+        self
+    }
+
     pub fn run_cmd(&mut self) -> CmdResult {
-        for cmds in self.group_cmds.iter_mut() {
-            if let Err(e) = cmds.run_cmd(&mut self.current_dir) {
-                if !cmds.ignore_error {
-                    return Err(e);
-                }
-            }
-        }
-        Ok(())
+        self.run(false).map(|_| ())
     }
 
     pub fn run_fun(&mut self) -> FunResult {
-        // run previous commands
-        let mut last_cmd = self.group_cmds.pop().unwrap();
-        self.run_cmd()?;
-        // run last function command
-        let ret = last_cmd.run_fun(&mut self.current_dir);
-        if ret.is_err() && last_cmd.ignore_error {
-            return Ok("".into());
+        self.run(true)
+    }
+
+    fn run(&mut self, with_output: bool) -> FunResult {
+        let mut output = String::new();
+        // error from a failed command followed by '&&' or '||', like bash with "set -e", it only
+        // fails the whole group if it is the final status of the last list
+        let mut list_err = None;
+        let len = self.group_cmds.len();
+        for i in 0..len {
+            let list_next = matches!(
+                self.group_cmds.get(i + 1),
+                Some(cmds) if cmds.and_prev || cmds.or_prev
+            );
+            // the output is from the last command, or the one followed by '||' only
+            let with_output =
+                with_output && self.group_cmds[i + 1..].iter().all(|cmds| cmds.or_prev);
+            let cmds = &mut self.group_cmds[i];
+            cmds.pipefail = cmds.pipefail.or(self.pipefail);
+            cmds.new_process_group = self.new_process_group;
+            cmds.stdin_null = self.stdin_null;
+            cmds.interactive = self.interactive;
+            #[cfg(all(unix, feature = "pty"))]
+            {
+                cmds.use_pty = self.use_pty;
+            }
+            if (cmds.and_prev && list_err.is_some()) || (cmds.or_prev && list_err.is_none()) {
+                // '&&' only runs after success, and '||' only runs after failure
+                continue;
+            }
+            list_err = None;
+            std::mem::swap(&mut cmds.exported_vars, &mut self.exported_vars);
+            std::mem::swap(&mut cmds.prev_dir, &mut self.prev_dir);
+            let ret = if with_output {
+                cmds.run_fun(&mut self.current_dir).map(|out| output = out)
+            } else {
+                cmds.run_cmd(&mut self.current_dir)
+            };
+            std::mem::swap(&mut cmds.exported_vars, &mut self.exported_vars);
+            std::mem::swap(&mut cmds.prev_dir, &mut self.prev_dir);
+            if let Err(e) = ret {
+                if !cmds.ignore_error {
+                    if !list_next {
+                        return Err(e);
+                    }
+                    list_err = Some(e);
+                }
+            }
         }
-        ret
+        list_err.map_or(Ok(output), Err)
     }
 
     pub fn spawn(mut self, with_output: bool) -> Result<CmdChildren> {
         assert_eq!(self.group_cmds.len(), 1);
         let mut cmds = self.group_cmds.pop().unwrap();
-        let ret = cmds.spawn(&mut self.current_dir, with_output);
-        // spawning error contains no command information, attach it here
-        if let Err(ref e) = ret {
-            if !cmds.ignore_error {
-                return Err(Error::new(
-                    e.kind(),
-                    format!("Spawning {} failed: {}", cmds.get_full_cmds(), e),
-                ));
+        cmds.pipefail = cmds.pipefail.or(self.pipefail);
+        cmds.new_process_group = self.new_process_group;
+        cmds.stdin_null = self.stdin_null;
+        cmds.interactive = self.interactive;
+        #[cfg(all(unix, feature = "pty"))]
+        {
+            cmds.use_pty = self.use_pty;
+        }
+        cmds.exported_vars = self.exported_vars;
+        cmds.prev_dir = self.prev_dir;
+        match cmds.spawn(&mut self.current_dir, with_output) {
+            // spawning error contains no command information, attach it here
+            Err(e) if !cmds.ignore_error => Err(CmdError::Spawn {
+                cmd: cmds.get_full_cmds().into(),
+                source: e,
             }
+            .into()),
+            ret => ret,
         }
-        ret
     }
 
     pub fn spawn_with_output(self) -> Result<FunChildren> {
         self.spawn(true).map(CmdChildren::into_fun_children)
     }
+
+    /// Spawns the pipeline with the output of the last command written into the file directly
+    ///
+    /// The output lands in the file handle without going through a pipe and the buffers of the
+    /// current process, no matter whether the last command is a program or a builtin command.
+    /// A `File` is taken instead of any `Stdio`, since the builtin commands write into it in the
+    /// current process. On Unix, other handles like a socket can be converted with
+    /// `File::from(OwnedFd::from(socket))`. The redirects of the last command, like `> file`,
+    /// still take precedence.
+    pub fn spawn_to(mut self, out: File) -> Result<CmdChildren> {
+        if let Some(cmds) = self.group_cmds.last_mut() {
+            cmds.stdout_sink = Some(out);
+        }
+        self.spawn(false)
+    }
+
+    /// Spawns the pipeline with stderr captured, while the output of the last command goes to
+    /// stdout of the current process directly
+    ///
+    /// The output is not captured, so the programs see the terminal as in the shell, and the
+    /// returned children are meant to be waited with `FunChildren::wait_stderr_captured()`.
+    pub fn spawn_stderr_captured(mut self) -> Result<FunChildren> {
+        if let Some(cmds) = self.group_cmds.last_mut() {
+            cmds.stdout_inherit = true;
+        }
+        self.spawn_with_output()
+    }
 }
 
 #[doc(hidden)]
@@ -161,22 +632,40 @@ pub struct Cmds {
     cmds: Vec<Option<Cmd>>,
     full_cmds: String,
     ignore_error: bool,
+    and_prev: bool,
+    or_prev: bool,
+    // overriding the global setting if set
+    pipefail: Option<bool>,
+    new_process_group: bool,
+    stdin_null: bool,
+    interactive: bool,
+    #[cfg(all(unix, feature = "pty"))]
+    use_pty: bool,
+    exported_vars: HashMap<String, Option<String>>,
+    prev_dir: PathBuf,
+    // stdout of the last command, set by `GroupCmds::spawn_to()`
+    stdout_sink: Option<File>,
+    // stdout of the last command is inherited, set by `GroupCmds::spawn_stderr_captured()`
+    stdout_inherit: bool,
 }
 
 impl Cmds {
+    /// Overrides the global pipefail setting for this pipeline
+    pub fn pipefail(mut self, enable: bool) -> Self {
+        self.pipefail = Some(enable);
+        self
+    }
+
     pub fn pipe(mut self, cmd: Cmd) -> Self {
         if !self.full_cmds.is_empty() {
             self.full_cmds += " | ";
         }
         self.full_cmds += &cmd.cmd_str();
-        let (ignore_error, cmd) = cmd.gen_command();
-        if ignore_error {
-            if self.cmds.is_empty() {
-                // first command in the pipe
-                self.ignore_error = true;
-            } else {
-                warn!("Builtin \"ignore\" command at wrong position");
-            }
+        let ignore_prefix = cmd.ignore_prefix_len() > 0;
+        let cmd = cmd.gen_command();
+        if ignore_prefix && self.cmds.is_empty() {
+            // first command in the pipe, ignoring the whole pipeline
+            self.ignore_error = true;
         }
         self.cmds.push(Some(cmd));
         self
@@ -187,8 +676,11 @@ impl Cmds {
     }
 
     fn spawn(&mut self, current_dir: &mut PathBuf, with_output: bool) -> Result<CmdChildren> {
+        if dry_run_enabled() {
+            return Ok(self.dry_run(current_dir));
+        }
         if debug_enabled() {
-            debug!("Running {} ...", self.get_full_cmds());
+            debug_trace(&format!("Running {} ...", self.get_full_cmds()));
         }
 
         // spawning all the sub-processes
@@ -197,19 +689,108 @@ impl Cmds {
         let mut prev_pipe_in = None;
         for (i, cmd_opt) in self.cmds.iter_mut().enumerate() {
             let mut cmd = cmd_opt.take().unwrap();
+            if i == 0 && self.stdin_null {
+                cmd.stdin_redirect = Some(CmdIn::Null);
+            }
+            // the output of the last command is not captured, so it can use the terminal
+            cmd.interactive = i == len - 1 && self.interactive && !with_output;
+            #[cfg(all(unix, feature = "pty"))]
+            {
+                cmd.use_pty = self.use_pty;
+            }
             if i != len - 1 {
                 // not the last, update redirects
                 let (pipe_reader, pipe_writer) = os_pipe::pipe()?;
-                cmd.setup_redirects(&mut prev_pipe_in, Some(pipe_writer), with_output)?;
+                let pipe_out = Some(CmdOut::Pipe(pipe_writer));
+                cmd.setup_redirects(&mut prev_pipe_in, pipe_out, with_output)?;
                 prev_pipe_in = Some(pipe_reader);
             } else {
-                cmd.setup_redirects(&mut prev_pipe_in, None, with_output)?;
+                let sink = self.stdout_sink.take().map(CmdOut::File);
+                let capture_output = with_output && !self.stdout_inherit;
+                cmd.setup_redirects(&mut prev_pipe_in, sink, capture_output)?;
             }
-            let child = cmd.spawn(current_dir, with_output);
+            let cmd_str = cmd.cmd_str();
+            let ignore_error = cmd.ignore_error;
+            let io_threads = std::mem::take(&mut cmd.io_threads);
+            // removed when dropped, if spawning fails
+            let atomic_files = std::mem::take(&mut cmd.atomic_files);
+            let started = Instant::now();
+            cmd.new_process_group = self.new_process_group;
+            let child = cmd
+                .spawn(
+                    current_dir,
+                    &mut self.prev_dir,
+                    &mut self.exported_vars,
+                    with_output,
+                )
+                .map(|child| {
+                    child
+                        .with_ignore_error(ignore_error)
+                        .with_io_threads(io_threads)
+                        .with_atomic_files(atomic_files)
+                        .with_started(started)
+                })
+                .map_err(|e| {
+                    CmdError::Spawn {
+                        cmd: cmd_str,
+                        source: e,
+                    }
+                    .into()
+                });
             children.push(child);
         }
 
-        Ok(CmdChildren::new(children, self.ignore_error))
+        Ok(CmdChildren::new(
+            children,
+            self.ignore_error,
+            self.pipefail.unwrap_or_else(pipefail_enabled),
+        )
+        .with_full_cmds(self.get_full_cmds()))
+    }
+
+    fn dry_run(&mut self, current_dir: &mut PathBuf) -> CmdChildren {
+        if current_dir.as_os_str().is_empty() {
+            info!("Dry running {} ...", self.get_full_cmds());
+        } else {
+            info!(
+                "Dry running {} in {} ...",
+                self.get_full_cmds(),
+                current_dir.display()
+            );
+        }
+
+        let mut children: Vec<Result<CmdChild>> = Vec::new();
+        for cmd_opt in self.cmds.iter_mut() {
+            let cmd = cmd_opt.take().unwrap();
+            if cmd.arg0() == CD_CMD && cmd.args.len() <= 2 {
+                // the directory might be created by previous commands, so no checking here
+                let dir = match cmd.args.get(1) {
+                    None => current_dir.join(home_dir()),
+                    Some(arg) if arg == "-" => self.prev_dir.clone(),
+                    Some(arg) => match cmd.expand_cd_path(arg, &self.exported_vars) {
+                        Ok(path) => current_dir.join(path),
+                        Err(_) => current_dir.join(arg),
+                    },
+                };
+                self.prev_dir = std::mem::replace(current_dir, dir);
+            } else if cmd.arg0() == EXPORT_CMD {
+                let _ = cmd.run_export_cmd(&mut self.exported_vars);
+            } else if cmd.arg0() == UNSET_CMD {
+                let _ = cmd.run_unset_cmd(&mut self.exported_vars);
+            }
+            children.push(Ok(CmdChild::new(
+                CmdChildHandle::SyncFn(0),
+                cmd.cmd_str(),
+                None,
+                None,
+            )));
+        }
+        CmdChildren::new(
+            children,
+            self.ignore_error,
+            self.pipefail.unwrap_or_else(pipefail_enabled),
+        )
+        .with_full_cmds(self.get_full_cmds())
     }
 
     fn spawn_with_output(&mut self, current_dir: &mut PathBuf) -> Result<FunChildren> {
@@ -229,42 +810,122 @@ impl Cmds {
 #[doc(hidden)]
 pub enum Redirect {
     FileToStdin(PathBuf),
+    StringToStdin(String),
+    HereDocToStdin(String),
+    BytesToStdin(Vec<u8>),
     StdoutToStderr,
     StderrToStdout,
     StdoutToFile(PathBuf, bool),
     StderrToFile(PathBuf, bool),
+    FdToFd(i32, i32),
+    FdToFile(i32, PathBuf, bool),
+    // `>|`, truncating the file even with noclobber
+    FdToFileForce(i32, PathBuf),
+    StdoutToBuffer(Arc<Mutex<Vec<u8>>>),
+    StderrToBuffer(Arc<Mutex<Vec<u8>>>),
 }
 impl fmt::Debug for Redirect {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Redirect::FileToStdin(path) => f.write_str(&format!("< {}", path.display())),
+            Redirect::FileToStdin(path) => f.write_str(&format!("< {}", quote_path(path))),
+            Redirect::StringToStdin(s) => f.write_str(&format!("<<< {:?}", s)),
+            Redirect::HereDocToStdin(s) => f.write_str(&format!("<< {:?}", s)),
+            Redirect::BytesToStdin(bytes) => f.write_str(&format!("< [{} bytes]", bytes.len())),
             Redirect::StdoutToStderr => f.write_str(">&2"),
             Redirect::StderrToStdout => f.write_str("2>&1"),
             Redirect::StdoutToFile(path, append) => {
                 if *append {
-                    f.write_str(&format!("1>> {}", path.display()))
+                    f.write_str(&format!("1>> {}", quote_path(path)))
                 } else {
-                    f.write_str(&format!("1> {}", path.display()))
+                    f.write_str(&format!("1> {}", quote_path(path)))
                 }
             }
             Redirect::StderrToFile(path, append) => {
                 if *append {
-                    f.write_str(&format!("2>> {}", path.display()))
+                    f.write_str(&format!("2>> {}", quote_path(path)))
+                } else {
+                    f.write_str(&format!("2> {}", quote_path(path)))
+                }
+            }
+            Redirect::FdToFd(fd1, fd2) => f.write_str(&format!("{}>&{}", fd1, fd2)),
+            Redirect::FdToFile(fd, path, append) => {
+                if *append {
+                    f.write_str(&format!("{}>> {}", fd, quote_path(path)))
                 } else {
-                    f.write_str(&format!("2> {}", path.display()))
+                    f.write_str(&format!("{}> {}", fd, quote_path(path)))
                 }
             }
+            Redirect::FdToFileForce(fd, path) => {
+                f.write_str(&format!("{}>| {}", fd, quote_path(path)))
+            }
+            Redirect::StdoutToBuffer(_) => f.write_str("1> [buffer]"),
+            Redirect::StderrToBuffer(_) => f.write_str("2> [buffer]"),
         }
     }
 }
 
+/// The resources limited by `Cmd::rlimit()`, only on Unix
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    /// CPU time in seconds
+    Cpu,
+    /// size of the files created, in bytes
+    FileSize,
+    /// size of the data segment, in bytes
+    Data,
+    /// size of the stack, in bytes
+    Stack,
+    /// size of the core dump files, in bytes
+    Core,
+    /// number of the open file descriptors
+    NoFile,
+    /// size of the virtual memory, in bytes
+    AddressSpace,
+}
+
+#[cfg(unix)]
+impl Resource {
+    fn as_raw(&self) -> RlimitResource {
+        match self {
+            Resource::Cpu => libc::RLIMIT_CPU,
+            Resource::FileSize => libc::RLIMIT_FSIZE,
+            Resource::Data => libc::RLIMIT_DATA,
+            Resource::Stack => libc::RLIMIT_STACK,
+            Resource::Core => libc::RLIMIT_CORE,
+            Resource::NoFile => libc::RLIMIT_NOFILE,
+            Resource::AddressSpace => libc::RLIMIT_AS,
+        }
+    }
+}
+
+// the type of the resource argument of setrlimit() differs between the platforms
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type RlimitResource = libc::__rlimit_resource_t;
+#[cfg(all(unix, not(all(target_os = "linux", target_env = "gnu"))))]
+type RlimitResource = libc::c_int;
+
 #[doc(hidden)]
 pub struct Cmd {
     // for parsing
     in_cmd_map: bool,
+    // built from the literal arguments, where a leading "ignore" is the program itself
+    literal: bool,
+    // the closure to run instead of a registered command
+    func: Option<FnMutFun>,
     args: Vec<OsString>,
+    // indexes of the arguments to expand as wildcards, with their glob patterns
+    glob_args: Vec<(usize, String)>,
     vars: HashMap<String, String>,
     redirects: Vec<Redirect>,
+    // started with "ignore" or set by `ignore_error()`, so its failure is ignored
+    ignore_error: bool,
+    new_process_group: bool,
+    // the last command not captured, running with the terminal of the current process
+    interactive: bool,
+    // directory for this command only, and whether it is the next argument of "with_dir"
+    current_dir: Option<PathBuf>,
+    current_dir_pending: bool,
     // for running
     #[cfg(target_os = "windows")]
     creation_flags: Option<u32>,
@@ -272,6 +933,20 @@ pub struct Cmd {
     stdin_redirect: Option<CmdIn>,
     stdout_redirect: Option<CmdOut>,
     stderr_redirect: Option<CmdOut>,
+    // output fds other than stdout and stderr, only for processes on Unix
+    extra_fds: Vec<(i32, CmdOut)>,
+    #[cfg(all(unix, feature = "pty"))]
+    use_pty: bool,
+    // set up in the forked child before running the program, only on Unix
+    #[cfg(unix)]
+    nice: Option<i32>,
+    #[cfg(unix)]
+    rlimits: Vec<(Resource, u64, u64)>,
+    // the source of stdin set by `stdin_from()`, and the threads copying it into the pipe or
+    // the output of a program into the buffers
+    stdin_reader: Option<Box<dyn Read + Send>>,
+    io_threads: Vec<thread::JoinHandle<()>>,
+    atomic_files: Vec<AtomicFile>,
     stdout_logging: Option<PipeReader>,
     stderr_logging: Option<PipeReader>,
 }
@@ -280,13 +955,31 @@ impl Default for Cmd {
     fn default() -> Self {
         Cmd {
             in_cmd_map: true,
+            literal: false,
+            func: None,
             args: vec![],
+            glob_args: vec![],
             vars: HashMap::new(),
             redirects: vec![],
+            ignore_error: false,
+            new_process_group: false,
+            interactive: false,
+            current_dir: None,
+            current_dir_pending: false,
             std_cmd: None,
             stdin_redirect: None,
             stdout_redirect: None,
             stderr_redirect: None,
+            extra_fds: vec![],
+            #[cfg(all(unix, feature = "pty"))]
+            use_pty: false,
+            #[cfg(unix)]
+            nice: None,
+            #[cfg(unix)]
+            rlimits: vec![],
+            stdin_reader: None,
+            io_threads: vec![],
+            atomic_files: vec![],
             stdout_logging: None,
             stderr_logging: None,
             #[cfg(target_os = "windows")]
@@ -296,11 +989,41 @@ impl Default for Cmd {
 }
 
 impl Cmd {
+    /// Creates a command running the closure, like a custom command without registering it
+    ///
+    /// The closure can capture the local state, and it runs on a thread in the pipeline the same
+    /// as the builtin commands, e.g. to transform the output of the previous command. It is
+    /// named `fn` in the logs and `CmdEnv::args()`, followed by the arguments added later.
+    pub fn from_fn<F>(func: F) -> Self
+    where
+        F: FnMut(&mut CmdEnv) -> CmdResult + Send + 'static,
+    {
+        let mut func = func;
+        Cmd {
+            func: Some(Box::new(move |env| func(env).map(|_| 0))),
+            args: vec![FN_CMD.into()],
+            ..Default::default()
+        }
+    }
+
     pub fn add_arg<O>(mut self, arg: O) -> Self
     where
         O: AsRef<OsStr>,
     {
         let arg_str = arg.as_ref().to_string_lossy().to_string();
+        if self.current_dir_pending {
+            self.current_dir_pending = false;
+            return self.set_current_dir(arg.as_ref());
+        }
+        if !self.args.iter().any(|cmd| *cmd != IGNORE_CMD) {
+            if arg_str == WITH_DIR_CMD && self.current_dir.is_none() {
+                self.current_dir_pending = true;
+                return self;
+            }
+            if arg_str == "--" && self.current_dir.is_some() {
+                return self;
+            }
+        }
         if arg_str != IGNORE_CMD && !self.args.iter().any(|cmd| *cmd != IGNORE_CMD) {
             let v: Vec<&str> = arg_str.split('=').collect();
             if v.len() == 2 && v[0].chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
@@ -313,6 +1036,33 @@ impl Cmd {
         self
     }
 
+    pub(crate) fn is_empty(&self) -> bool {
+        self.args.is_empty()
+    }
+
+    // the argument as it is, without the special prefixes of the macros like "VAR=value"
+    pub(crate) fn add_literal_arg<O: AsRef<OsStr>>(mut self, arg: O) -> Self {
+        self.literal = true;
+        if self.args.is_empty() {
+            self.in_cmd_map = CMD_MAP.lock().unwrap().contains_key(arg.as_ref());
+        }
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    pub fn add_glob(mut self, arg: CmdString) -> Self {
+        let len = self.args.len();
+        let pattern = match arg.pattern {
+            Some(ref pattern) => pattern.clone(),
+            None => glob::Pattern::escape(&arg.value.to_string_lossy()),
+        };
+        self = self.add_arg(arg);
+        if self.args.len() > len {
+            self.glob_args.push((len, pattern));
+        }
+        self
+    }
+
     pub fn add_args<I, O>(mut self, args: I) -> Self
     where
         I: IntoIterator<Item = O>,
@@ -324,6 +1074,74 @@ impl Cmd {
         self
     }
 
+    /// Sets the directory for this command only, relative to the current directory of the group
+    ///
+    /// It is the same as `with_dir DIR cmd ...` in the macros, and the following commands are
+    /// not affected, unlike `cd`.
+    pub fn set_current_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.current_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Feeds stdin from the reader, which is copied into a pipe by a thread as the command reads
+    ///
+    /// It generalizes the redirects from files or strings to any source, like a closure producing
+    /// the data lazily. When the command exits without reading all of it, the thread stops with
+    /// the broken pipe silently. The thread is joined when waiting for the command, so a reader
+    /// blocking forever also blocks the waiting.
+    pub fn stdin_from(mut self, reader: Box<dyn Read + Send>) -> Self {
+        self.stdin_reader = Some(reader);
+        self
+    }
+
+    /// Ignores the failure of this command only, even at the start of a pipeline
+    ///
+    /// Unlike the `ignore` prefix at the start of a pipeline, the failures of the other commands
+    /// in the same pipeline are still reported, e.g. with pipefail.
+    pub fn ignore_error(mut self) -> Self {
+        self.ignore_error = true;
+        self
+    }
+
+    /// Sets the niceness of the program, from -20 (the highest priority) to 19 (the lowest)
+    ///
+    /// Unlike the `nice` command, the value is not added to the current niceness. Raising the
+    /// priority usually needs privileges, otherwise spawning the program fails. Builtin and
+    /// custom commands are not affected, since they are not spawned as processes.
+    #[cfg(unix)]
+    pub fn nice(mut self, value: i32) -> Self {
+        self.nice = Some(value);
+        self
+    }
+
+    /// Sets the soft and hard limits of the resource for the program, same as `ulimit`
+    ///
+    /// Pass `u64::MAX` for no limit. Same as `nice()`, it only applies to the programs.
+    #[cfg(unix)]
+    pub fn rlimit(mut self, resource: Resource, soft: u64, hard: u64) -> Self {
+        self.rlimits.push((resource, soft, hard));
+        self
+    }
+
+    /// Feeds stdin from the bytes in memory, read by a builtin command directly
+    pub fn stdin_buffer<T: Into<Vec<u8>>>(self, content: T) -> Self {
+        self.add_redirect(Redirect::BytesToStdin(content.into()))
+    }
+
+    /// Appends stdout to the shared buffer in memory instead of a file or a pipe
+    ///
+    /// It helps to test the commands without touching the filesystem, as the output can be
+    /// inspected after waiting for the command. Builtin commands write into the buffer directly,
+    /// while the output of a program is copied into it by a thread.
+    pub fn stdout_buffer(self, buffer: Arc<Mutex<Vec<u8>>>) -> Self {
+        self.add_redirect(Redirect::StdoutToBuffer(buffer))
+    }
+
+    /// Appends stderr to the shared buffer in memory, same as `stdout_buffer()`
+    pub fn stderr_buffer(self, buffer: Arc<Mutex<Vec<u8>>>) -> Self {
+        self.add_redirect(Redirect::StderrToBuffer(buffer))
+    }
+
     pub fn add_redirect(mut self, redirect: Redirect) -> Self {
         self.redirects.push(redirect);
         self
@@ -336,41 +1154,63 @@ impl Cmd {
     }
 
     fn arg0(&self) -> OsString {
-        let mut args = self.args.iter().skip_while(|cmd| *cmd == IGNORE_CMD);
+        let mut args = self.args.iter().skip(self.ignore_prefix_len());
         if let Some(arg) = args.next() {
             return arg.into();
         }
         "".into()
     }
 
+    // the command line for logging, with the arguments quoted the same as in shell
     fn cmd_str(&self) -> String {
-        let mut ret = format!("{:?}", self.args);
-        let mut extra = String::new();
-        if !self.vars.is_empty() {
-            extra += &format!("{:?}", self.vars);
-        }
-        if !self.redirects.is_empty() {
-            if !extra.is_empty() {
-                extra += ", ";
-            }
-            extra += &format!("{:?}", self.redirects);
-        }
-        if !extra.is_empty() {
-            ret += &format!("({})", extra);
+        let mut vars: Vec<String> = self
+            .vars
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, quote_arg(v.as_ref())))
+            .collect();
+        vars.sort();
+        let args = self.args.iter().map(|arg| quote_arg(arg));
+        let redirects = self
+            .redirects
+            .iter()
+            .map(|redirect| format!("{:?}", redirect));
+        vars.into_iter()
+            .chain(args)
+            .chain(redirects)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    // the leading "ignore" of the macros, which is not passed to the command
+    fn ignore_prefix_len(&self) -> usize {
+        if self.literal {
+            return 0;
         }
-        ret
+        self.args
+            .iter()
+            .take_while(|arg| *arg == IGNORE_CMD)
+            .count()
     }
 
-    fn gen_command(mut self) -> (bool, Self) {
+    fn gen_command(mut self) -> Self {
+        self.ignore_error |= self.ignore_prefix_len() > 0;
+        self.gen_std_cmd();
+        self
+    }
+
+    fn gen_std_cmd(&mut self) {
         let args: Vec<OsString> = self
             .args
             .iter()
-            .skip_while(|cmd| *cmd == IGNORE_CMD)
+            .skip(self.ignore_prefix_len())
             .map(|s| s.into())
             .collect();
         if !self.in_cmd_map {
             let mut cmd = Command::new(&args[0]);
             cmd.args(&args[1..]);
+            if !env_inherit_enabled() {
+                cmd.env_clear();
+            }
             for (k, v) in self.vars.iter() {
                 cmd.env(k, v);
             }
@@ -380,30 +1220,128 @@ impl Cmd {
             }
             self.std_cmd = Some(cmd);
         }
-        (self.args.len() > args.len(), self)
     }
 
-    fn spawn(mut self, current_dir: &mut PathBuf, with_output: bool) -> Result<CmdChild> {
+    // expand wildcards like bash, relative to the current directory; patterns without any match
+    // are kept as they are
+    fn expand_globs(&mut self, current_dir: &Path) {
+        if self.glob_args.is_empty() {
+            return;
+        }
+        let options = glob::MatchOptions {
+            require_literal_leading_dot: true,
+            ..Default::default()
+        };
+        let mut args = vec![];
+        let glob_args = std::mem::take(&mut self.glob_args);
+        for (i, arg) in std::mem::take(&mut self.args).into_iter().enumerate() {
+            let pattern = match glob_args.iter().find(|(j, _)| *j == i) {
+                Some((_, pattern)) => pattern,
+                None => {
+                    args.push(arg);
+                    continue;
+                }
+            };
+            let full_pattern = if current_dir.as_os_str().is_empty() {
+                pattern.to_string()
+            } else {
+                let dir = glob::Pattern::escape(&current_dir.to_string_lossy());
+                Path::new(&dir).join(pattern).to_string_lossy().to_string()
+            };
+            let matched: Vec<OsString> = match glob::glob_with(&full_pattern, options) {
+                Ok(paths) => paths
+                    .filter_map(|path| path.ok())
+                    .map(|path| {
+                        path.strip_prefix(current_dir)
+                            .map(|path| path.to_path_buf())
+                            .unwrap_or(path)
+                            .into_os_string()
+                    })
+                    .collect(),
+                Err(_) => vec![],
+            };
+            if matched.is_empty() {
+                args.push(arg);
+            } else {
+                args.extend(matched);
+            }
+        }
+        self.args = args;
+        self.gen_std_cmd();
+    }
+
+    fn spawn(
+        mut self,
+        current_dir: &mut PathBuf,
+        prev_dir: &mut PathBuf,
+        exported_vars: &mut HashMap<String, Option<String>>,
+        with_output: bool,
+    ) -> Result<CmdChild> {
+        if let Some(dir) = self.current_dir.take() {
+            // a local directory, so the group's current directory is kept for the following ones
+            let mut dir = current_dir.join(dir);
+            return self.spawn(&mut dir, prev_dir, exported_vars, with_output);
+        }
+        self.expand_globs(current_dir);
         let arg0 = self.arg0();
-        if arg0 == CD_CMD {
-            let child = self.run_cd_cmd(current_dir)?;
-            Ok(CmdChild::new(
-                CmdChildHandle::SyncFn(child),
+        if arg0 == CD_CMD || arg0 == EXPORT_CMD || arg0 == READ_CMD || arg0 == UNSET_CMD {
+            if arg0 == CD_CMD {
+                self.run_cd_cmd(current_dir, prev_dir, exported_vars)?;
+            } else if arg0 == EXPORT_CMD {
+                self.run_export_cmd(exported_vars)?;
+            } else if arg0 == READ_CMD {
+                self.run_read_cmd(exported_vars)?;
+            } else {
+                self.run_unset_cmd(exported_vars)?;
+            }
+            return Ok(CmdChild::new(
+                CmdChildHandle::SyncFn(0),
                 self.cmd_str(),
                 self.stdout_logging,
                 self.stderr_logging,
-            ))
-        } else if self.in_cmd_map {
+            ));
+        }
+
+        // the command's own variables take precedence over the exported ones
+        let mut removed_vars = vec![];
+        for (k, v) in exported_vars.iter() {
+            if self.vars.contains_key(k) {
+                continue;
+            }
+            match v {
+                Some(v) => {
+                    if let Some(cmd) = self.std_cmd.as_mut() {
+                        cmd.env(k, v);
+                    }
+                    self.vars.insert(k.clone(), v.clone());
+                }
+                None => {
+                    if let Some(cmd) = self.std_cmd.as_mut() {
+                        cmd.env_remove(k);
+                    }
+                    removed_vars.push(k.clone());
+                }
+            }
+        }
+        if self.in_cmd_map {
+            if let Some((fd, _)) = self.extra_fds.first() {
+                return Err(Error::other(format!(
+                    "redirecting fd {} is not supported for builtin or custom commands",
+                    fd
+                )));
+            }
             let cmd_str = self.cmd_str();
             let pipe_out = self.stdout_logging.is_none();
+            let ignore_prefix_len = self.ignore_prefix_len();
             let mut env = CmdEnv {
                 args: self
                     .args
                     .into_iter()
-                    .skip_while(|cmd| *cmd == IGNORE_CMD)
+                    .skip(ignore_prefix_len)
                     .map(|s| s.to_string_lossy().to_string())
                     .collect(),
                 vars: self.vars,
+                removed_vars,
                 current_dir: if current_dir.as_os_str().is_empty() {
                     std::env::current_dir()?
                 } else {
@@ -424,28 +1362,45 @@ impl Cmd {
                 } else {
                     CmdOut::Pipe(os_pipe::dup_stderr()?)
                 },
+                killed: Arc::new(AtomicBool::new(false)),
             };
 
-            let internal_cmd = CMD_MAP.lock().unwrap()[&arg0];
-            if pipe_out || with_output {
+            let hook = *BUILTIN_HOOK.lock().unwrap();
+            if let Some(hook) = hook {
+                hook(&mut env);
+            }
+
+            let mut internal_cmd: FnMutFun = match self.func.take() {
+                Some(func) => func,
+                None => {
+                    let func = CMD_MAP.lock().unwrap()[&arg0].clone();
+                    Box::new(move |env| func(env))
+                }
+            };
+            if (pipe_out || with_output) && !self.interactive {
+                let killed = env.killed.clone();
                 let handle = thread::Builder::new().spawn(move || internal_cmd(&mut env))?;
                 Ok(CmdChild::new(
-                    CmdChildHandle::Thread(handle),
+                    CmdChildHandle::Thread(handle, killed),
                     cmd_str,
                     self.stdout_logging,
                     self.stderr_logging,
                 ))
             } else {
-                let child = internal_cmd(&mut env)?;
+                let code = internal_cmd(&mut env)?;
                 Ok(CmdChild::new(
-                    CmdChildHandle::SyncFn(child),
+                    CmdChildHandle::SyncFn(code),
                     cmd_str,
                     self.stdout_logging,
                     self.stderr_logging,
                 ))
             }
         } else {
+            if cfg!(unix) && path_check_enabled() {
+                self.check_program(current_dir)?;
+            }
             let mut cmd = self.std_cmd.take().unwrap();
+            self.pipe_buffers()?;
 
             // setup current_dir
             if !current_dir.as_os_str().is_empty() {
@@ -467,6 +1422,27 @@ impl Cmd {
                 cmd.stderr(redirect_err);
             }
 
+            // update other fds
+            #[cfg(unix)]
+            if !self.extra_fds.is_empty() {
+                self.setup_extra_fds(&mut cmd)?;
+            }
+
+            #[cfg(unix)]
+            if self.new_process_group {
+                cmd.process_group(0);
+            }
+
+            #[cfg(unix)]
+            if self.nice.is_some() || !self.rlimits.is_empty() {
+                self.setup_limits(&mut cmd);
+            }
+
+            let hook = *COMMAND_HOOK.lock().unwrap();
+            if let Some(hook) = hook {
+                hook(&mut cmd);
+            }
+
             // spawning process
             let child = cmd.spawn()?;
             Ok(CmdChild::new(
@@ -474,108 +1450,668 @@ impl Cmd {
                 self.cmd_str(),
                 self.stdout_logging,
                 self.stderr_logging,
-            ))
+            )
+            .with_process_group(self.new_process_group)
+            .with_io_threads(self.io_threads))
+        }
+    }
+
+    // the buffers are only in the current process, so the program gets the pipes instead, with
+    // the threads copying the content from or into the buffers
+    fn pipe_buffers(&mut self) -> CmdResult {
+        if let Some(CmdIn::Buffer(content)) = &self.stdin_redirect {
+            let mut content = content.clone();
+            let (pipe_reader, mut pipe_writer) = os_pipe::pipe()?;
+            thread::Builder::new().spawn(move || {
+                // the reading side could exit early without consuming it
+                let _ = std::io::copy(&mut content, &mut pipe_writer);
+            })?;
+            self.stdin_redirect = Some(CmdIn::Pipe(pipe_reader));
+        }
+        let io_threads = &mut self.io_threads;
+        let mut pipe_out = |out: &mut CmdOut| -> CmdResult {
+            if let CmdOut::Buffer(buffer) = out {
+                let mut buffer = CmdOut::Buffer(buffer.clone());
+                let (mut pipe_reader, pipe_writer) = os_pipe::pipe()?;
+                io_threads.push(thread::Builder::new().spawn(move || {
+                    let _ = std::io::copy(&mut pipe_reader, &mut buffer);
+                })?);
+                *out = CmdOut::Pipe(pipe_writer);
+            }
+            Ok(())
+        };
+        let outs = self
+            .stdout_redirect
+            .iter_mut()
+            .chain(self.stderr_redirect.iter_mut());
+        for out in outs.chain(self.extra_fds.iter_mut().map(|(_, out)| out)) {
+            pipe_out(out)?;
+        }
+        Ok(())
+    }
+
+    // the other fds are set up in the forked child, just before running the program
+    #[cfg(unix)]
+    fn setup_extra_fds(&mut self, cmd: &mut Command) -> CmdResult {
+        let mut fds = vec![];
+        for (fd, out) in self.extra_fds.iter_mut() {
+            if let CmdOut::Null = out {
+                *out = CmdOut::File(Self::open_file(Path::new("/dev/null"), false, false)?);
+            }
+            let src = match out {
+                CmdOut::File(file) => file.as_raw_fd(),
+                CmdOut::Pipe(pipe) => pipe.as_raw_fd(),
+                CmdOut::Null | CmdOut::Buffer(_) => unreachable!(),
+            };
+            fds.push((*fd, src));
+        }
+        // move the sources above all the targets first, so none of them gets overwritten
+        let min_tmp_fd = fds.iter().map(|(fd, _)| *fd).max().unwrap_or(0) + 1;
+        let mut tmp_fds = vec![0; fds.len()];
+        unsafe {
+            cmd.pre_exec(move || {
+                for ((_, src), tmp) in fds.iter().zip(tmp_fds.iter_mut()) {
+                    *tmp = libc::fcntl(*src, libc::F_DUPFD_CLOEXEC, min_tmp_fd);
+                    if *tmp < 0 {
+                        return Err(Error::last_os_error());
+                    }
+                }
+                for ((fd, _), tmp) in fds.iter().zip(tmp_fds.iter()) {
+                    if libc::dup2(*tmp, *fd) < 0 {
+                        return Err(Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    // same as the other fds, the limits are set in the forked child, only affecting the program
+    #[cfg(unix)]
+    fn setup_limits(&mut self, cmd: &mut Command) {
+        let nice = self.nice;
+        let rlimits: Vec<_> = self
+            .rlimits
+            .iter()
+            .map(|(resource, soft, hard)| {
+                let limit = |value: u64| {
+                    if value == u64::MAX {
+                        libc::RLIM_INFINITY
+                    } else {
+                        value as libc::rlim_t
+                    }
+                };
+                let rlimit = libc::rlimit {
+                    rlim_cur: limit(*soft),
+                    rlim_max: limit(*hard),
+                };
+                (*resource, rlimit)
+            })
+            .collect();
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(nice) = nice {
+                    if libc::setpriority(libc::PRIO_PROCESS, 0, nice) < 0 {
+                        return Err(Error::last_os_error());
+                    }
+                }
+                for (resource, rlimit) in rlimits.iter() {
+                    if libc::setrlimit(resource.as_raw(), rlimit) < 0 {
+                        return Err(Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    fn run_export_cmd(&self, exported_vars: &mut HashMap<String, Option<String>>) -> CmdResult {
+        let args = &self.args[1..];
+        if args.is_empty() {
+            return Err(Error::other("export: missing variable"));
+        }
+        for arg in args {
+            let arg = arg.to_string_lossy();
+            match arg.split_once('=') {
+                Some((k, v))
+                    if !k.is_empty()
+                        && k.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+                {
+                    exported_vars.insert(k.into(), Some(v.into()));
+                }
+                _ => {
+                    let err_msg = format!("export: not a valid assignment: {}", arg);
+                    return Err(Error::other(err_msg));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn run_unset_cmd(&self, exported_vars: &mut HashMap<String, Option<String>>) -> CmdResult {
+        let args = &self.args[1..];
+        if args.is_empty() {
+            return Err(Error::other("unset: missing variable"));
+        }
+        for arg in args {
+            let arg = arg.to_string_lossy();
+            if arg.is_empty() || !arg.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(Error::other(format!("unset: not a valid name: {}", arg)));
+            }
+            // kept as None, so the variable inherited from the current process is removed too
+            exported_vars.insert(arg.into(), None);
+        }
+        Ok(())
+    }
+
+    fn run_read_cmd(&mut self, exported_vars: &mut HashMap<String, Option<String>>) -> CmdResult {
+        let names: Vec<String> = self.args[1..]
+            .iter()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+        if names.is_empty() {
+            return Err(Error::other("read: missing variable"));
         }
+        if let Some(name) = names.iter().find(|name| {
+            name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }) {
+            return Err(Error::other(format!("read: not a valid name: {}", name)));
+        }
+
+        // read byte by byte, so the input after the first line is left for the others
+        let mut stdin = match self.stdin_redirect.take() {
+            Some(redirect_in) => redirect_in,
+            None => CmdIn::Pipe(os_pipe::dup_stdin()?),
+        };
+        let mut buf = vec![];
+        let mut byte = [0; 1];
+        while stdin.read(&mut byte)? == 1 && byte[0] != b'\n' {
+            buf.push(byte[0]);
+        }
+        if buf.is_empty() && byte[0] != b'\n' {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "read: end of input"));
+        }
+
+        // like bash, the last variable gets the remaining words and the missing ones are empty
+        let line = String::from_utf8_lossy(&buf);
+        let mut rest = line.trim();
+        for (i, name) in names.iter().enumerate() {
+            let value = if i == names.len() - 1 {
+                std::mem::take(&mut rest)
+            } else {
+                let (word, remaining) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                rest = remaining.trim_start();
+                word
+            };
+            exported_vars.insert(name.clone(), Some(value.to_owned()));
+        }
+        Ok(())
     }
 
-    fn run_cd_cmd(&self, current_dir: &mut PathBuf) -> CmdResult {
-        if self.args.len() == 1 {
-            return Err(Error::new(ErrorKind::Other, "cd: missing directory"));
-        } else if self.args.len() > 2 {
+    fn run_cd_cmd(
+        &mut self,
+        current_dir: &mut PathBuf,
+        prev_dir: &mut PathBuf,
+        exported_vars: &HashMap<String, Option<String>>,
+    ) -> CmdResult {
+        if self.args.len() > 2 {
             let err_msg = format!("cd: too many arguments: {}", self.cmd_str());
-            return Err(Error::new(ErrorKind::Other, err_msg));
+            return Err(Error::other(err_msg));
         }
 
-        let dir = current_dir.join(&self.args[1]);
+        let is_prev = self.args.get(1).is_some_and(|arg| arg == "-");
+        let dir = match self.args.get(1) {
+            None => current_dir.join(home_dir()),
+            Some(_) if is_prev => {
+                if prev_dir.as_os_str().is_empty() {
+                    return Err(Error::other("cd -: no previous directory"));
+                }
+                prev_dir.clone()
+            }
+            Some(arg) => current_dir.join(self.expand_cd_path(arg, exported_vars)?),
+        };
+        // show the fully resolved path in the errors
+        let resolved = || match std::env::current_dir() {
+            Ok(cwd) if dir.is_relative() => cwd.join(&dir),
+            _ => dir.clone(),
+        };
         if !dir.is_dir() {
-            let err_msg = format!("cd {}: No such file or directory", dir.display());
-            return Err(Error::new(ErrorKind::Other, err_msg));
+            let err_msg = format!("cd {}: No such file or directory", resolved().display());
+            return Err(Error::other(err_msg));
         }
 
-        dir.access(AccessMode::EXECUTE)?;
+        dir.access(AccessMode::EXECUTE)
+            .map_err(|e| Error::new(e.kind(), format!("cd {}: {}", resolved().display(), e)))?;
+        *prev_dir = if current_dir.as_os_str().is_empty() {
+            std::env::current_dir()?
+        } else {
+            current_dir.clone()
+        };
+        // same as bash, "cd -" prints the new directory
+        if is_prev {
+            let line = format!("{}\n", dir.display());
+            match self.stdout_redirect.as_mut() {
+                Some(out) => out.write_all(line.as_bytes())?,
+                None => std::io::stdout().write_all(line.as_bytes())?,
+            }
+        }
         *current_dir = dir;
         Ok(())
     }
 
+    // expand a leading "~" to the home directory, and "$VAR" or "${VAR}" to the variables set by
+    // this command, the exported ones or the environment, for the paths only known at runtime
+    fn expand_cd_path(
+        &self,
+        arg: &OsStr,
+        exported_vars: &HashMap<String, Option<String>>,
+    ) -> Result<PathBuf> {
+        let arg = match arg.to_str() {
+            Some(arg) => arg,
+            None => return Ok(PathBuf::from(arg)),
+        };
+        let mut path = String::new();
+        let mut rest = arg;
+        if rest == "~" || rest.starts_with("~/") {
+            path.push_str(&home_dir().to_string_lossy());
+            rest = &rest[1..];
+        }
+        while let Some(i) = rest.find('$') {
+            path.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+            let (name, next) = match rest.strip_prefix('{') {
+                Some(braced) => match braced.find('}') {
+                    Some(end) => (&braced[..end], &braced[end + 1..]),
+                    None => ("", rest),
+                },
+                None => {
+                    let end = rest
+                        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                        .unwrap_or(rest.len());
+                    (&rest[..end], &rest[end..])
+                }
+            };
+            if name.is_empty() {
+                // not a variable, like "a$" or "$/"
+                path.push('$');
+                continue;
+            }
+            // same as for the programs, "unset" hides the variable of the environment
+            let value = match (self.vars.get(name), exported_vars.get(name)) {
+                (Some(value), _) => Some(value.clone()),
+                (None, Some(value)) => value.clone(),
+                (None, None) => std::env::var(name).ok(),
+            };
+            match value {
+                Some(value) => path.push_str(&value),
+                None => {
+                    let err_msg = format!("cd {}: {}: variable not set", arg, name);
+                    return Err(Error::other(err_msg));
+                }
+            }
+            rest = next;
+        }
+        path.push_str(rest);
+        Ok(PathBuf::from(path))
+    }
+
+    // look up the program like the shell, to report a missing one with what was searched
+    fn check_program(&self, current_dir: &Path) -> CmdResult {
+        let is_program = |path: &Path| path.is_file() && path.access(AccessMode::EXECUTE).is_ok();
+        let arg0 = self.arg0();
+        let program = Path::new(&arg0);
+        let (found, searched) = if program.components().count() > 1 {
+            (is_program(&current_dir.join(program)), "")
+        } else {
+            // without env inheritance the child has no PATH, and spawning
+            // falls back to the libc default search path instead
+            let paths = match self.vars.get("PATH") {
+                Some(paths) => OsString::from(paths),
+                None if env_inherit_enabled() => std::env::var_os("PATH").unwrap_or_default(),
+                None => OsString::from(DEFAULT_PATH),
+            };
+            let found = std::env::split_paths(&paths).any(|dir| is_program(&dir.join(program)));
+            (found, " (searched PATH)")
+        };
+        if found {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorKind::NotFound,
+                format!("command not found: {}{}", program.display(), searched),
+            ))
+        }
+    }
+
     fn open_file(path: &Path, read_only: bool, append: bool) -> Result<File> {
-        if read_only {
+        let ret = if read_only {
             OpenOptions::new().read(true).open(path)
         } else {
-            OpenOptions::new()
-                .create(true)
-                .truncate(!append)
-                .write(true)
-                .append(append)
-                .open(path)
+            Self::create_parent_dir(path).and_then(|_| {
+                OpenOptions::new()
+                    .create(true)
+                    .truncate(!append)
+                    .write(true)
+                    .append(append)
+                    .open(path)
+            })
+        };
+        ret.map_err(|e| {
+            let mode = match (read_only, append) {
+                (true, _) => "reading",
+                (false, true) => "writing (append)",
+                (false, false) => "writing (truncate)",
+            };
+            let err_msg = format!("failed to open {} for {}: {}", quote_path(path), mode, e);
+            Error::new(e.kind(), err_msg)
+        })
+    }
+
+    fn create_parent_dir(path: &Path) -> Result<()> {
+        match path.parent() {
+            Some(dir) if redirect_mkdir_enabled() && !dir.as_os_str().is_empty() => {
+                fs::create_dir_all(dir)
+            }
+            _ => Ok(()),
         }
     }
 
+    // with noclobber, plain ">" refuses to overwrite an existing regular file, same as bash, while
+    // `force` is set for ">|"
+    fn open_out_file(&mut self, path: &Path, append: bool, force: bool) -> Result<File> {
+        if !append && !force && noclobber_enabled() && path.is_file() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!(
+                    "cannot overwrite existing file {} with noclobber, use \">|\" to force",
+                    quote_path(path)
+                ),
+            ));
+        }
+        // devices like "/dev/null" or the fifos are never replaced
+        let special = path.exists() && !path.is_file();
+        if append || special || !atomic_redirect_enabled() {
+            return Self::open_file(path, false, append);
+        }
+        let (atomic_file, file) = Self::create_parent_dir(path)
+            .and_then(|_| AtomicFile::create(path))
+            .map_err(|e| {
+                let err_msg = format!(
+                    "failed to open {} for writing (atomic): {}",
+                    quote_path(path),
+                    e
+                );
+                Error::new(e.kind(), err_msg)
+            })?;
+        self.atomic_files.push(atomic_file);
+        Ok(file)
+    }
+
+    // "/dev/null" is accepted on all platforms, as well as "NUL" on Windows
+    fn is_null_device(path: &Path) -> bool {
+        if path == Path::new("/dev/null") {
+            return true;
+        }
+        #[cfg(windows)]
+        {
+            if path.as_os_str().eq_ignore_ascii_case("NUL") {
+                return true;
+            }
+        }
+        false
+    }
+
     fn setup_redirects(
         &mut self,
         pipe_in: &mut Option<PipeReader>,
-        pipe_out: Option<PipeWriter>,
+        pipe_out: Option<CmdOut>,
         with_output: bool,
     ) -> CmdResult {
         // set up stdin pipe
         if let Some(pipe) = pipe_in.take() {
             self.stdin_redirect = Some(CmdIn::Pipe(pipe));
         }
-        // set up stdout pipe
-        if let Some(pipe) = pipe_out {
-            self.stdout_redirect = Some(CmdOut::Pipe(pipe));
+        if let Some(mut reader) = self.stdin_reader.take() {
+            let (pipe_reader, mut pipe_writer) = os_pipe::pipe()?;
+            self.io_threads.push(thread::Builder::new().spawn(move || {
+                // the reading side could exit early without consuming it
+                let _ = std::io::copy(&mut reader, &mut pipe_writer);
+            })?);
+            self.stdin_redirect = Some(CmdIn::Pipe(pipe_reader));
+        }
+        // set up stdout pipe, or the file given by `GroupCmds::spawn_to()`
+        if let Some(out) = pipe_out {
+            self.stdout_redirect = Some(out);
         } else if with_output {
-            let (pipe_reader, pipe_writer) = os_pipe::pipe()?;
-            self.stdout_redirect = Some(CmdOut::Pipe(pipe_writer));
+            let (pipe_reader, pipe_writer) = self.output_pipe()?;
+            self.stdout_redirect = Some(pipe_writer);
             self.stdout_logging = Some(pipe_reader);
         }
-        // set up stderr pipe
+        // set up stderr pipe, unless it is inherited from the current process
+        if !self.interactive {
+            let (pipe_reader, pipe_writer) = os_pipe::pipe()?;
+            self.stderr_redirect = Some(CmdOut::Pipe(pipe_writer));
+            self.stderr_logging = Some(pipe_reader);
+        }
+
+        // same as bash, redirects are applied from left to right and `2>&1`/`1>&2` duplicate the
+        // current target, so `> file 2>&1` sends both streams to the file, while `2>&1 > file`
+        // sends stderr to the previous stdout and only stdout to the file
+        let redirects = std::mem::take(&mut self.redirects);
+        let ret = redirects
+            .iter()
+            .try_for_each(|redirect| self.setup_redirect(redirect));
+        self.redirects = redirects;
+        ret
+    }
+
+    // the pipe capturing the output, or a pseudo-terminal with `GroupCmds::use_pty()`
+    fn output_pipe(&self) -> Result<(PipeReader, CmdOut)> {
+        #[cfg(all(unix, feature = "pty"))]
+        if self.use_pty {
+            let (pipe_reader, terminal) = crate::pty::open_pty()?;
+            return Ok((pipe_reader, CmdOut::File(terminal)));
+        }
         let (pipe_reader, pipe_writer) = os_pipe::pipe()?;
-        self.stderr_redirect = Some(CmdOut::Pipe(pipe_writer));
-        self.stderr_logging = Some(pipe_reader);
-
-        for redirect in self.redirects.iter() {
-            match redirect {
-                Redirect::FileToStdin(path) => {
-                    self.stdin_redirect = Some(if path == Path::new("/dev/null") {
-                        CmdIn::Null
-                    } else {
-                        CmdIn::File(Self::open_file(path, true, false)?)
-                    });
-                }
-                Redirect::StdoutToStderr => {
-                    if let Some(ref redirect) = self.stderr_redirect {
-                        self.stdout_redirect = Some(redirect.try_clone()?);
-                    } else {
-                        self.stdout_redirect = Some(CmdOut::Pipe(os_pipe::dup_stderr()?));
-                    }
-                }
-                Redirect::StderrToStdout => {
-                    if let Some(ref redirect) = self.stdout_redirect {
-                        self.stderr_redirect = Some(redirect.try_clone()?);
-                    } else {
-                        self.stderr_redirect = Some(CmdOut::Pipe(os_pipe::dup_stdout()?));
-                    }
-                }
-                Redirect::StdoutToFile(path, append) => {
-                    self.stdout_redirect = Some(if path == Path::new("/dev/null") {
-                        CmdOut::Null
-                    } else {
-                        CmdOut::File(Self::open_file(path, false, *append)?)
-                    });
+        Ok((pipe_reader, CmdOut::Pipe(pipe_writer)))
+    }
+
+    fn setup_redirect(&mut self, redirect: &Redirect) -> CmdResult {
+        match redirect {
+            Redirect::FileToStdin(path) => {
+                self.stdin_redirect = Some(if Self::is_null_device(path) {
+                    CmdIn::Null
+                } else {
+                    CmdIn::File(Self::open_file(path, true, false)?)
+                });
+            }
+            Redirect::StringToStdin(s) => {
+                // same as bash, a trailing newline is appended to the here-string
+                let content = format!("{}\n", s).into_bytes();
+                self.stdin_redirect = Some(CmdIn::Buffer(Cursor::new(content)));
+            }
+            Redirect::HereDocToStdin(s) => {
+                let content = s.clone().into_bytes();
+                self.stdin_redirect = Some(CmdIn::Buffer(Cursor::new(content)));
+            }
+            Redirect::BytesToStdin(bytes) => {
+                self.stdin_redirect = Some(CmdIn::Buffer(Cursor::new(bytes.clone())));
+            }
+            Redirect::StdoutToStderr => {
+                if let Some(ref redirect) = self.stderr_redirect {
+                    self.stdout_redirect = Some(redirect.try_clone()?);
+                } else {
+                    self.stdout_redirect = Some(CmdOut::Pipe(os_pipe::dup_stderr()?));
                 }
-                Redirect::StderrToFile(path, append) => {
-                    self.stderr_redirect = Some(if path == Path::new("/dev/null") {
-                        CmdOut::Null
-                    } else {
-                        CmdOut::File(Self::open_file(path, false, *append)?)
-                    });
+            }
+            Redirect::StderrToStdout => {
+                if let Some(ref redirect) = self.stdout_redirect {
+                    self.stderr_redirect = Some(redirect.try_clone()?);
+                } else {
+                    self.stderr_redirect = Some(CmdOut::Pipe(os_pipe::dup_stdout()?));
                 }
             }
+            Redirect::StdoutToFile(path, append) => {
+                self.stdout_redirect = Some(if Self::is_null_device(path) {
+                    CmdOut::Null
+                } else {
+                    CmdOut::File(self.open_out_file(path, *append, false)?)
+                });
+            }
+            Redirect::StderrToFile(path, append) => {
+                self.stderr_redirect = Some(if Self::is_null_device(path) {
+                    CmdOut::Null
+                } else {
+                    CmdOut::File(self.open_out_file(path, *append, false)?)
+                });
+            }
+            Redirect::FdToFd(fd1, fd2) => {
+                let out = self.out_fd(*fd2)?;
+                self.set_out_fd(*fd1, out)?;
+            }
+            Redirect::FdToFile(fd, path, append) => {
+                let out = CmdOut::File(self.open_out_file(path, *append, false)?);
+                self.set_out_fd(*fd, out)?;
+            }
+            Redirect::FdToFileForce(fd, path) => {
+                let out = if Self::is_null_device(path) {
+                    CmdOut::Null
+                } else {
+                    CmdOut::File(self.open_out_file(path, false, true)?)
+                };
+                self.set_out_fd(*fd, out)?;
+            }
+            Redirect::StdoutToBuffer(buffer) => {
+                self.stdout_redirect = Some(CmdOut::Buffer(buffer.clone()));
+            }
+            Redirect::StderrToBuffer(buffer) => {
+                self.stderr_redirect = Some(CmdOut::Buffer(buffer.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    // a copy of the current output target of the fd
+    fn out_fd(&self, fd: i32) -> Result<CmdOut> {
+        let redirect = match fd {
+            1 => self.stdout_redirect.as_ref(),
+            2 => self.stderr_redirect.as_ref(),
+            _ => self
+                .extra_fds
+                .iter()
+                .find(|(extra_fd, _)| *extra_fd == fd)
+                .map(|(_, out)| out),
+        };
+        match (redirect, fd) {
+            (Some(redirect), _) => redirect.try_clone(),
+            (None, 1) => Ok(CmdOut::Pipe(os_pipe::dup_stdout()?)),
+            (None, 2) => Ok(CmdOut::Pipe(os_pipe::dup_stderr()?)),
+            (None, _) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{}: bad file descriptor", fd),
+            )),
+        }
+    }
+
+    fn set_out_fd(&mut self, fd: i32, out: CmdOut) -> CmdResult {
+        match fd {
+            1 => self.stdout_redirect = Some(out),
+            2 => self.stderr_redirect = Some(out),
+            _ if cfg!(unix) => {
+                self.extra_fds.retain(|(extra_fd, _)| *extra_fd != fd);
+                self.extra_fds.push((fd, out));
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("redirecting fd {}: unsupported fd on this platform", fd),
+                ))
+            }
         }
         Ok(())
     }
 }
 
+#[doc(hidden)]
+pub fn home_dir() -> OsString {
+    let key = if cfg!(target_os = "windows") {
+        "USERPROFILE"
+    } else {
+        "HOME"
+    };
+    // keep '~' as it is if home directory is unknown, same as bash
+    std::env::var_os(key).unwrap_or_else(|| "~".into())
+}
+
+/// In-memory data which can be fed into stdin with `< $var`, instead of being used as the file path
+pub trait AsStdinBytes {
+    fn as_stdin_bytes(&self) -> &[u8];
+}
+
+impl AsStdinBytes for [u8] {
+    fn as_stdin_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl<const N: usize> AsStdinBytes for [u8; N] {
+    fn as_stdin_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsStdinBytes for Vec<u8> {
+    fn as_stdin_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl<T: AsStdinBytes + ?Sized> AsStdinBytes for &T {
+    fn as_stdin_bytes(&self) -> &[u8] {
+        (**self).as_stdin_bytes()
+    }
+}
+
+// The stdin redirect from a variable, chosen by its type with auto-ref: for `&&StdinVar`, the
+// methods of `StdinBytesVar` for `&StdinVar` are preferred over `StdinPathVar` for `&&StdinVar`,
+// and then `StdinStrVar` for `StdinVar` itself.
+#[doc(hidden)]
+pub struct StdinVar<'a, T: ?Sized>(pub &'a T);
+
+#[doc(hidden)]
+pub trait StdinBytesVar {
+    fn stdin_redirect(&self) -> Redirect;
+}
+
+impl<T: AsStdinBytes + ?Sized> StdinBytesVar for &StdinVar<'_, T> {
+    fn stdin_redirect(&self) -> Redirect {
+        Redirect::BytesToStdin(self.0.as_stdin_bytes().to_vec())
+    }
+}
+
+#[doc(hidden)]
+pub trait StdinPathVar {
+    fn stdin_redirect(&self) -> Redirect;
+}
+
+impl<T: AsRef<OsStr> + ?Sized> StdinPathVar for &&StdinVar<'_, T> {
+    fn stdin_redirect(&self) -> Redirect {
+        Redirect::FileToStdin(self.0.as_ref().into())
+    }
+}
+
+#[doc(hidden)]
+pub trait StdinStrVar {
+    fn stdin_redirect(&self) -> Redirect;
+}
+
+impl<T: AsOsStr + ?Sized> StdinStrVar for StdinVar<'_, T> {
+    fn stdin_redirect(&self) -> Redirect {
+        Redirect::FileToStdin(self.0.as_os_str().into())
+    }
+}
+
 #[doc(hidden)]
 pub trait AsOsStr {
     fn as_os_str(&self) -> OsString;
@@ -587,39 +2123,124 @@ impl<T: ToString> AsOsStr for T {
     }
 }
 
+// "${var:?message}" in the string literals, failing if the variable is empty
+#[doc(hidden)]
+pub fn check_var(value: OsString, name: &str, message: &OsStr) -> Result<OsString> {
+    if !value.is_empty() {
+        return Ok(value);
+    }
+    let message = if message.is_empty() {
+        "parameter null or not set".into()
+    } else {
+        message.to_string_lossy()
+    };
+    Err(Error::new(
+        ErrorKind::InvalidInput,
+        format!("{}: {}", name, message),
+    ))
+}
+
+// "${var#pattern}" and the other forms in the string literals, removing the shortest or the
+// longest prefix or suffix matching the glob pattern
+#[doc(hidden)]
+pub fn trim_var(value: OsString, pattern: &OsStr, prefix: bool, longest: bool) -> OsString {
+    let value = value.to_string_lossy();
+    let pattern = pattern.to_string_lossy();
+    // same as bash, an invalid pattern matches the text as it is
+    let pattern = glob::Pattern::new(&pattern)
+        .unwrap_or_else(|_| glob::Pattern::new(&glob::Pattern::escape(&pattern)).unwrap());
+    let mut bounds: Vec<usize> = value.char_indices().map(|(i, _)| i).collect();
+    bounds.push(value.len());
+    // from the shortest part to the longest one
+    if prefix == longest {
+        bounds.reverse();
+    }
+    for i in bounds {
+        let (part, rest) = if prefix {
+            (&value[..i], &value[i..])
+        } else {
+            (&value[i..], &value[..i])
+        };
+        if pattern.matches(part) {
+            return rest.into();
+        }
+    }
+    value.into_owned().into()
+}
+
 #[doc(hidden)]
 #[derive(Default)]
-pub struct CmdString(OsString);
+pub struct CmdString {
+    value: OsString,
+    // the glob pattern once a wildcard is appended, with the other parts like variables escaped
+    pattern: Option<String>,
+}
 impl CmdString {
     pub fn append<T: AsRef<OsStr>>(mut self, value: T) -> Self {
-        self.0.push(value);
+        if let Some(ref mut pattern) = self.pattern {
+            pattern.push_str(&glob::Pattern::escape(&value.as_ref().to_string_lossy()));
+        }
+        self.value.push(value);
+        self
+    }
+
+    /// Appends an unquoted wildcard like `*`, which is kept as it is in the glob pattern
+    pub fn append_glob(mut self, value: &str) -> Self {
+        let prev = &self.value;
+        self.pattern
+            .get_or_insert_with(|| glob::Pattern::escape(&prev.to_string_lossy()))
+            .push_str(value);
+        self.value.push(value);
         self
     }
 
     pub fn into_os_string(self) -> OsString {
-        self.0
+        self.value
     }
 
     pub fn into_path_buf(self) -> PathBuf {
-        self.0.into()
+        self.value.into()
+    }
+
+    /// Quotes the string if needed, so it reads as one argument in a logged command line
+    pub fn quoted(self) -> Self {
+        Self::from(&quote_arg(&self.value))
     }
 }
 
+// same as in shell, the argument is kept as it is if it is safe, or single quoted otherwise
+fn quote_arg(arg: &OsStr) -> String {
+    let s = arg.to_string_lossy();
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !s.is_empty() && s.chars().all(is_safe) {
+        s.into_owned()
+    } else {
+        format!("'{}'", s.replace('\'', r"'\''"))
+    }
+}
+
+fn quote_path(path: &Path) -> String {
+    quote_arg(path.as_os_str())
+}
+
 impl AsRef<OsStr> for CmdString {
     fn as_ref(&self) -> &OsStr {
-        self.0.as_ref()
+        self.value.as_ref()
     }
 }
 
 impl<T: ?Sized + AsRef<OsStr>> From<&T> for CmdString {
     fn from(s: &T) -> Self {
-        Self(s.as_ref().into())
+        Self {
+            value: s.as_ref().into(),
+            pattern: None,
+        }
     }
 }
 
 impl fmt::Display for CmdString {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str(&self.0.to_string_lossy())
+        f.write_str(&self.value.to_string_lossy())
     }
 }
 
@@ -685,4 +2306,17 @@ mod tests {
             .run_cmd(&mut current_dir)
             .is_ok());
     }
+
+    #[test]
+    fn test_cmd_str_quoted() {
+        let cmd = Cmd::default()
+            .add_args(["FOO=a b", "sh", "-c", "echo 'x'; exit", "", "/tmp/a.txt"])
+            .add_redirect(Redirect::StdoutToFile(PathBuf::from("/tmp/my file"), true));
+        assert_eq!(
+            cmd.cmd_str(),
+            r#"FOO='a b' sh -c 'echo '\''x'\''; exit' '' /tmp/a.txt 1>> '/tmp/my file'"#
+        );
+        assert_eq!(CmdString::from("it's").quoted().to_string(), r#"'it'\''s'"#);
+        assert_eq!(CmdString::from("a.txt").quoted().to_string(), "a.txt");
+    }
 }
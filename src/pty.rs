@@ -0,0 +1,71 @@
+use os_pipe::PipeReader;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::thread;
+
+/// Opens a pseudo-terminal for capturing the output of a command
+///
+/// It returns the reader of the output and the terminal to be used as the command's stdout.
+/// The output is copied from the master side into a pipe by a thread, so it can be read like
+/// the normal pipes, with the end of the output when the terminal is closed.
+pub(crate) fn open_pty() -> Result<(PipeReader, File)> {
+    let mut master: RawFd = -1;
+    let mut terminal: RawFd = -1;
+    let ret = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut terminal,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    let mut master = unsafe { File::from_raw_fd(master) };
+    let terminal = unsafe { File::from_raw_fd(terminal) };
+    for fd in [master.as_raw_fd(), terminal.as_raw_fd()] {
+        // not leaked to the commands, which only get the terminal as stdout
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    disable_crlf(&terminal)?;
+
+    let (pipe_reader, mut pipe_writer) = os_pipe::pipe()?;
+    thread::Builder::new().spawn(move || {
+        let mut buf = [0; 4096];
+        loop {
+            match master.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if pipe_writer.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                // reading the master fails with EIO on Linux once the terminal is closed
+                Err(_) => break,
+            }
+        }
+    })?;
+    Ok((pipe_reader, terminal))
+}
+
+// keep "\n" as it is, instead of the "\r\n" written by the terminal by default
+fn disable_crlf(terminal: &File) -> Result<()> {
+    let fd = terminal.as_raw_fd();
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(Error::last_os_error());
+        }
+        termios.c_oflag &= !libc::ONLCR;
+        if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
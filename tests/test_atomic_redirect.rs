@@ -0,0 +1,22 @@
+use cmd_lib::*;
+
+// in its own test binary, since the global setting would affect the other tests running in
+// parallel
+#[test]
+fn test_atomic_redirect() {
+    let dir = std::path::Path::new("/tmp/cmd_lib_atomic_redirect");
+    let (f, seen) = (dir.join("config"), dir.join("seen"));
+    std::fs::create_dir_all(dir).unwrap();
+    std::fs::write(&f, "old\n").unwrap();
+    set_atomic_redirect(true);
+    // the file is only replaced after the command finishes
+    assert!(run_cmd!(sh -c "echo new; cat $f > $seen" > $f).is_ok());
+    assert!(run_cmd!(sh -c "echo partial; exit 1" > $f).is_err());
+    assert!(run_cmd!(echo forced >| $f; false).is_err());
+    set_atomic_redirect(false);
+    assert_eq!(std::fs::read_to_string(&seen).unwrap(), "old\n");
+    assert_eq!(std::fs::read_to_string(&f).unwrap(), "forced\n");
+    // no temporary file is left
+    assert_eq!(std::fs::read_dir(dir).unwrap().count(), 2);
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -24,6 +24,34 @@ fn test_cd_fails() {
     .is_err());
 }
 
+#[test]
+fn test_cd_prev_and_home() {
+    assert_eq!(run_fun!(cd /tmp; cd /; cd -).unwrap(), "/tmp");
+    assert_eq!(run_fun!(cd /tmp; cd /; cd - > /dev/null; pwd).unwrap(), "/tmp");
+    assert_eq!(run_fun!(cd /tmp; cd /; cd -; cd -).unwrap(), "/");
+    assert_eq!(
+        run_fun!(cd /; cd; pwd).unwrap(),
+        home_dir().to_string_lossy()
+    );
+    assert!(run_cmd!(cd -).is_err());
+}
+
+#[test]
+fn test_cd_expand() {
+    let home = home_dir().to_string_lossy().to_string();
+    let dir = "~";
+    assert_eq!(run_fun!(cd $dir; pwd).unwrap(), home);
+    let dir = "${CMD_LIB_CD_DIR}/$CMD_LIB_CD_SUB";
+    assert_eq!(
+        run_fun!(export CMD_LIB_CD_DIR=/usr CMD_LIB_CD_SUB=bin; cd $dir; pwd).unwrap(),
+        "/usr/bin"
+    );
+    let err = run_cmd!(cd $dir).unwrap_err().to_string();
+    assert!(err.contains("CMD_LIB_CD_DIR: variable not set"));
+    let err = run_cmd!(cd /; cd cmd_lib_no_such_dir).unwrap_err().to_string();
+    assert!(err.contains("cd /cmd_lib_no_such_dir: No such file or directory"));
+}
+
 #[test]
 fn test_run_cmds() {
     assert!(run_cmd! {
@@ -93,6 +121,78 @@ fn test_vars_in_str0() {
 fn test_vars_in_str1() {
     assert_eq!(run_fun!(echo "$$").unwrap(), "$");
     assert_eq!(run_fun!(echo "$$a").unwrap(), "$a");
+    let a = "x";
+    assert_eq!(run_fun!(echo "price: $$5").unwrap(), "price: $5");
+    assert_eq!(run_fun!(echo "$$$a $${a}").unwrap(), "$x ${a}");
+    assert_eq!(run_fun!(echo r"$a ${a}").unwrap(), "$a ${a}");
+}
+
+#[test]
+fn test_vars_in_str_default() {
+    let empty = "";
+    let name = "rust";
+    assert_eq!(run_fun!(echo "${empty:-none}").unwrap(), "none");
+    assert_eq!(run_fun!(echo "${name:-none}").unwrap(), "rust");
+    assert_eq!(run_fun!(echo "[${empty:-}]").unwrap(), "[]");
+    assert_eq!(run_fun!(echo "${empty:-$name $$1}").unwrap(), "rust $1");
+    assert_eq!(run_fun!(echo "${empty:-${empty:-x}}").unwrap(), "x");
+    assert_eq!(run_fun!(echo "${empty:-$(echo sub)}").unwrap(), "sub");
+    cmd_info!("${empty:-also in the logging macros}");
+
+    assert_eq!(run_fun!(echo "${name:?name is required}").unwrap(), "rust");
+    let err = run_fun!(echo "${empty:?name is required}").unwrap_err();
+    assert_eq!(err.to_string(), "empty: name is required");
+    let err = run_cmd!(echo "${empty:?}").unwrap_err();
+    assert_eq!(err.to_string(), "empty: parameter null or not set");
+}
+
+#[test]
+fn test_vars_in_str_expansions() {
+    let empty = "";
+    let name = "rust";
+    assert_eq!(run_fun!(echo "[${name:+set}]").unwrap(), "[set]");
+    assert_eq!(run_fun!(echo "[${empty:+set}]").unwrap(), "[]");
+    assert_eq!(run_fun!(echo "${name:+-o $name}").unwrap(), "-o rust");
+    assert_eq!(run_fun!(echo "${#name} ${#empty}").unwrap(), "4 0");
+    let word = "héllo";
+    assert_eq!(run_fun!(echo "${#word}").unwrap(), "5");
+
+    let path = "/usr/local/lib/libfoo.so.1";
+    assert_eq!(run_fun!(echo "${path#*/}").unwrap(), "usr/local/lib/libfoo.so.1");
+    assert_eq!(run_fun!(echo "${path##*/}").unwrap(), "libfoo.so.1");
+    assert_eq!(run_fun!(echo "${path%.*}").unwrap(), "/usr/local/lib/libfoo.so");
+    assert_eq!(run_fun!(echo "${path%%.*}").unwrap(), "/usr/local/lib/libfoo");
+    assert_eq!(run_fun!(echo "${path%/*}").unwrap(), "/usr/local/lib");
+    assert_eq!(run_fun!(echo "${path#/usr}").unwrap(), "/local/lib/libfoo.so.1");
+    assert_eq!(run_fun!(echo "${path%nomatch}").unwrap(), path);
+    let ext = ".so.1";
+    assert_eq!(run_fun!(echo "${path%$ext}").unwrap(), "/usr/local/lib/libfoo");
+}
+
+#[test]
+fn test_arith_expansion() {
+    assert_eq!(run_fun!(echo $((2 + 3 * 4))).unwrap(), "14");
+    assert_eq!(run_fun!(echo $(((2 + 3) * -4 % 7))).unwrap(), "-6");
+    let n = 10;
+    let s = "3";
+    assert_eq!(run_fun!(echo $((n / s)) $(($n - ${s}))).unwrap(), "3 7");
+    assert_eq!(run_fun!(echo "n+1=$((n + 1))").unwrap(), "n+1=11");
+    assert_eq!(run_fun!(echo x$((n * n))).unwrap(), "x100");
+
+    let s = "abc";
+    assert!(run_fun!(echo $((n + s))).is_err());
+    let zero = 0;
+    assert!(run_fun!(echo $((n / zero))).is_err());
+    let max = i64::MAX;
+    assert!(run_fun!(echo $((max + 1))).is_err());
+}
+
+#[test]
+fn test_raw_str_no_interpolation() {
+    assert_eq!(run_fun!(echo r"$HOME ${HOME}").unwrap(), "$HOME ${HOME}");
+    assert_eq!(run_fun!(echo r#"say "$HOME" \n"#).unwrap(), r#"say "$HOME" \n"#);
+    assert_eq!(run_fun!(cat <<< r"$HOME $(pwd)").unwrap(), "$HOME $(pwd)");
+    assert_eq!(run_fun!(cat << r"$HOME").unwrap(), "$HOME");
 }
 
 #[test]
@@ -122,6 +222,9 @@ fn test_vars_in_str3() {
 /// assert_eq!(run_fun!(echo "${0msg}").unwrap(), "${0msg}");
 /// assert_eq!(run_fun!(echo "${msg 0}").unwrap(), "${msg 0}");
 /// assert_eq!(run_fun!(echo "${msg 0}").unwrap(), "${msg 0}");
+/// assert_eq!(run_fun!(echo "${msg:=0}").unwrap(), "${msg:=0}");
+/// assert_eq!(run_fun!(echo "${msg/a/b}").unwrap(), "${msg/a/b}");
+/// assert_eq!(run_fun!(echo "${#}").unwrap(), "${#}");
 /// ```
 fn test_vars_in_str4() {}
 
@@ -153,6 +256,161 @@ fn test_pipe() {
     assert!(run_cmd!(ls | $wc_cmd).is_ok());
 }
 
+#[test]
+fn test_pipe_stderr() {
+    assert_eq!(
+        run_fun!(sh -c "echo out; echo error >&2" |& grep error).unwrap(),
+        "error"
+    );
+    assert_eq!(
+        run_fun!(sh -c "echo error >&2" |& cat | wc -l).unwrap().trim(),
+        "1"
+    );
+    // only the stderr of the command before `|&` is piped
+    assert_eq!(
+        run_fun!(sh -c "echo error >&2" | sh -c "cat; echo error2 >&2" |& wc -l)
+            .unwrap()
+            .trim(),
+        "1"
+    );
+}
+
+#[test]
+fn test_run_cmd_retry() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+    static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+    #[export_cmd(flaky_cmd)]
+    fn flaky_cmd(_env: &mut CmdEnv) -> CmdResult {
+        if ATTEMPTS.fetch_add(1, Ordering::SeqCst) < 2 {
+            return Err(std::io::Error::other("not ready yet"));
+        }
+        Ok(())
+    }
+    use_custom_cmd!(flaky_cmd);
+
+    let now = Instant::now();
+    assert!(run_cmd_retry!(3, Duration::from_millis(20), 2.0; flaky_cmd).is_ok());
+    assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 3);
+    // waiting 20ms and then 40ms
+    assert!(now.elapsed() >= Duration::from_millis(60));
+
+    let delay = Duration::from_millis(1);
+    assert!(run_cmd_retry!(2, delay; ls /nofile).is_err());
+    assert!(run_cmd_retry!(1, delay; true; ls /nofile).is_err());
+    assert!(run_cmd_retry!(2, delay; true; true).is_ok());
+}
+
+#[test]
+fn test_debug_target() {
+    use std::sync::Mutex;
+    static TRACES: Mutex<Vec<String>> = Mutex::new(vec![]);
+    fn save_trace(msg: &str) {
+        TRACES.lock().unwrap().push(msg.to_owned());
+    }
+
+    set_debug_target(DebugTarget::Custom(save_trace));
+    set_debug(true);
+    let ret = run_cmd!(echo cmd_lib_debug_target | cat > /dev/null);
+    set_debug(false);
+    set_debug_target(DebugTarget::Log);
+    assert!(ret.is_ok());
+    assert!(TRACES
+        .lock()
+        .unwrap()
+        .contains(&"Running echo cmd_lib_debug_target | cat 1> /dev/null ...".to_owned()));
+    assert!(TRACES
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|trace| trace.starts_with("Command cat 1> /dev/null finished in ")));
+}
+
+#[test]
+fn test_command_hook() {
+    fn add_var(cmd: &mut std::process::Command) {
+        cmd.env("CMD_LIB_COMMAND_HOOK", "1");
+    }
+    fn add_builtin_var(env: &mut CmdEnv) {
+        env.set_var("CMD_LIB_BUILTIN_HOOK".to_owned(), "1".to_owned());
+    }
+    #[export_cmd(hook_var)]
+    fn hook_var(env: &mut CmdEnv) -> CmdResult {
+        let value = env.var("CMD_LIB_BUILTIN_HOOK").cloned().unwrap_or_default();
+        env.write_stdout(&value)
+    }
+    use_custom_cmd!(hook_var);
+
+    set_command_hook(Some(add_var));
+    set_builtin_hook(Some(add_builtin_var));
+    let var = run_fun!(printenv CMD_LIB_COMMAND_HOOK);
+    let builtin_var = run_fun!(hook_var);
+    set_command_hook(None);
+    set_builtin_hook(None);
+    assert_eq!(var.unwrap(), "1");
+    assert_eq!(builtin_var.unwrap(), "1");
+    assert!(run_cmd!(printenv CMD_LIB_COMMAND_HOOK).is_err());
+}
+
+#[test]
+fn test_wait_with_timings() {
+    let timings = spawn!(sleep 0.2 | cat).unwrap().wait_with_timings().unwrap();
+    assert_eq!(timings.len(), 2);
+    assert_eq!(timings[0].0, "sleep 0.2");
+    assert_eq!(timings[1].0, "cat");
+    assert!(timings[0].1 >= std::time::Duration::from_millis(200));
+    assert!(spawn!(false).unwrap().wait_with_timings().is_err());
+    assert!(spawn!(false | true).unwrap().wait_with_timings().is_err());
+}
+
+#[test]
+fn test_pipefail_override() {
+    let pipe = || {
+        Cmds::default()
+            .pipe(Cmd::default().add_arg("false"))
+            .pipe(Cmd::default().add_arg("true"))
+    };
+    assert!(GroupCmds::default().append(pipe()).run_cmd().is_err());
+    assert!(GroupCmds::default()
+        .pipefail(false)
+        .append(pipe())
+        .run_cmd()
+        .is_ok());
+    assert!(GroupCmds::default()
+        .pipefail(false)
+        .append(pipe().pipefail(true))
+        .run_cmd()
+        .is_err());
+    let mut proc = GroupCmds::default()
+        .append(pipe().pipefail(false))
+        .spawn(false)
+        .unwrap();
+    assert!(proc.wait().is_ok());
+}
+
+#[test]
+fn test_redirect_open_error() {
+    let dir = std::env::temp_dir().join("cmd_lib_test_redirect_open_error");
+    let file = dir.join("logs/out.log");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let err = run_cmd!(echo xx > $file).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    let msg = err.to_string();
+    assert!(msg.contains(&format!("failed to open {} for writing (truncate)", file.display())));
+    let err = run_cmd!(echo xx >> $file).unwrap_err();
+    assert!(err.to_string().contains("for writing (append)"));
+    let err = run_cmd!(cat < $file).unwrap_err();
+    assert!(err.to_string().contains("for reading"));
+
+    set_redirect_mkdir(true);
+    let ret = run_cmd!(echo xx > $file; echo yy >> $file);
+    set_redirect_mkdir(false);
+    assert!(ret.is_ok());
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "xx\nyy\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
 #[test]
 /// ```compile_fail
 /// run_cmd!(ls > >&1).unwrap();
@@ -180,6 +438,106 @@ fn test_redirect() {
     assert!(run_cmd!(rm -f $tmp_file $tmp_log).is_ok());
 }
 
+#[test]
+/// ```compile_fail
+/// run_cmd!(ls >|&1).unwrap();
+/// ```
+fn test_redirect_clobber() {
+    let tmp_file = "/tmp/clobber_test.log";
+    assert!(run_cmd!(echo xxxx > $tmp_file; echo yyyy >| $tmp_file).is_ok());
+    assert_eq!(run_fun!(cat $tmp_file).unwrap(), "yyyy");
+    assert!(run_cmd!(ignore ls /x 2>|$tmp_file; echo zzzz &>| $tmp_file).is_ok());
+    assert_eq!(run_fun!(cat $tmp_file).unwrap(), "zzzz");
+    assert!(run_cmd!(rm $tmp_file).is_ok());
+}
+
+#[test]
+fn test_cmd_ignore_error() {
+    let run = |first: Cmd, last: Cmd| {
+        GroupCmds::default()
+            .pipefail(true)
+            .append(Cmds::default().pipe(first).pipe(last))
+            .run_cmd()
+    };
+    let cmd = |name| Cmd::default().add_arg(name);
+    assert!(run(cmd("false").ignore_error(), cmd("true")).is_ok());
+    assert!(run(cmd("true"), cmd("false").ignore_error()).is_ok());
+    // unlike the "ignore" prefix, the other commands in the pipeline are not ignored
+    assert!(run(cmd("false").ignore_error(), cmd("false")).is_err());
+    assert!(run(cmd("ignore").add_arg("false"), cmd("false")).is_ok());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_cmd_limits() {
+    let run_fun = |cmd: Cmd| {
+        GroupCmds::default()
+            .append(Cmds::default().pipe(cmd))
+            .run_fun()
+    };
+    let cmd = Cmd::default().add_args(["sh", "-c", "ulimit -n; ulimit -Hn"]);
+    assert_eq!(
+        run_fun(cmd.rlimit(Resource::NoFile, 64, 128)).unwrap(),
+        "64\n128"
+    );
+    let cmd = Cmd::default().add_args(["sh", "-c", "ulimit -t"]);
+    assert_eq!(
+        run_fun(cmd.rlimit(Resource::Cpu, u64::MAX, u64::MAX)).unwrap(),
+        "unlimited"
+    );
+    let cmd = Cmd::default().add_arg("nice");
+    assert_eq!(run_fun(cmd.nice(19)).unwrap(), "19");
+    // the soft limit can't be higher than the hard one
+    let cmd = Cmd::default().add_arg("true");
+    assert!(run_fun(cmd.rlimit(Resource::NoFile, 128, 64)).is_err());
+}
+
+#[test]
+fn test_cmd_buffers() {
+    use std::sync::{Arc, Mutex};
+
+    #[export_cmd(cmd_lib_buffers)]
+    fn buffers(env: &mut CmdEnv) -> CmdResult {
+        let input = env.stdin_string()?;
+        env.write_stdout(&input.to_uppercase())?;
+        env.write_stderr("to stderr\n")
+    }
+    use_custom_cmd!(cmd_lib_buffers);
+
+    let run = |cmd: Cmd| {
+        GroupCmds::default()
+            .append(Cmds::default().pipe(cmd))
+            .run_cmd()
+            .unwrap()
+    };
+    let take = |buffer: &Arc<Mutex<Vec<u8>>>| {
+        String::from_utf8(std::mem::take(&mut *buffer.lock().unwrap())).unwrap()
+    };
+    let (out, err) = (Arc::default(), Arc::default());
+    run(Cmd::default()
+        .add_arg("cmd_lib_buffers")
+        .stdin_buffer("from buffer")
+        .stdout_buffer(Arc::clone(&out))
+        .stderr_buffer(Arc::clone(&err)));
+    assert_eq!(take(&out), "FROM BUFFER");
+    assert_eq!(take(&err), "to stderr\n");
+
+    run(Cmd::default()
+        .add_args(["sh", "-c", "cat; echo to stderr >&2"])
+        .stdin_buffer("from buffer\n")
+        .stdout_buffer(Arc::clone(&out))
+        .stderr_buffer(Arc::clone(&err)));
+    assert_eq!(take(&out), "from buffer\n");
+    assert_eq!(take(&err), "to stderr\n");
+
+    // the clones share the same buffer
+    run(Cmd::default()
+        .add_args(["sh", "-c", "echo out; sleep 0.1; echo err >&2"])
+        .stdout_buffer(Arc::clone(&out))
+        .add_redirect(Redirect::StderrToStdout));
+    assert_eq!(take(&out), "out\nerr\n");
+}
+
 #[test]
 fn test_proc_env() {
     let output = run_fun!(FOO=100 printenv | grep FOO).unwrap();
@@ -253,3 +611,1763 @@ fn test_path_as_var() {
     let dir2 = std::path::PathBuf::from("/");
     assert_eq!("/", run_fun!(cd $dir2; pwd).unwrap());
 }
+
+#[test]
+fn test_wait_timeout() {
+    use std::time::{Duration, Instant};
+    let now = Instant::now();
+    let mut proc = spawn!(sleep 10 | sleep 1).unwrap();
+    let err = proc.wait_timeout(Duration::from_millis(100)).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    assert!(now.elapsed() < Duration::from_secs(5));
+
+    assert!(spawn!(sleep 0).unwrap().wait_timeout(Duration::from_secs(5)).is_ok());
+    assert!(spawn!(false).unwrap().wait_timeout(Duration::from_secs(5)).is_err());
+}
+
+#[test]
+fn test_run_cmd_timeout() {
+    use std::time::{Duration, Instant};
+    let now = Instant::now();
+    let err = run_cmd_timeout!(Duration::from_millis(100); sleep 10 | sleep 1).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    assert!(err.to_string().contains("sleep 10 | sleep 1"));
+    assert!(now.elapsed() < Duration::from_secs(5));
+    // the stages failed to spawn are still in the error
+    let err = run_cmd_timeout!(Duration::from_millis(100); sleep 10 | cmd_lib_no_such_cmd).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    assert!(err.to_string().contains("sleep 10 | cmd_lib_no_such_cmd"));
+
+    let timeout = Duration::from_secs(5);
+    assert!(run_cmd_timeout!(timeout; sleep 0).is_ok());
+    assert!(run_cmd_timeout!(timeout; false).is_err());
+}
+
+#[test]
+fn test_pids() {
+    use_builtin_cmd!(echo);
+    let mut proc = spawn!(echo xx | sleep 1).unwrap();
+    let pids = proc.pids();
+    assert_eq!(pids.len(), 2);
+    assert!(pids[0].is_none());
+    assert!(pids[1].is_some());
+    assert!(proc.wait().is_ok());
+}
+
+#[test]
+fn test_here_string() {
+    let content = "hello, here-string";
+    assert_eq!(run_fun!(cat <<< "$content").unwrap(), content);
+    assert_eq!(run_fun!(cat <<< $content | wc -l).unwrap().trim(), "1");
+    assert_eq!(run_fun!(cat <<< "").unwrap(), "");
+    assert_eq!(run_fun!(cat <<<"x").unwrap(), "x");
+}
+
+#[test]
+fn test_builtin_pwd() {
+    use_builtin_cmd!(pwd);
+    assert_eq!(run_fun!(cd /tmp; pwd).unwrap(), "/tmp");
+    assert_eq!(
+        run_fun!(pwd).unwrap(),
+        std::env::current_dir().unwrap().display().to_string()
+    );
+
+    let f = "/tmp/builtin_pwd";
+    assert!(run_cmd!(cd /; pwd > $f).is_ok());
+    assert_eq!(run_fun!(cat $f).unwrap(), "/");
+    run_cmd!(rm -f $f).unwrap();
+}
+
+#[test]
+fn test_cmd_env_vars() {
+    use std::io::Write;
+    #[export_cmd(set_var_cmd)]
+    fn set_var_cmd(env: &mut CmdEnv) -> CmdResult {
+        env.set_var("BAR".into(), "200".into());
+        let mut vars: Vec<String> = env.vars().map(|(k, v)| format!("{}={}", k, v)).collect();
+        vars.sort();
+        writeln!(env.stdout(), "{}", vars.join(","))
+    }
+    use_custom_cmd!(set_var_cmd);
+    assert_eq!(run_fun!(FOO=100 set_var_cmd).unwrap(), "BAR=200,FOO=100");
+}
+
+#[test]
+fn test_cmd_error() {
+    let err = run_cmd!(sh -c "exit 3").unwrap_err();
+    let cmd_err = CmdError::from_io_error(&err).unwrap();
+    assert_eq!(cmd_err.exit_code(), Some(3));
+    assert!(cmd_err.cmd().contains("exit 3"));
+
+    let err = run_cmd!(/no_such_cmd_xx).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    let cmd_err = CmdError::from_io_error(&err).unwrap();
+    assert!(matches!(cmd_err, CmdError::Spawn { .. }));
+    assert_eq!(cmd_err.exit_code(), None);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_path_check() {
+    let err = run_cmd!(no_such_cmd_xx arg).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    assert!(err
+        .to_string()
+        .contains("command not found: no_such_cmd_xx (searched PATH)"));
+
+    let err = run_cmd!(PATH=/no_such_dir ls).unwrap_err();
+    assert!(err.to_string().contains("searched PATH"));
+    assert!(run_cmd!(PATH=/bin:/usr/bin ls).is_ok());
+
+    // not a program
+    let err = run_cmd!(/tmp).unwrap_err();
+    assert!(err.to_string().contains("command not found: /tmp"));
+    assert!(run_cmd!(cd /bin; ./ls).is_ok());
+}
+
+#[test]
+fn test_brace_expansion() {
+    use_builtin_cmd!(echo);
+    assert_eq!(run_fun!(echo a{b,c}d).unwrap(), "abd acd");
+    assert_eq!(run_fun!(echo {a,b{c,d}}).unwrap(), "a bc bd");
+    assert_eq!(run_fun!(echo x{a,,b}).unwrap(), "xa x xb");
+    assert_eq!(run_fun!(echo {a}).unwrap(), "{a}");
+    assert_eq!(run_fun!(echo {1,2}-{x,y}).unwrap(), "1-x 1-y 2-x 2-y");
+
+    let dir = "/tmp/brace_expansion";
+    assert!(run_cmd!(mkdir -p $dir/{src,tests,docs}).is_ok());
+    assert_eq!(run_fun!(ls $dir | wc -l).unwrap().trim(), "3");
+    assert!(run_cmd!(rm -rf $dir).is_ok());
+}
+
+#[test]
+/// ```compile_fail
+/// run_cmd!(ls &&).unwrap();
+/// run_cmd!(&& ls).unwrap();
+/// run_cmd!(ls && && ls).unwrap();
+/// run_cmd!(ls && ; ls).unwrap();
+/// ```
+fn test_and_list() {
+    use_builtin_cmd!(echo);
+    assert_eq!(run_fun!(echo a && echo b).unwrap(), "b");
+    assert!(run_cmd!(true && false).is_err());
+    assert!(run_cmd!(false && echo b).is_err());
+    assert!(run_fun!(false && echo b).is_err());
+    assert_eq!(run_fun!(false && echo b; echo c).unwrap(), "c");
+    assert_eq!(run_fun!(true && false && echo b; echo c).unwrap(), "c");
+    assert!(run_cmd!(true && false; echo c).is_err());
+    assert_eq!(run_fun!(ignore false && echo b).unwrap(), "b");
+
+    let f = "/tmp/and_list";
+    assert!(run_cmd!(ls /no_such_dir && touch $f; echo c).is_ok());
+    assert!(run_cmd!(ls $f).is_err());
+}
+
+#[test]
+/// ```compile_fail
+/// run_cmd!(ls ||).unwrap();
+/// run_cmd!(|| ls).unwrap();
+/// run_cmd!(ls || && ls).unwrap();
+/// ```
+fn test_or_list() {
+    use_builtin_cmd!(echo);
+    assert_eq!(run_fun!(false || echo b).unwrap(), "b");
+    assert_eq!(run_fun!(echo a || echo b).unwrap(), "a");
+    assert!(run_cmd!(false || false).is_err());
+    assert_eq!(run_fun!(false && echo a || echo b).unwrap(), "b");
+    assert_eq!(run_fun!(true && echo a || echo b).unwrap(), "a");
+    assert_eq!(run_fun!(false || false || echo c).unwrap(), "c");
+    assert!(run_cmd!(false || false; echo c).is_err());
+    // pipefail by default
+    assert_eq!(run_fun!(ls /no_such_dir | wc -l || echo b).unwrap(), "b");
+}
+
+#[test]
+fn test_builtin_tee() {
+    use_builtin_cmd!(echo, tee);
+    let f = "/tmp/builtin_tee";
+    assert_eq!(run_fun!(echo xx | tee $f).unwrap(), "xx");
+    assert_eq!(run_fun!(echo yy | tee -a $f).unwrap(), "yy");
+    assert_eq!(run_fun!(cat $f).unwrap(), "xx\nyy");
+    assert!(run_cmd!(echo zz | tee $f > /dev/null).is_ok());
+    assert_eq!(run_fun!(cat $f).unwrap(), "zz");
+    assert!(run_cmd!(echo ww | tee $f -a > /dev/null).is_ok());
+    assert_eq!(run_fun!(cat $f).unwrap(), "zz\nww");
+    run_cmd!(rm -f $f).unwrap();
+}
+
+#[test]
+fn test_wait_with_output_bytes() {
+    let output = spawn_with_output!(printf r"\xff\x00\n")
+        .unwrap()
+        .wait_with_output_bytes()
+        .unwrap();
+    assert_eq!(output, vec![0xff, 0x00, b'\n']);
+}
+
+#[test]
+fn test_wait_with_all() {
+    let output = spawn_with_output!(sh -c "echo o1; echo e1 >&2; echo o2; exit 2")
+        .unwrap()
+        .wait_with_all()
+        .unwrap();
+    assert_eq!(
+        output,
+        CmdOutput {
+            stdout: "o1\no2\n".into(),
+            stderr: "e1\n".into(),
+            status: 2,
+        }
+    );
+
+    // large output on both streams won't block
+    let output = spawn_with_output!(sh -c "seq 1 100000 >&2; seq 1 100000" | wc -l)
+        .unwrap()
+        .wait_with_all()
+        .unwrap();
+    assert_eq!(output.stdout.trim(), "100000");
+    assert_eq!(output.stderr.lines().count(), 100000);
+    assert_eq!(output.status, 0);
+}
+
+#[test]
+fn test_wait_stderr_captured() {
+    use cmd_lib::builder::Pipeline;
+    let err = Pipeline::new()
+        .command(["sh", "-c", "echo e1 >&2; exit 3"])
+        .spawn_stderr_captured()
+        .unwrap()
+        .wait_stderr_captured()
+        .unwrap_err();
+    assert!(err.to_string().ends_with("status code: 3\ne1"));
+
+    let stderr = Pipeline::new()
+        .command(["sh", "-c", "echo e1 >&2; echo out"])
+        .pipe(["sh", "-c", "cat >/dev/null; echo e2 >&2"])
+        .spawn_stderr_captured()
+        .unwrap()
+        .wait_stderr_captured()
+        .unwrap();
+    assert_eq!(stderr.lines().count(), 2);
+    assert!(stderr.contains("e1\n") && stderr.contains("e2\n"));
+
+    // nothing is captured from the output
+    let mut proc = Pipeline::new()
+        .command(["true"])
+        .spawn_stderr_captured()
+        .unwrap();
+    assert_eq!(proc.wait_with_output().unwrap(), "");
+}
+
+#[test]
+fn test_wait_with_streams() {
+    let mut out = vec![];
+    let mut err = vec![];
+    spawn_with_output!(sh -c "echo o1; echo e1 >&2; echo o2; printf e2 >&2; printf o3")
+        .unwrap()
+        .wait_with_streams(|line| out.push(line.to_owned()), |line| err.push(line.to_owned()))
+        .unwrap();
+    assert_eq!(out, vec!["o1", "o2", "o3"]);
+    assert_eq!(err, vec!["e1", "e2"]);
+
+    let mut err = vec![];
+    let res = spawn_with_output!(sh -c "echo e1 >&2" | sh -c "cat; echo e2 >&2; exit 1")
+        .unwrap()
+        .wait_with_streams(|_| {}, |line| err.push(line.to_owned()));
+    assert!(res.is_err());
+    err.sort();
+    assert_eq!(err, vec!["e1", "e2"]);
+}
+
+#[test]
+fn test_wait_with_output_and_status() {
+    let (output, status) = spawn_with_output!(sh -c "echo partial; echo err >&2; exit 3")
+        .unwrap()
+        .wait_with_output_and_status();
+    assert_eq!(output, "partial");
+    assert_eq!(status.unwrap(), 3);
+
+    let (output, status) = spawn_with_output!(echo ok | cat)
+        .unwrap()
+        .wait_with_output_and_status();
+    assert_eq!(output, "ok");
+    assert_eq!(status.unwrap(), 0);
+}
+
+#[test]
+fn test_wait_with_output_combined() {
+    // sleeping in between, so the order of the lines is deterministic
+    let output = spawn_with_output!(sh -c "echo o1; sleep 0.1; echo e1 >&2; sleep 0.1; echo o2")
+        .unwrap()
+        .wait_with_output_combined()
+        .unwrap();
+    assert_eq!(output, "o1\ne1\no2");
+
+    let mut lines: Vec<_> = spawn_with_output!(sh -c "echo e1 >&2" | sh -c "cat; echo o1")
+        .unwrap()
+        .wait_with_output_combined()
+        .unwrap()
+        .lines()
+        .map(str::to_owned)
+        .collect();
+    lines.sort();
+    assert_eq!(lines, vec!["e1", "o1"]);
+
+    assert!(spawn_with_output!(sh -c "echo e1 >&2; exit 1")
+        .unwrap()
+        .wait_with_output_combined()
+        .is_err());
+}
+
+#[test]
+fn test_stream_prefixed() {
+    assert!(spawn_with_output!(sh -c "echo out; echo err >&2")
+        .unwrap()
+        .stream_prefixed("test")
+        .is_ok());
+    assert!(spawn_with_output!(echo out | false)
+        .unwrap()
+        .stream_prefixed("test")
+        .is_err());
+}
+
+#[test]
+fn test_wait_with_output_raw() {
+    let output = spawn_with_output!(printf "a\nb\n\n")
+        .unwrap()
+        .wait_with_output_raw()
+        .unwrap();
+    assert_eq!(output, "a\nb\n\n");
+    let output = spawn_with_output!(printf "a\nb\n\n")
+        .unwrap()
+        .wait_with_output()
+        .unwrap();
+    assert_eq!(output, "a\nb\n");
+}
+
+#[test]
+fn test_wait_into() {
+    let mut buf = String::from("x");
+    for i in 1..=2 {
+        spawn_with_output!(printf "a$i\n")
+            .unwrap()
+            .wait_into(&mut buf)
+            .unwrap();
+    }
+    assert_eq!(buf, "xa1\na2\n");
+    let mut bytes = vec![];
+    spawn_with_output!(printf r"\xff")
+        .unwrap()
+        .wait_into_bytes(&mut bytes)
+        .unwrap();
+    assert_eq!(bytes, [0xff]);
+    spawn_with_output!(printf r"\xff")
+        .unwrap()
+        .wait_into(&mut buf)
+        .unwrap();
+    assert_eq!(buf, "xa1\na2\n\u{fffd}");
+    assert!(spawn_with_output!(false)
+        .unwrap()
+        .wait_into(&mut buf)
+        .is_err());
+}
+
+#[test]
+/// ```compile_fail
+/// run_cmd!(ls ~root).unwrap();
+/// ```
+fn test_tilde_expansion() {
+    use_builtin_cmd!(echo);
+    let home = std::env::var("HOME").unwrap();
+    assert_eq!(run_fun!(echo ~).unwrap(), home);
+    assert_eq!(run_fun!(echo ~/Documents).unwrap(), format!("{}/Documents", home));
+    assert_eq!(run_fun!(echo file~1).unwrap(), "file~1");
+    assert_eq!(run_fun!(echo "~/x").unwrap(), "~/x");
+    assert_eq!(run_fun!(cd ~; pwd).unwrap(), home);
+}
+
+#[test]
+fn test_cmd_env_write() {
+    #[export_cmd(write_cmd)]
+    fn write_cmd(env: &mut CmdEnv) -> CmdResult {
+        env.write_stderr("msg from write_cmd\n")?;
+        env.write_stdout("out1\n")?;
+        env.write_stdout("out2")
+    }
+    use_custom_cmd!(write_cmd);
+    assert_eq!(run_fun!(write_cmd).unwrap(), "out1\nout2");
+}
+
+#[test]
+fn test_builtin_seq() {
+    use_builtin_cmd!(seq);
+    assert_eq!(run_fun!(seq 3).unwrap(), "1\n2\n3");
+    assert_eq!(run_fun!(seq 2 4).unwrap(), "2\n3\n4");
+    assert_eq!(run_fun!(seq 1 2 6).unwrap(), "1\n3\n5");
+    assert_eq!(run_fun!(seq 3 -1 1).unwrap(), "3\n2\n1");
+    assert_eq!(run_fun!(seq 3 1).unwrap(), "");
+    assert!(run_fun!(seq 1 0 3).is_err());
+    assert!(run_fun!(seq a).is_err());
+    assert!(run_fun!(seq).is_err());
+
+    let f = "/tmp/builtin_seq";
+    assert!(run_cmd!(seq 5 > $f).is_ok());
+    assert_eq!(run_fun!(wc -l < $f).unwrap().trim(), "5");
+    run_cmd!(rm -f $f).unwrap();
+}
+
+#[test]
+fn test_command_substitution() {
+    assert_eq!(run_fun!(echo $(echo a | tr a b)).unwrap(), "b");
+    assert_eq!(run_fun!(echo x$(echo y)z).unwrap(), "xyz");
+    assert_eq!(
+        run_fun!(echo "got $(printf \"a b\n\n\") here").unwrap(),
+        "got a b here"
+    );
+    assert_eq!(
+        run_fun!(echo "$(echo $(echo nested) | tr a-z A-Z)").unwrap(),
+        "NESTED"
+    );
+    let name = "sub";
+    assert_eq!(run_fun!(echo "x $(echo $name)").unwrap(), "x sub");
+    assert!(run_cmd!(echo $(false)).is_err());
+    assert!(run_fun!(echo "$(ls /nofile)").is_err());
+}
+
+#[test]
+fn test_kill_all() {
+    use std::time::{Duration, Instant};
+    #[export_cmd(wait_killed)]
+    fn wait_killed(env: &mut CmdEnv) -> CmdResult {
+        while !env.is_killed() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        env.write_stdout("killed")
+    }
+    use_custom_cmd!(wait_killed);
+
+    let now = Instant::now();
+    let mut proc = spawn!(sleep 10 | wait_killed | cat).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(proc.kill_all().is_ok());
+    assert_eq!(proc.pids(), vec![]);
+    assert!(now.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_signal() {
+    use std::time::{Duration, Instant};
+    const SIGTERM: i32 = 15;
+    let now = Instant::now();
+    let mut proc = spawn!(sleep 10 | cat).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(proc.signal(SIGTERM).is_ok());
+    assert!(proc.wait().is_err());
+    assert!(now.elapsed() < Duration::from_secs(5));
+
+    let mut proc = spawn!(sleep 10).unwrap();
+    assert!(proc.signal(SIGTERM).is_ok());
+    assert_eq!(proc.wait_with_status().unwrap(), 128 + SIGTERM);
+}
+
+#[test]
+fn test_ignore_in_pipe() {
+    assert!(run_cmd!(echo a | ignore false | cat).is_ok());
+    assert!(run_cmd!(echo a | ignore false).is_ok());
+    assert!(run_cmd!(ignore false | false | cat).is_ok());
+    assert!(run_cmd!(false | ignore true | cat).is_err());
+    assert_eq!(run_fun!(echo a | ignore grep b | wc -l).unwrap().trim(), "0");
+    assert_eq!(run_fun!(echo a | ignore sh -c "cat; exit 3").unwrap(), "a");
+}
+
+#[test]
+fn test_stdin_null() {
+    let cat = |redirect: Option<Redirect>| {
+        let mut cmd = Cmd::default().add_arg("cat");
+        if let Some(redirect) = redirect {
+            cmd = cmd.add_redirect(redirect);
+        }
+        GroupCmds::default()
+            .stdin_null(true)
+            .append(Cmds::default().pipe(cmd).pipe(Cmd::default().add_arg("cat")))
+    };
+    assert_eq!(cat(None).run_fun().unwrap(), "");
+    let redirect = Redirect::StringToStdin("from here-string".into());
+    assert_eq!(cat(Some(redirect)).run_fun().unwrap(), "from here-string");
+    let mut proc = cat(None).spawn_with_output().unwrap();
+    assert_eq!(proc.wait_with_output().unwrap(), "");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_interactive() {
+    let f = "/tmp/cmd_lib_interactive.txt";
+    let stderr_of_last = |interactive: bool| {
+        let cmd = Cmd::default()
+            .add_args(["readlink", "/proc/self/fd/2"])
+            .add_redirect(Redirect::StdoutToFile(f.into(), false));
+        GroupCmds::default()
+            .interactive(interactive)
+            .append(Cmds::default().pipe(Cmd::default().add_arg("true")).pipe(cmd))
+            .run_cmd()
+            .unwrap();
+        std::fs::read_to_string(f).unwrap()
+    };
+    let stderr = std::fs::read_link("/proc/self/fd/2").unwrap();
+    assert_eq!(stderr_of_last(true).trim_end(), stderr.to_str().unwrap());
+    assert!(stderr_of_last(false).starts_with("pipe:"));
+    run_cmd!(rm -f $f).unwrap();
+
+    #[export_cmd(cmd_lib_thread_id)]
+    fn thread_id(env: &mut CmdEnv) -> CmdResult {
+        env.write_stdout(&format!("{:?}", std::thread::current().id()))
+    }
+    use_custom_cmd!(cmd_lib_thread_id);
+    let cmd = Cmd::default()
+        .add_arg("cmd_lib_thread_id")
+        .add_redirect(Redirect::StdoutToFile(f.into(), false));
+    GroupCmds::default()
+        .interactive(true)
+        .append(Cmds::default().pipe(cmd))
+        .run_cmd()
+        .unwrap();
+    let thread_id = format!("{:?}", std::thread::current().id());
+    assert_eq!(std::fs::read_to_string(f).unwrap(), thread_id);
+    run_cmd!(rm -f $f).unwrap();
+}
+
+#[test]
+fn test_stdin_from() {
+    let run = |reader: Box<dyn std::io::Read + Send>, args: &[&str]| {
+        let cmd = Cmd::default().add_args(args).stdin_from(reader);
+        GroupCmds::default()
+            .append(Cmds::default().pipe(cmd))
+            .run_fun()
+    };
+    let reader = std::io::Cursor::new(b"a\nb\n".to_vec());
+    assert_eq!(run(Box::new(reader), &["cat"]).unwrap(), "a\nb");
+    // the endless input stops with the broken pipe after the command exits
+    let reader = std::io::repeat(b'y');
+    assert_eq!(run(Box::new(reader), &["head", "-c", "3"]).unwrap(), "yyy");
+}
+
+#[test]
+#[cfg(all(unix, feature = "pty"))]
+fn test_use_pty() {
+    let is_tty = |use_pty: bool| {
+        let cmd = Cmd::default().add_args(["sh", "-c", "test -t 1 && echo tty; echo done"]);
+        GroupCmds::default()
+            .use_pty(use_pty)
+            .append(Cmds::default().pipe(cmd))
+            .run_fun()
+            .unwrap()
+    };
+    assert_eq!(is_tty(true), "tty\ndone");
+    assert_eq!(is_tty(false), "done");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_new_process_group() {
+    use std::time::Duration;
+    let f = "/tmp/cmd_lib_process_group.pid";
+    run_cmd!(rm -f $f).unwrap();
+    let script = format!("sleep 10 & echo $! > {}; wait", f);
+    let mut proc = GroupCmds::default()
+        .new_process_group(true)
+        .append(Cmds::default().pipe(Cmd::default().add_args(["sh", "-c", &script])))
+        .spawn(false)
+        .unwrap();
+    let pid = loop {
+        match std::fs::read_to_string(f) {
+            Ok(pid) if pid.ends_with('\n') => break pid.trim().to_string(),
+            _ => std::thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    assert!(proc.kill_all().is_ok());
+    std::thread::sleep(Duration::from_millis(100));
+    // the grandchild is killed as well, and it could be a zombie until reaped by init
+    let state = run_fun!(ps -o stat= -p $pid).unwrap_or_default();
+    assert!(state.is_empty() || state.starts_with('Z'));
+    run_cmd!(rm -f $f).unwrap();
+}
+
+#[test]
+fn test_builtin_sleep() {
+    use std::time::{Duration, Instant};
+    // registering it as "sleep" would affect other tests running in parallel
+    #[export_cmd(builtin_sleep)]
+    fn my_sleep(env: &mut CmdEnv) -> CmdResult {
+        builtin_sleep(env)
+    }
+    use_custom_cmd!(builtin_sleep);
+
+    let now = Instant::now();
+    assert!(run_cmd!(builtin_sleep 0.2).is_ok());
+    assert!(run_cmd!(builtin_sleep 100ms).is_ok());
+    assert!(run_cmd!(builtin_sleep 0.1s).is_ok());
+    assert!(now.elapsed() >= Duration::from_millis(400));
+    assert!(run_cmd!(builtin_sleep).is_err());
+    assert!(run_cmd!(builtin_sleep -1).is_err());
+    assert!(run_cmd!(builtin_sleep abc).is_err());
+
+    let now = Instant::now();
+    let mut proc = spawn!(builtin_sleep 10 | cat).unwrap();
+    assert!(proc.wait_timeout(Duration::from_millis(100)).is_err());
+    assert!(now.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_fun_children_lines() {
+    let lines: Vec<String> = spawn_with_output!(printf "a\nb\nc")
+        .unwrap()
+        .lines()
+        .collect::<std::io::Result<_>>()
+        .unwrap();
+    assert_eq!(lines, vec!["a", "b", "c"]);
+
+    let mut lines = spawn_with_output!(seq 1 1000000).unwrap().lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "1");
+    assert_eq!(lines.next().unwrap().unwrap(), "2");
+    drop(lines);
+
+    let mut lines = spawn_with_output!(sh -c "echo ok; exit 1").unwrap().lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "ok");
+    assert!(lines.next().unwrap().is_err());
+    assert!(lines.next().is_none());
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn test_wait_with_json() {
+    let value: serde_json::Value = spawn_with_output!(echo r#"{"name": "cmd_lib", "tags": [1, 2]}"#)
+        .unwrap()
+        .wait_with_json()
+        .unwrap();
+    assert_eq!(value["name"], "cmd_lib");
+    assert_eq!(value["tags"][1], 2);
+
+    let tags: Vec<u32> = spawn_with_output!(echo "[1, 2, 3]")
+        .unwrap()
+        .wait_with_json()
+        .unwrap();
+    assert_eq!(tags, [1, 2, 3]);
+
+    let err = spawn_with_output!(echo "not json")
+        .unwrap()
+        .wait_with_json::<serde_json::Value>()
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("not json"));
+    assert!(spawn_with_output!(false)
+        .unwrap()
+        .wait_with_json::<serde_json::Value>()
+        .is_err());
+}
+
+#[test]
+fn test_fun_children_reader() {
+    use std::io::Read;
+    let f = "/tmp/cmd_lib_fun_reader.txt";
+    let mut reader = spawn_with_output!(seq 1 100000).unwrap().into_reader();
+    let mut file = std::fs::File::create(f).unwrap();
+    assert_eq!(std::io::copy(&mut reader, &mut file).unwrap(), 588895);
+    assert_eq!(run_fun!(tail -n 1 $f).unwrap(), "100000");
+    run_cmd!(rm -f $f).unwrap();
+
+    let mut buf = [0; 4];
+    let mut reader = spawn_with_output!(yes).unwrap().into_reader();
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"y\ny\n");
+    drop(reader);
+
+    let mut out = String::new();
+    let mut reader = spawn_with_output!(sh -c "echo ok; exit 1").unwrap().into_reader();
+    assert!(reader.read_to_string(&mut out).is_err());
+    assert_eq!(out, "ok\n");
+}
+
+#[test]
+fn test_glob_expansion() {
+    let dir = "/tmp/cmd_lib_glob_test";
+    run_cmd! {
+        rm -rf $dir;
+        mkdir -p $dir;
+        touch $dir/a.txt $dir/b.txt $dir/c.log $dir/.hidden.txt;
+    }
+    .unwrap();
+    assert_eq!(run_fun!(cd $dir; echo *.txt).unwrap(), "a.txt b.txt");
+    assert_eq!(run_fun!(cd $dir; echo ?.log).unwrap(), "c.log");
+    assert_eq!(run_fun!(cd $dir; echo [ac].*).unwrap(), "a.txt c.log");
+    assert_eq!(run_fun!(echo $dir/?.log).unwrap(), format!("{}/c.log", dir));
+    // no match or quoted, no expansion
+    assert_eq!(run_fun!(cd $dir; echo *.none).unwrap(), "*.none");
+    assert_eq!(run_fun!(cd $dir; echo "*.txt").unwrap(), "*.txt");
+    // wildcards in the variables are not expanded
+    let (sub, star) = (format!("{}/[a]", dir), format!("{}/s*", dir));
+    run_cmd! {
+        mkdir $sub $dir/a $star $dir/sx;
+        touch $sub/x.txt $dir/a/y.txt $star/z.txt $dir/sx/w.txt;
+    }
+    .unwrap();
+    assert_eq!(run_fun!(echo $sub/?.txt).unwrap(), format!("{}/x.txt", sub));
+    assert_eq!(run_fun!(echo $star/?.txt).unwrap(), format!("{}/z.txt", star));
+    assert_eq!(run_fun!(echo $sub/?.none).unwrap(), format!("{}/?.none", sub));
+    run_cmd!(rm -rf $dir).unwrap();
+}
+
+#[test]
+fn test_wait_with_status() {
+    assert_eq!(spawn!(true).unwrap().wait_with_status().unwrap(), 0);
+    assert_eq!(spawn!(sh -c "exit 3").unwrap().wait_with_status().unwrap(), 3);
+    assert_eq!(spawn!(false | true).unwrap().wait_with_status().unwrap(), 0);
+    assert_eq!(spawn!(true | false).unwrap().wait_with_status().unwrap(), 1);
+    assert_eq!(
+        spawn!(sh -c "kill -9 $$$$").unwrap().wait_with_status().unwrap(),
+        128 + 9
+    );
+    assert!(spawn!(no_such_cmd_xxx)
+        .and_then(|mut proc| proc.wait_with_status())
+        .is_err());
+}
+
+#[test]
+fn test_wait_with_statuses() {
+    let statuses = spawn!(echo a | sh -c "cat; exit 3" | grep -q b | true)
+        .unwrap()
+        .wait_with_statuses()
+        .unwrap();
+    let expected = vec![
+        ("echo a".to_string(), Some(0)),
+        ("sh -c 'cat; exit 3'".to_string(), Some(3)),
+        ("grep -q b".to_string(), Some(1)),
+        ("true".to_string(), Some(0)),
+    ];
+    assert_eq!(statuses, expected);
+
+    let statuses = spawn!(true | no_such_cmd_xxx)
+        .unwrap()
+        .wait_with_statuses()
+        .unwrap();
+    assert_eq!(statuses[0], ("true".to_string(), Some(0)));
+    assert_eq!(statuses[1], ("no_such_cmd_xxx".to_string(), None));
+}
+
+#[test]
+fn test_builtin_printf() {
+    // registering it as "printf" would affect other tests running in parallel
+    #[export_cmd(builtin_printf)]
+    fn my_printf(env: &mut CmdEnv) -> CmdResult {
+        builtin_printf(env)
+    }
+    use_custom_cmd!(builtin_printf);
+
+    assert_eq!(
+        run_fun!(builtin_printf "%s=%d\n" key 42).unwrap(),
+        "key=42"
+    );
+    assert_eq!(run_fun!(builtin_printf r"a\tb%%").unwrap(), "a\tb%");
+    assert_eq!(
+        run_fun!(builtin_printf "[%5s|%-3s|%04d|%x]" ab c "-7" 255).unwrap(),
+        "[   ab|c  |-007|ff]"
+    );
+    // best-effort output for mismatched arguments
+    assert_eq!(run_fun!(builtin_printf "%s-%s" a).unwrap(), "a-");
+    assert_eq!(run_fun!(builtin_printf "%s" a b).unwrap(), "a");
+    assert!(run_fun!(builtin_printf "%d" abc).is_err());
+    let output = spawn_with_output!(builtin_printf "a=%q b=%s" x y)
+        .unwrap()
+        .wait_with_all()
+        .unwrap();
+    assert_eq!(output.stdout, "a=");
+    assert!(output.stderr.contains("invalid format specifier"));
+    assert!(run_fun!(builtin_printf).is_err());
+}
+
+#[test]
+fn test_redirect_append_stdout_stderr() {
+    let f = "/tmp/cmd_lib_append_both.log";
+    run_cmd!(rm -f $f).unwrap();
+    assert!(run_cmd!(sh -c "echo out1; echo err1 >&2" &>> $f).is_ok());
+    assert!(run_cmd!(sh -c "echo out2; echo err2 >&2" &>> $f).is_ok());
+    assert_eq!(run_fun!(cat $f).unwrap(), "out1\nerr1\nout2\nerr2");
+    assert!(run_cmd!(sh -c "echo out3; echo err3 >&2" &> $f).is_ok());
+    assert_eq!(run_fun!(cat $f).unwrap(), "out3\nerr3");
+    run_cmd!(rm -f $f).unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn test_redirect_other_fds() {
+    let f = "/tmp/cmd_lib_redirect_fds.log";
+    run_cmd!(sh -c "echo status >&3" 3> $f).unwrap();
+    assert_eq!(run_fun!(cat $f).unwrap(), "status");
+    run_cmd!(sh -c "echo more >&4" 4>>$f).unwrap();
+    assert_eq!(run_fun!(cat $f).unwrap(), "status\nmore");
+    assert_eq!(run_fun!(sh -c "echo out >&5" 5>&1).unwrap(), "out");
+    run_cmd!(sh -c "echo err >&2" 3> $f 2>&3).unwrap();
+    assert_eq!(run_fun!(cat $f).unwrap(), "err");
+    assert_eq!(run_fun!(echo 3 > $f; cat $f).unwrap(), "3");
+    // not opened yet
+    assert!(run_cmd!(sh -c "echo err >&2" 2>&3).is_err());
+    run_cmd!(rm -f $f).unwrap();
+}
+
+#[test]
+fn test_redirect_order() {
+    let f = "/tmp/cmd_lib_redirect_order.log";
+    // both streams go to the file
+    let out = run_fun!(sh -c "echo out; echo err >&2" > $f 2>&1).unwrap();
+    assert_eq!(out, "");
+    assert_eq!(run_fun!(cat $f).unwrap(), "out\nerr");
+    // stderr goes to the previous stdout, only stdout goes to the file
+    let out = run_fun!(sh -c "echo out; echo err >&2" 2>&1 > $f).unwrap();
+    assert_eq!(out, "err");
+    assert_eq!(run_fun!(cat $f).unwrap(), "out");
+    // same for the builtin commands running in threads
+    #[export_cmd(out_err_cmd)]
+    fn out_err_cmd(env: &mut CmdEnv) -> CmdResult {
+        env.write_stdout("out\n")?;
+        env.write_stderr("err\n")
+    }
+    use_custom_cmd!(out_err_cmd);
+    let out = run_fun!(out_err_cmd 2>&1 > $f).unwrap();
+    assert_eq!(out, "err");
+    assert_eq!(run_fun!(cat $f).unwrap(), "out");
+    let out = run_fun!(out_err_cmd > $f 2>&1).unwrap();
+    assert_eq!(out, "");
+    assert_eq!(run_fun!(cat $f).unwrap(), "out\nerr");
+    run_cmd!(rm -f $f).unwrap();
+}
+
+#[test]
+fn test_cmd_env_stdin() {
+    #[export_cmd(upper_cmd)]
+    fn upper_cmd(env: &mut CmdEnv) -> CmdResult {
+        let input = env.stdin_string()?;
+        env.write_stdout(&input.to_uppercase())
+    }
+    #[export_cmd(count_bytes_cmd)]
+    fn count_bytes_cmd(env: &mut CmdEnv) -> CmdResult {
+        let len = env.stdin_bytes()?.len();
+        env.write_stdout(&len.to_string())
+    }
+    use_custom_cmd!(upper_cmd, count_bytes_cmd);
+    assert_eq!(run_fun!(echo abc | upper_cmd).unwrap(), "ABC");
+    assert_eq!(run_fun!(upper_cmd <<< "xyz").unwrap(), "XYZ");
+    assert_eq!(run_fun!(printf "a\nb" | count_bytes_cmd).unwrap(), "3");
+    assert_eq!(run_fun!(count_bytes_cmd < /dev/null).unwrap(), "0");
+}
+
+#[test]
+fn test_builtin_cat() {
+    // registering it as "cat" would affect other tests running in parallel
+    #[export_cmd(builtin_cat)]
+    fn my_cat(env: &mut CmdEnv) -> CmdResult {
+        builtin_cat(env)
+    }
+    use_custom_cmd!(builtin_cat);
+
+    let dir = "/tmp/cmd_lib_builtin_cat";
+    run_cmd! {
+        rm -rf $dir;
+        mkdir -p $dir;
+        echo aaa > $dir/a.txt;
+        echo bbb > $dir/b.txt;
+    }
+    .unwrap();
+    assert_eq!(run_fun!(echo hi | builtin_cat).unwrap(), "hi");
+    assert_eq!(
+        run_fun!(builtin_cat $dir/a.txt $dir/b.txt).unwrap(),
+        "aaa\nbbb"
+    );
+    assert_eq!(run_fun!(cd $dir; builtin_cat b.txt).unwrap(), "bbb");
+    assert_eq!(
+        run_fun!(echo hi | builtin_cat $dir/a.txt - $dir/b.txt).unwrap(),
+        "aaa\nhi\nbbb"
+    );
+    // keep going after errors
+    assert!(run_cmd!(builtin_cat $dir/a.txt $dir/none.txt $dir/b.txt > $dir/out.txt).is_err());
+    assert_eq!(run_fun!(builtin_cat $dir/out.txt).unwrap(), "aaa\nbbb");
+    run_cmd!(rm -rf $dir).unwrap();
+}
+
+#[test]
+/// ```compile_fail
+/// run_cmd!(&).unwrap();
+/// run_cmd!(ls & ls).unwrap();
+/// run_cmd!(ls; &).unwrap();
+/// spawn!(ls &).unwrap();
+/// run_cmd!(echo $(ls &)).unwrap();
+/// ```
+fn test_background_job() {
+    use std::time::{Duration, Instant};
+    let now = Instant::now();
+    let mut proc = run_cmd!(sleep 10 &).unwrap();
+    assert!(now.elapsed() < Duration::from_secs(5));
+    assert!(proc.kill_all().is_ok());
+
+    let mut proc = run_cmd!(true | false&).unwrap();
+    assert!(proc.wait().is_err());
+    let mut proc = run_fun!(echo hi | tr a-z A-Z &).unwrap();
+    assert_eq!(proc.wait_with_output().unwrap(), "HI");
+}
+
+#[test]
+fn test_builtin_test() {
+    use_builtin_cmd!(test, echo);
+    let dir = "/tmp/cmd_lib_builtin_test";
+    run_cmd!(mkdir -p $dir; touch $dir/f.txt).unwrap();
+    assert!(run_cmd!(test -d $dir).is_ok());
+    assert!(run_cmd!([ -f $dir/f.txt ]).is_ok());
+    assert!(run_cmd!([ -e $dir/none ]).is_err());
+    assert!(run_cmd!([ ! -e $dir/none ]).is_ok());
+    assert!(run_cmd!(cd $dir; [ -f f.txt ]).is_ok());
+    assert_eq!(
+        run_fun!([ -f $dir/none ] || echo missing).unwrap(),
+        "missing"
+    );
+    assert_eq!(run_fun!([ -d $dir ] && echo found).unwrap(), "found");
+
+    let s = "";
+    assert!(run_cmd!([ -z "$s" ]).is_ok());
+    assert!(run_cmd!(test -n "$s").is_err());
+    assert!(run_cmd!([ "$s" ]).is_err());
+    assert!(run_cmd!([ abc = abc ]).is_ok());
+    assert!(run_cmd!([ abc != abc ]).is_err());
+    assert!(run_cmd!([ -x a b c ]).is_err());
+    run_cmd!(rm -rf $dir).unwrap();
+}
+
+#[test]
+fn test_export_cmd_exit_code() {
+    #[export_cmd(exit_with)]
+    fn exit_with(env: &mut CmdEnv) -> std::io::Result<i32> {
+        Ok(env.args()[1].parse().unwrap())
+    }
+    use_custom_cmd!(exit_with);
+    use_builtin_cmd!(echo);
+
+    assert!(run_cmd!(exit_with 0).is_ok());
+    assert!(run_cmd!(exit_with 2).is_err());
+    assert_eq!(run_fun!(exit_with 1 || echo failed).unwrap(), "failed");
+    assert!(run_fun!(exit_with 1 && echo ok).is_err());
+    assert_eq!(spawn!(exit_with 5).unwrap().wait_with_status().unwrap(), 5);
+    assert!(spawn_with_output!(exit_with 0 | exit_with 7)
+        .unwrap()
+        .wait_with_output()
+        .is_err());
+}
+
+#[test]
+fn test_builtin_echo() {
+    use_builtin_cmd!(echo);
+    assert_eq!(run_fun!(echo a b | wc -c).unwrap().trim(), "4");
+    assert_eq!(run_fun!(echo -n a b | wc -c).unwrap().trim(), "3");
+    assert_eq!(run_fun!(echo -e "a\\tb\\nc").unwrap(), "a\tb\nc");
+    assert_eq!(run_fun!(echo "a\\tb").unwrap(), "a\\tb");
+    assert_eq!(run_fun!(echo -ne "a\\n" | wc -c).unwrap().trim(), "2");
+    assert_eq!(run_fun!(echo -e -E "a\\tb").unwrap(), "a\\tb");
+    assert_eq!(run_fun!(echo -x -n).unwrap(), "-x -n");
+    assert_eq!(run_fun!(echo -).unwrap(), "-");
+}
+
+#[test]
+fn test_builtin_head() {
+    // registering it as "head" would affect other tests running in parallel
+    #[export_cmd(builtin_head)]
+    fn my_head(env: &mut CmdEnv) -> CmdResult {
+        builtin_head(env)
+    }
+    use_custom_cmd!(builtin_head);
+    use_builtin_cmd!(seq);
+    assert_eq!(run_fun!(seq 1 20 | builtin_head).unwrap(), run_fun!(seq 1 10).unwrap());
+    assert_eq!(run_fun!(seq 1 20 | builtin_head -n 3).unwrap(), "1\n2\n3");
+    assert_eq!(run_fun!(seq 1 20 | builtin_head -2).unwrap(), "1\n2");
+    assert_eq!(run_fun!(seq 1 2 | builtin_head -n 5).unwrap(), "1\n2");
+    assert_eq!(run_fun!(seq 1 2 | builtin_head -n 0).unwrap(), "");
+
+    let f = "/tmp/cmd_lib_builtin_head.txt";
+    run_cmd!(seq 1 5 > $f).unwrap();
+    assert_eq!(run_fun!(builtin_head -n2 $f).unwrap(), "1\n2");
+    assert!(run_cmd!(builtin_head -n x $f).is_err());
+    assert!(run_cmd!(builtin_head /no/such/file).is_err());
+    run_cmd!(rm -f $f).unwrap();
+
+    // the infinite upstream commands stop on the broken pipe
+    assert_eq!(run_fun!(ignore yes | builtin_head -n 3).unwrap(), "y\ny\ny");
+    assert_eq!(
+        run_fun!(ignore seq 1 100000000 | builtin_head -n 2).unwrap(),
+        "1\n2"
+    );
+}
+
+#[test]
+fn test_builtin_tail() {
+    use std::time::Duration;
+    // registering it as "tail" would affect other tests running in parallel
+    #[export_cmd(builtin_tail)]
+    fn my_tail(env: &mut CmdEnv) -> CmdResult {
+        builtin_tail(env)
+    }
+    use_custom_cmd!(builtin_tail);
+    use_builtin_cmd!(seq);
+    assert_eq!(run_fun!(seq 1 20 | builtin_tail).unwrap(), run_fun!(seq 11 20).unwrap());
+    assert_eq!(run_fun!(seq 1 20 | builtin_tail -n 2).unwrap(), "19\n20");
+    assert_eq!(run_fun!(seq 1 2 | builtin_tail -5).unwrap(), "1\n2");
+    assert_eq!(run_fun!(seq 1 2 | builtin_tail -n 0).unwrap(), "");
+
+    let f = "/tmp/cmd_lib_builtin_tail.txt";
+    let out = "/tmp/cmd_lib_builtin_tail.out";
+    run_cmd!(seq 1 5 > $f).unwrap();
+    assert_eq!(run_fun!(builtin_tail -n2 $f).unwrap(), "4\n5");
+    assert!(run_cmd!(builtin_tail /no/such/file).is_err());
+
+    let mut proc = spawn!(builtin_tail -n 1 -f $f > $out).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    run_cmd!(seq 6 7 >> $f).unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(proc.kill_all().is_ok());
+    assert_eq!(run_fun!(cat $out).unwrap(), "5\n6\n7");
+    run_cmd!(rm -f $f $out).unwrap();
+}
+
+#[test]
+fn test_builtin_xargs() {
+    // registering it as "xargs" would affect other tests running in parallel
+    #[export_cmd(builtin_xargs)]
+    fn my_xargs(env: &mut CmdEnv) -> std::io::Result<i32> {
+        builtin_xargs(env)
+    }
+    use_custom_cmd!(builtin_xargs);
+    use_builtin_cmd!(echo);
+
+    assert_eq!(
+        run_fun!(echo "a b\nc" | builtin_xargs -n 1 echo).unwrap(),
+        "a\nb\nc"
+    );
+    assert_eq!(
+        run_fun!(echo "a b\nc" | builtin_xargs -n 2 echo x).unwrap(),
+        "x a b\nx c"
+    );
+    assert_eq!(run_fun!(echo "a b c" | builtin_xargs).unwrap(), "a b c");
+    assert_eq!(run_fun!(echo "" | builtin_xargs echo x).unwrap(), "");
+    assert_eq!(
+        run_fun!(cd /; echo tmp | builtin_xargs ls -d).unwrap(),
+        "tmp"
+    );
+    assert!(run_cmd!(echo "1 2" | builtin_xargs -n 1 sh -c "exit 3").is_err());
+    assert!(run_cmd!(echo a | builtin_xargs -n 0 echo).is_err());
+}
+
+#[test]
+fn test_here_doc() {
+    let name = "cmd_lib";
+    let f = "/tmp/cmd_lib_here_doc.txt";
+    run_cmd!(cat > $f << {
+        [package]
+        name = "$name"
+
+          indented = true
+    })
+    .unwrap();
+    assert_eq!(
+        run_fun!(cat $f).unwrap(),
+        "[package]\nname = \"cmd_lib\"\n\n  indented = true"
+    );
+    assert_eq!(run_fun!(wc -l << { one line }).unwrap().trim(), "1");
+    assert_eq!(
+        run_fun!(cat <<"a\nb $name").unwrap(),
+        "a\nb cmd_lib"
+    );
+    assert_eq!(run_fun!(cat << "no newline" | wc -c).unwrap().trim(), "10");
+    run_cmd!(rm -f $f).unwrap();
+}
+
+#[test]
+fn test_redirect_stdin_bytes() {
+    let input = b"hello\0world\n".to_vec();
+    assert_eq!(run_fun!(wc -c < $input).unwrap().trim(), "12");
+    let data: &[u8] = b"a\nb\nc\n";
+    assert_eq!(run_fun!(tail -n 1 < ${data}).unwrap(), "c");
+    assert_eq!(run_fun!(cat < $data | wc -l).unwrap().trim(), "3");
+
+    // other variables are still the file paths
+    let f = "/tmp/cmd_lib_stdin_bytes.txt";
+    run_cmd!(echo from file > $f).unwrap();
+    assert_eq!(run_fun!(cat < $f).unwrap(), "from file");
+    run_cmd!(rm -f $f).unwrap();
+}
+
+#[test]
+fn test_cmd_env_run() {
+    #[export_cmd(run_args)]
+    fn run_args(env: &mut CmdEnv) -> CmdResult {
+        let args = env.args()[1..].to_vec();
+        env.run(args)
+    }
+    use_custom_cmd!(run_args);
+
+    assert_eq!(run_fun!(echo abc | run_args cat).unwrap(), "abc");
+    assert_eq!(run_fun!(X=1 run_args sh -c "echo $$X").unwrap(), "1");
+    let dir = std::env::temp_dir().canonicalize().unwrap();
+    assert_eq!(
+        run_fun!(cd $dir; run_args pwd).unwrap(),
+        dir.to_string_lossy()
+    );
+    // running itself again
+    assert_eq!(run_fun!(run_args run_args echo hi).unwrap(), "hi");
+    assert!(run_cmd!(run_args false).is_err());
+    assert!(run_cmd!(run_args).is_err());
+}
+
+#[test]
+fn test_builtin_env() {
+    // registering it as "env" would affect other tests running in parallel
+    #[export_cmd(builtin_env)]
+    fn my_env(env: &mut CmdEnv) -> CmdResult {
+        builtin_env(env)
+    }
+    use_custom_cmd!(builtin_env);
+
+    assert!(run_fun!(builtin_env | grep "^PATH=").is_ok());
+    assert_eq!(
+        run_fun!(CMD_LIB_ENV_A=1 builtin_env | grep CMD_LIB_ENV_).unwrap(),
+        "CMD_LIB_ENV_A=1"
+    );
+    assert_eq!(
+        run_fun!(A=1 builtin_env B=x=y sh -c "echo $$A $$B").unwrap(),
+        "1 x=y"
+    );
+    assert!(run_cmd!(builtin_env false).is_err());
+    assert!(run_cmd!(builtin_env -i).is_err());
+
+    std::env::set_var("CMD_LIB_ENV_UNSET", "1");
+    assert_eq!(
+        run_fun!(builtin_env | grep CMD_LIB_ENV_UNSET).unwrap(),
+        "CMD_LIB_ENV_UNSET=1"
+    );
+    assert!(run_fun!(unset CMD_LIB_ENV_UNSET; builtin_env | grep CMD_LIB_ENV_UNSET).is_err());
+    assert_eq!(
+        run_fun!(unset CMD_LIB_ENV_UNSET; CMD_LIB_ENV_UNSET=2 builtin_env | grep CMD_LIB_ENV_UNSET)
+            .unwrap(),
+        "CMD_LIB_ENV_UNSET=2"
+    );
+}
+
+#[test]
+fn test_export() {
+    assert_eq!(
+        run_fun!(export CMD_LIB_EXPORT_A=1 CMD_LIB_EXPORT_B=2; sh -c "echo $$CMD_LIB_EXPORT_A$$CMD_LIB_EXPORT_B").unwrap(),
+        "12"
+    );
+    assert_eq!(
+        run_fun!(export CMD_LIB_EXPORT_A=1; CMD_LIB_EXPORT_A=2 sh -c "echo $$CMD_LIB_EXPORT_A").unwrap(),
+        "2"
+    );
+    assert_eq!(
+        run_fun!(
+            export CMD_LIB_EXPORT_A=1;
+            export CMD_LIB_EXPORT_A=3;
+            echo hi | sh -c "cat; echo $$CMD_LIB_EXPORT_A"
+        )
+        .unwrap(),
+        "hi\n3"
+    );
+    // not kept for the following macro blocks
+    assert_eq!(
+        run_fun!(sh -c "echo x$$CMD_LIB_EXPORT_A").unwrap(),
+        "x"
+    );
+    assert!(run_cmd!(export).is_err());
+    assert!(run_cmd!(export CMD-LIB=1).is_err());
+}
+
+#[test]
+fn test_unset() {
+    std::env::set_var("CMD_LIB_UNSET_A", "1");
+    assert!(run_cmd!(printenv CMD_LIB_UNSET_A > /dev/null).is_ok());
+    assert!(run_cmd!(unset CMD_LIB_UNSET_A; printenv CMD_LIB_UNSET_A).is_err());
+    assert_eq!(
+        run_fun!(export CMD_LIB_UNSET_B=2; unset CMD_LIB_UNSET_B; sh -c "echo x$$CMD_LIB_UNSET_B")
+            .unwrap(),
+        "x"
+    );
+    assert_eq!(
+        run_fun!(unset CMD_LIB_UNSET_A; CMD_LIB_UNSET_A=3 printenv CMD_LIB_UNSET_A).unwrap(),
+        "3"
+    );
+    assert!(run_cmd!(unset).is_err());
+    assert!(run_cmd!(unset CMD-LIB).is_err());
+}
+
+#[test]
+fn test_read() {
+    assert_eq!(
+        run_fun!(read CMD_LIB_READ_A CMD_LIB_READ_B << "1  2 3"; printenv CMD_LIB_READ_A CMD_LIB_READ_B)
+            .unwrap(),
+        "1\n2 3"
+    );
+    assert_eq!(
+        run_fun!(read CMD_LIB_READ_A CMD_LIB_READ_B << " 1 "; printenv CMD_LIB_READ_A CMD_LIB_READ_B)
+            .unwrap(),
+        "1\n"
+    );
+    assert_eq!(
+        run_fun!(printf "a\nb\n" | read CMD_LIB_READ_A; printenv CMD_LIB_READ_A).unwrap(),
+        "a"
+    );
+    assert!(run_cmd!(read CMD_LIB_READ_A < /dev/null).is_err());
+    assert!(run_cmd!(read << "1").is_err());
+    assert!(run_cmd!(read CMD-LIB << "1").is_err());
+}
+
+#[test]
+fn test_builtin_wc() {
+    // registering it as "wc" would affect other tests running in parallel
+    #[export_cmd(builtin_wc)]
+    fn my_wc(env: &mut CmdEnv) -> CmdResult {
+        builtin_wc(env)
+    }
+    use_custom_cmd!(builtin_wc);
+
+    assert_eq!(run_fun!(echo rust | builtin_wc -c).unwrap(), "5");
+    assert_eq!(run_fun!(echo "a b\nc" | builtin_wc -lw).unwrap(), "2 3");
+    assert_eq!(run_fun!(echo "a b\nc" | builtin_wc -wl).unwrap(), "2 3");
+    assert_eq!(run_fun!(builtin_wc << "no newline").unwrap(), " 0  2 10");
+
+    let dir = std::env::temp_dir().join("cmd_lib_test_builtin_wc");
+    let (a, b) = (dir.join("a"), dir.join("b"));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(&a, "one\ntwo three\n").unwrap();
+    std::fs::write(&b, "four\n").unwrap();
+    assert_eq!(
+        run_fun!(cd $dir; builtin_wc -l a b).unwrap(),
+        "2 a\n1 b\n3 total"
+    );
+    assert_eq!(run_fun!(builtin_wc $a).unwrap(), format!(" 2  3 14 {}", a.display()));
+    assert!(run_cmd!(builtin_wc -l $dir/nofile $b).is_err());
+    assert!(run_cmd!(builtin_wc -x).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_builtin_basename_dirname() {
+    // registering them as "basename" and "dirname" would affect other tests running in parallel
+    #[export_cmd(builtin_basename)]
+    fn my_basename(env: &mut CmdEnv) -> CmdResult {
+        builtin_basename(env)
+    }
+    #[export_cmd(builtin_dirname)]
+    fn my_dirname(env: &mut CmdEnv) -> CmdResult {
+        builtin_dirname(env)
+    }
+    use_custom_cmd!(builtin_basename, builtin_dirname);
+
+    assert_eq!(run_fun!(builtin_basename /a/b/c.txt).unwrap(), "c.txt");
+    assert_eq!(run_fun!(builtin_basename /a/b/c.txt .txt).unwrap(), "c");
+    assert_eq!(run_fun!(builtin_basename /a/b/.txt .txt).unwrap(), ".txt");
+    assert_eq!(run_fun!(builtin_basename "/a/b//").unwrap(), "b");
+    assert_eq!(run_fun!(builtin_basename c.txt).unwrap(), "c.txt");
+    assert_eq!(run_fun!(builtin_basename "///").unwrap(), "/");
+    assert!(run_cmd!(builtin_basename).is_err());
+    assert!(run_cmd!(builtin_basename a b c).is_err());
+
+    assert_eq!(run_fun!(builtin_dirname /a/b/c.txt).unwrap(), "/a/b");
+    assert_eq!(run_fun!(builtin_dirname "/a/b//c//").unwrap(), "/a/b");
+    assert_eq!(run_fun!(builtin_dirname /a).unwrap(), "/");
+    assert_eq!(run_fun!(builtin_dirname "///").unwrap(), "/");
+    assert_eq!(run_fun!(builtin_dirname c.txt a/b).unwrap(), ".\na");
+    assert!(run_cmd!(builtin_dirname).is_err());
+}
+
+#[test]
+#[cfg(feature = "date")]
+fn test_builtin_date() {
+    // registering it as "date" would affect other tests running in parallel
+    #[export_cmd(builtin_date)]
+    fn my_date(env: &mut CmdEnv) -> CmdResult {
+        builtin_date(env)
+    }
+    use_custom_cmd!(builtin_date);
+
+    let day = run_fun!(builtin_date -u +%Y%m%d).unwrap();
+    assert_eq!(day.len(), 8);
+    assert!(day.chars().all(|c| c.is_ascii_digit()));
+    #[cfg(unix)]
+    assert_eq!(day, run_fun!(date -u +%Y%m%d).unwrap());
+    assert_eq!(run_fun!(builtin_date -u +%Z).unwrap(), "UTC");
+    assert!(run_fun!(builtin_date).unwrap().ends_with(&day[..4]));
+    assert!(run_cmd!(builtin_date "+%Y%").is_err());
+    assert!(run_cmd!(builtin_date -x).is_err());
+}
+
+#[test]
+fn test_builtin_which() {
+    // registering it as "which" would affect other tests running in parallel
+    #[export_cmd(builtin_which)]
+    fn my_which(env: &mut CmdEnv) -> std::io::Result<i32> {
+        builtin_which(env)
+    }
+    use_custom_cmd!(builtin_which);
+
+    let sh = run_fun!(builtin_which sh).unwrap();
+    assert!(sh.ends_with("/sh"));
+    assert_eq!(run_fun!(builtin_which $sh).unwrap(), sh);
+    assert_eq!(
+        run_fun!(builtin_which builtin_which).unwrap(),
+        "builtin_which: shell builtin"
+    );
+    assert!(run_fun!(PATH=/nonexistent builtin_which sh).is_err());
+    let dir = "/tmp/cmd_lib_which";
+    run_cmd!(mkdir -p $dir; touch $dir/not_exec).unwrap();
+    assert!(run_fun!(PATH=$dir builtin_which not_exec).is_err());
+    run_cmd!(chmod +x $dir/not_exec).unwrap();
+    assert_eq!(
+        run_fun!(PATH=$dir builtin_which not_exec).unwrap(),
+        format!("{}/not_exec", dir)
+    );
+    let output = spawn_with_output!(builtin_which sh cmd_lib_no_such_program)
+        .unwrap()
+        .wait_with_all()
+        .unwrap();
+    assert_eq!(output.stdout, format!("{}\n", sh));
+    assert_eq!(output.stderr, "cmd_lib_no_such_program: not found\n");
+    run_cmd!(rm -rf $dir).unwrap();
+}
+
+#[test]
+fn test_builtin_file_ops() {
+    // registering them as "cp", "mv" and "rm" would affect other tests running in parallel
+    #[export_cmd(builtin_cp)]
+    fn my_cp(env: &mut CmdEnv) -> CmdResult {
+        builtin_cp(env)
+    }
+    #[export_cmd(builtin_mv)]
+    fn my_mv(env: &mut CmdEnv) -> CmdResult {
+        builtin_mv(env)
+    }
+    #[export_cmd(builtin_rm)]
+    fn my_rm(env: &mut CmdEnv) -> CmdResult {
+        builtin_rm(env)
+    }
+    use_custom_cmd!(builtin_cp, builtin_mv, builtin_rm);
+
+    let dir = "/tmp/cmd_lib_file_ops";
+    run_cmd! {
+        rm -rf $dir;
+        mkdir -p $dir/src/sub;
+        echo a > $dir/src/a.txt;
+        echo b > $dir/src/sub/b.txt;
+        cd $dir;
+        builtin_cp src/a.txt c.txt;
+        builtin_cp -r src dst;
+        builtin_mv c.txt d.txt;
+        builtin_mv d.txt dst;
+    }
+    .unwrap();
+    assert_eq!(
+        run_fun!(cat $dir/dst/a.txt $dir/dst/sub/b.txt $dir/dst/d.txt).unwrap(),
+        "a\nb\na"
+    );
+    assert!(run_cmd!(test -e $dir/c.txt || test -e $dir/d.txt).is_err());
+
+    assert!(run_cmd!(builtin_cp $dir/src $dir/dst2 2>/dev/null).is_err());
+    assert!(run_cmd!(builtin_cp $dir/no_file $dir/dst 2>/dev/null).is_err());
+    assert!(run_cmd!(builtin_rm $dir/src 2>/dev/null).is_err());
+    assert!(run_cmd!(builtin_rm $dir/no_file 2>/dev/null).is_err());
+    assert!(run_cmd!(builtin_rm -f $dir/no_file).is_ok());
+    assert!(run_cmd!(builtin_rm -r $dir/src $dir/dst/a.txt).is_ok());
+    assert_eq!(run_fun!(ls $dir/dst).unwrap(), "d.txt\nsub");
+    run_cmd!(builtin_rm -rf $dir).unwrap();
+    assert!(run_cmd!(test -e $dir).is_err());
+}
+
+#[test]
+fn test_is_builtin() {
+    #[export_cmd(cmd_lib_is_builtin)]
+    fn foo(_env: &mut CmdEnv) -> CmdResult {
+        Ok(())
+    }
+    assert!(!is_builtin("cmd_lib_is_builtin"));
+    use_custom_cmd!(cmd_lib_is_builtin);
+    assert!(is_builtin("cmd_lib_is_builtin"));
+    assert!(!is_builtin("cmd_lib_no_such_cmd"));
+}
+
+#[test]
+fn test_registered_builtins() {
+    #[export_cmd(cmd_lib_registered_b)]
+    fn b(_env: &mut CmdEnv) -> CmdResult {
+        Ok(())
+    }
+    #[export_cmd(cmd_lib_registered_a)]
+    fn a(_env: &mut CmdEnv) -> CmdResult {
+        Ok(())
+    }
+    use_custom_cmd!(cmd_lib_registered_b, cmd_lib_registered_a);
+    let names = registered_builtins();
+    let mut sorted = names.clone();
+    sorted.sort();
+    assert_eq!(names, sorted);
+    let registered: Vec<_> = names
+        .iter()
+        .filter(|name| name.starts_with("cmd_lib_registered_"))
+        .collect();
+    assert_eq!(registered, ["cmd_lib_registered_a", "cmd_lib_registered_b"]);
+    assert!(!names.iter().any(|name| name == "cd"));
+}
+
+#[test]
+fn test_builtin_mkdir() {
+    // registering it as "mkdir" would affect other tests running in parallel
+    #[export_cmd(builtin_mkdir)]
+    fn my_mkdir(env: &mut CmdEnv) -> CmdResult {
+        builtin_mkdir(env)
+    }
+    use_custom_cmd!(builtin_mkdir);
+
+    let dir = "/tmp/cmd_lib_mkdir";
+    run_cmd!(rm -rf $dir).unwrap();
+    assert!(run_cmd!(builtin_mkdir $dir/a/b 2>/dev/null).is_err());
+    run_cmd!(builtin_mkdir -p $dir/a/b $dir/c).unwrap();
+    run_cmd!(builtin_mkdir -p $dir/a/b).unwrap();
+    assert!(run_cmd!(builtin_mkdir $dir/c 2>/dev/null).is_err());
+    run_cmd!(cd $dir; builtin_mkdir d).unwrap();
+    assert_eq!(run_fun!(ls $dir).unwrap(), "a\nc\nd");
+    assert!(run_cmd!(test -d $dir/a/b).is_ok());
+    assert!(run_cmd!(builtin_mkdir).is_err());
+    run_cmd!(rm -rf $dir).unwrap();
+}
+
+#[test]
+fn test_builtin_grep() {
+    // registering it as "grep" would affect other tests running in parallel
+    #[export_cmd(builtin_grep)]
+    fn my_grep(env: &mut CmdEnv) -> std::io::Result<i32> {
+        builtin_grep(env)
+    }
+    use_custom_cmd!(builtin_grep);
+    use_builtin_cmd!(echo);
+
+    let dir = "/tmp/cmd_lib_builtin_grep";
+    run_cmd!(mkdir -p $dir; touch $dir/a.rs $dir/b.rs $dir/c.txt $dir/d.rst).unwrap();
+    assert_eq!(
+        run_fun!(ls $dir | builtin_grep r"\.rs$").unwrap(),
+        "a.rs\nb.rs"
+    );
+    assert_eq!(run_fun!(ls $dir | builtin_grep -v rs).unwrap(), "c.txt");
+    assert_eq!(run_fun!(ls $dir | builtin_grep -c rs).unwrap(), "3");
+    assert_eq!(run_fun!(ls $dir | builtin_grep -ic "A|C").unwrap(), "2");
+    run_cmd!(ls $dir > $dir/list).unwrap();
+    assert_eq!(run_fun!(builtin_grep txt $dir/list).unwrap(), "c.txt");
+    assert_eq!(run_fun!(echo "-x" | builtin_grep -- -x).unwrap(), "-x");
+
+    assert!(run_cmd!(ls $dir | builtin_grep nothing).is_err());
+    assert_eq!(
+        run_fun!(ls $dir | builtin_grep nothing || echo none).unwrap(),
+        "none"
+    );
+    assert!(run_cmd!(echo x | builtin_grep "(").is_err());
+    assert!(run_cmd!(echo x | builtin_grep -z x).is_err());
+    run_cmd!(rm -rf $dir).unwrap();
+}
+
+#[test]
+fn test_builtin_cut() {
+    // registering it as "cut" would affect other tests running in parallel
+    #[export_cmd(builtin_cut)]
+    fn my_cut(env: &mut CmdEnv) -> CmdResult {
+        builtin_cut(env)
+    }
+    use_custom_cmd!(builtin_cut);
+
+    let input = "root:x:0:0\nbin:x:1\nno delimiter";
+    assert_eq!(
+        run_fun!(builtin_cut -d: -f1 <<< $input).unwrap(),
+        "root\nbin\nno delimiter"
+    );
+    assert_eq!(
+        run_fun!(builtin_cut -d ":" -f 3-,1 <<< $input).unwrap(),
+        "root:0:0\nbin:1\nno delimiter"
+    );
+    assert_eq!(
+        run_fun!(builtin_cut -d: -f4 <<< $input).unwrap(),
+        "0\n\nno delimiter"
+    );
+    assert_eq!(
+        run_fun!(builtin_cut -c 2-3,6- <<< $input).unwrap(),
+        "oox:0:0\nin:1\no limiter"
+    );
+    assert_eq!(run_fun!(builtin_cut -f2 <<< "a\tb\tc").unwrap(), "b");
+    assert!(run_cmd!(builtin_cut -d: <<< $input).is_err());
+    assert!(run_cmd!(builtin_cut -f 0 <<< $input).is_err());
+    assert!(run_cmd!(builtin_cut -f 3-1 <<< $input).is_err());
+    assert!(run_cmd!(builtin_cut -d "::" -f1 <<< $input).is_err());
+}
+
+#[test]
+fn test_builtin_sort() {
+    // registering it as "sort" would affect other tests running in parallel
+    #[export_cmd(builtin_sort)]
+    fn my_sort(env: &mut CmdEnv) -> CmdResult {
+        builtin_sort(env)
+    }
+    use_custom_cmd!(builtin_sort);
+
+    let input = "10\nb\n9\na\n-1.5\n9\nb";
+    assert_eq!(
+        run_fun!(builtin_sort <<< $input).unwrap(),
+        "-1.5\n10\n9\n9\na\nb\nb"
+    );
+    assert_eq!(
+        run_fun!(builtin_sort -n <<< $input).unwrap(),
+        "-1.5\na\nb\nb\n9\n9\n10"
+    );
+    assert_eq!(
+        run_fun!(builtin_sort -nr <<< $input).unwrap(),
+        "10\n9\n9\nb\nb\na\n-1.5"
+    );
+    assert_eq!(
+        run_fun!(builtin_sort -u <<< $input).unwrap(),
+        "-1.5\n10\n9\na\nb"
+    );
+    // the lines without a number are all 0, so only the first of them is kept
+    assert_eq!(
+        run_fun!(builtin_sort -n -u <<< $input).unwrap(),
+        "-1.5\nb\n9\n10"
+    );
+    assert_eq!(
+        run_fun!(builtin_sort -n <<< $input | head -1).unwrap(),
+        "-1.5"
+    );
+    assert!(run_cmd!(builtin_sort -x <<< $input).is_err());
+}
+
+#[test]
+fn test_builtin_uniq() {
+    // registering it as "uniq" would affect other tests running in parallel
+    #[export_cmd(builtin_uniq)]
+    fn my_uniq(env: &mut CmdEnv) -> CmdResult {
+        builtin_uniq(env)
+    }
+    use_custom_cmd!(builtin_uniq);
+
+    let input = "a\na\nb\na\nc\nc\nc";
+    assert_eq!(run_fun!(builtin_uniq <<< $input).unwrap(), "a\nb\na\nc");
+    assert_eq!(run_fun!(builtin_uniq -d <<< $input).unwrap(), "a\nc");
+    assert_eq!(
+        run_fun!(builtin_uniq -c <<< $input).unwrap(),
+        "      2 a\n      1 b\n      1 a\n      3 c"
+    );
+    assert_eq!(
+        run_fun!(builtin_uniq -cd <<< $input).unwrap(),
+        "      2 a\n      3 c"
+    );
+    assert_eq!(run_fun!(echo -n "" | builtin_uniq).unwrap(), "");
+    assert_eq!(
+        run_fun!(sort <<< $input | builtin_uniq -c | sort -rn | head -1).unwrap(),
+        "      3 c"
+    );
+    assert!(run_cmd!(builtin_uniq -x <<< $input).is_err());
+}
+
+#[test]
+fn test_builtin_tr() {
+    // registering it as "tr" would affect other tests running in parallel
+    #[export_cmd(builtin_tr)]
+    fn my_tr(env: &mut CmdEnv) -> CmdResult {
+        builtin_tr(env)
+    }
+    use_custom_cmd!(builtin_tr);
+
+    assert_eq!(run_fun!(echo Hello | builtin_tr a-z A-Z).unwrap(), "HELLO");
+    assert_eq!(
+        run_fun!(echo Hello | builtin_tr "[:upper:][:lower:]" "[:lower:][:upper:]").unwrap(),
+        "hELLO"
+    );
+    assert_eq!(run_fun!(echo abcde | builtin_tr a-e xy).unwrap(), "xyyyy");
+    assert_eq!(
+        run_fun!(echo "a1b22c333" | builtin_tr -d "[:digit:]").unwrap(),
+        "abc"
+    );
+    assert_eq!(
+        run_fun!(echo "aa  bb   cc" | builtin_tr -s " ").unwrap(),
+        "aa bb cc"
+    );
+    assert_eq!(
+        run_fun!(echo "a,,b;;c" | builtin_tr -s ",;" "\n").unwrap(),
+        "a\nb\nc"
+    );
+    assert_eq!(
+        run_fun!(echo "a11b22" | builtin_tr -ds "[:alpha:]" 0-9).unwrap(),
+        "12"
+    );
+    assert!(run_cmd!(echo x | builtin_tr a-z).is_err());
+    assert!(run_cmd!(echo x | builtin_tr z-a A-Z).is_err());
+    assert!(run_cmd!(echo x | builtin_tr "[:nope:]" x).is_err());
+}
+
+#[test]
+fn test_with_dir() {
+    use_builtin_cmd!(pwd);
+    let dir = "/tmp/cmd_lib_with_dir";
+    run_cmd!(mkdir -p $dir/sub; touch $dir/sub/f.txt).unwrap();
+    assert_eq!(run_fun!(with_dir $dir ls sub).unwrap(), "f.txt");
+    assert_eq!(run_fun!(with_dir $dir -- pwd).unwrap(), dir);
+    assert_eq!(
+        run_fun!(cd $dir; with_dir sub ls; ls).unwrap(),
+        "sub"
+    );
+    assert_eq!(
+        run_fun!(cd $dir; with_dir sub ls | cat).unwrap(),
+        "f.txt"
+    );
+    assert!(run_cmd!(ignore with_dir /no_such_dir ls).is_ok());
+    assert!(run_cmd!(with_dir /no_such_dir ls).is_err());
+
+    let output = GroupCmds::default()
+        .append(Cmds::default().pipe(Cmd::default().add_arg("ls").set_current_dir(dir)))
+        .run_fun()
+        .unwrap();
+    assert_eq!(output, "sub");
+    run_cmd!(rm -rf $dir).unwrap();
+}
+
+#[test]
+fn test_pipeline_builder() {
+    use cmd_lib::builder::Pipeline;
+
+    let argv = vec!["echo".to_string(), "a b;c".to_string()];
+    assert_eq!(
+        Pipeline::new().command(&argv).pipe(["wc", "-w"]).run_fun().unwrap().trim(),
+        "2"
+    );
+    // the arguments are not interpreted like in the macros
+    assert!(Pipeline::new().command(["with_dir", "/", "true"]).run().is_err());
+    assert!(Pipeline::new().command(["FOO=1", "true"]).run().is_err());
+    assert!(Pipeline::new().command(["ignore", "false"]).run().is_err());
+    assert!(Pipeline::new().command(["ignore"]).run().is_err());
+    // an empty argv is rejected instead of panicking
+    let empty = Vec::<String>::new();
+    for ret in [
+        Pipeline::new().command(&empty).run(),
+        Pipeline::new().command(["true"]).pipe(&empty).run(),
+        Pipeline::new().command(&empty).spawn().map(|_| ()),
+    ] {
+        assert_eq!(ret.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    }
+    assert_eq!(
+        Pipeline::new()
+            .command(["true"])
+            .pipe(["echo", "x"])
+            .command(["echo", "y"])
+            .run_fun()
+            .unwrap(),
+        "y"
+    );
+
+    assert!(Pipeline::new().command(["false"]).pipe(["cat"]).run().is_err());
+    assert!(Pipeline::new()
+        .pipefail(false)
+        .command(["false"])
+        .pipe(["cat"])
+        .run()
+        .is_ok());
+
+    let mut proc = Pipeline::new()
+        .pipe(["echo", "spawned"])
+        .spawn_with_output()
+        .unwrap();
+    assert_eq!(proc.wait_with_output().unwrap(), "spawned");
+    assert!(Pipeline::new()
+        .command(["false"])
+        .ignore_error()
+        .command(["true"])
+        .run()
+        .is_ok());
+    assert!(Pipeline::new()
+        .command(["false"])
+        .ignore_error()
+        .pipe(["false"])
+        .run()
+        .is_err());
+    assert!(Pipeline::new().spawn().is_err());
+    assert!(Pipeline::new()
+        .command(["true"])
+        .command(["true"])
+        .spawn()
+        .is_err());
+}
+
+#[test]
+fn test_pipe_fn() {
+    use cmd_lib::builder::Pipeline;
+    use std::sync::{Arc, Mutex};
+
+    let lines = Arc::new(Mutex::new(vec![]));
+    let seen = Arc::clone(&lines);
+    let output = Pipeline::new()
+        .command(["printf", "a\\nb\\n"])
+        .pipe_fn(move |env| {
+            for line in env.stdin_string()?.lines() {
+                seen.lock().unwrap().push(line.to_string());
+                env.write_stdout(&format!("{}{}\n", line, line))?;
+            }
+            Ok(())
+        })
+        .pipe(["sort", "-r"])
+        .run_fun()
+        .unwrap();
+    assert_eq!(output, "bb\naa");
+    assert_eq!(*lines.lock().unwrap(), ["a", "b"]);
+
+    let mut calls = 0;
+    let cmd = Cmd::from_fn(move |env| {
+        calls += 1;
+        env.write_stdout(&format!("{}:{}", calls, env.args()[1..].join(",")))
+    });
+    let cmds = Cmds::default().pipe(cmd.add_args(["x", "y"]));
+    assert_eq!(
+        GroupCmds::default().append(cmds).run_fun().unwrap(),
+        "1:x,y"
+    );
+
+    assert!(Pipeline::new()
+        .pipe_fn(|_| Err(std::io::Error::other("failed")))
+        .run()
+        .is_err());
+}
+
+#[test]
+fn test_spawn_to() {
+    use cmd_lib::builder::Pipeline;
+    use std::fs::File;
+
+    let f = "/tmp/cmd_lib_spawn_to.txt";
+    let cmds = Cmds::default()
+        .pipe(Cmd::default().add_args(["echo", "a b"]))
+        .pipe(Cmd::default().add_args(["tr", " ", "\n"]));
+    GroupCmds::default()
+        .append(cmds)
+        .spawn_to(File::create(f).unwrap())
+        .unwrap()
+        .wait()
+        .unwrap();
+    assert_eq!(std::fs::read_to_string(f).unwrap(), "a\nb\n");
+
+    #[export_cmd(cmd_lib_spawn_to)]
+    fn spawn_to_cmd(env: &mut CmdEnv) -> CmdResult {
+        env.write_stdout("from builtin\n")
+    }
+    use_custom_cmd!(cmd_lib_spawn_to);
+    Pipeline::new()
+        .command(["cmd_lib_spawn_to"])
+        .spawn_to(File::create(f).unwrap())
+        .unwrap()
+        .wait()
+        .unwrap();
+    assert_eq!(std::fs::read_to_string(f).unwrap(), "from builtin\n");
+    run_cmd!(rm -f $f).unwrap();
+}
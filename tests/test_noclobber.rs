@@ -0,0 +1,24 @@
+use cmd_lib::*;
+
+// in its own test binary, since the global setting would affect the other tests running in
+// parallel
+#[test]
+fn test_noclobber() {
+    let f = "/tmp/cmd_lib_noclobber";
+    std::fs::write(f, "old\n").unwrap();
+    set_noclobber(true);
+    let err = run_cmd!(echo rust > $f).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    assert_eq!(run_fun!(cat $f).unwrap(), "old");
+    assert!(run_cmd!(echo rust >> $f).is_ok());
+    assert_eq!(run_fun!(cat $f).unwrap(), "old\nrust");
+    assert!(run_cmd!(echo rust >| $f).is_ok());
+    assert_eq!(run_fun!(cat $f).unwrap(), "rust");
+    // only the existing regular files are protected
+    assert!(run_cmd!(echo rust > /dev/null).is_ok());
+    run_cmd!(rm -f $f).unwrap();
+    assert!(run_cmd!(echo new > $f).is_ok());
+    set_noclobber(false);
+    assert!(run_cmd!(echo rust > $f).is_ok());
+    run_cmd!(rm -f $f).unwrap();
+}